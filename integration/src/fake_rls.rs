@@ -0,0 +1,171 @@
+//! A fake Envoy Rate Limit Service.
+//!
+//! Answers `ShouldRateLimit` like the reference service configured with one limit per
+//! minute for every descriptor, counting hits in memory. Carries just enough of the
+//! protobuf wire format to read the request and write the response.
+
+use crate::{GrpcCall, GrpcService};
+use std::collections::HashMap;
+
+pub const SERVICE: &str = "envoy.service.ratelimit.v3.RateLimitService";
+
+// gRPC status codes.
+const INVALID_ARGUMENT: u32 = 3;
+const UNIMPLEMENTED: u32 = 12;
+
+// RateLimitResponse.Code
+const OK: u64 = 1;
+const OVER_LIMIT: u64 = 2;
+// RateLimitResponse.RateLimit.Unit
+const MINUTE: u64 = 2;
+
+type Descriptor = Vec<(String, String)>;
+
+pub struct FakeRls {
+    limit: u64,
+    hits: HashMap<(String, Descriptor), u64>,
+}
+
+impl FakeRls {
+    /// Allows `limit` hits per minute of each descriptor.
+    pub fn new(limit: u64) -> FakeRls {
+        FakeRls {
+            limit,
+            hits: HashMap::new(),
+        }
+    }
+
+    fn should_rate_limit(&mut self, message: &[u8]) -> Option<Vec<u8>> {
+        let (mut domain, mut descriptors, mut hits_addend) = (String::new(), Vec::new(), 0);
+        for (field, value) in fields(message)? {
+            match (field, value) {
+                (1, Field::Bytes(bytes)) => domain = String::from_utf8(bytes.to_vec()).ok()?,
+                (2, Field::Bytes(bytes)) => descriptors.push(descriptor(bytes)?),
+                (3, Field::Varint(hits)) => hits_addend = hits,
+                _ => {}
+            }
+        }
+        // Like the reference service, no hits means one.
+        let hits_addend = hits_addend.max(1);
+
+        let mut response = Vec::new();
+        let mut overall = OK;
+        let mut statuses = Vec::new();
+        for entries in descriptors {
+            let hits = self.hits.entry((domain.clone(), entries)).or_default();
+            *hits += hits_addend;
+            let code = if *hits > self.limit { OVER_LIMIT } else { OK };
+            if code == OVER_LIMIT {
+                overall = OVER_LIMIT;
+            }
+            let mut current_limit = Vec::new();
+            put_varint(&mut current_limit, 1, self.limit);
+            put_varint(&mut current_limit, 2, MINUTE);
+            let mut duration_until_reset = Vec::new();
+            put_varint(&mut duration_until_reset, 1, 60);
+            let mut status = Vec::new();
+            put_varint(&mut status, 1, code);
+            put_bytes(&mut status, 2, &current_limit);
+            put_varint(&mut status, 3, self.limit.saturating_sub(*hits));
+            put_bytes(&mut status, 4, &duration_until_reset);
+            statuses.push(status);
+        }
+        put_varint(&mut response, 1, overall);
+        for status in statuses {
+            put_bytes(&mut response, 2, &status);
+        }
+        let mut header = Vec::new();
+        put_bytes(&mut header, 1, b"x-rate-limited-by");
+        put_bytes(&mut header, 2, b"fake-rls");
+        put_bytes(&mut response, 3, &header);
+        Some(response)
+    }
+}
+
+impl GrpcService for FakeRls {
+    fn call(&mut self, call: &GrpcCall) -> (u32, Vec<u8>) {
+        if call.service != SERVICE || call.method != "ShouldRateLimit" {
+            return (UNIMPLEMENTED, Vec::new());
+        }
+        match self.should_rate_limit(&call.message) {
+            Some(response) => (0, response),
+            None => (INVALID_ARGUMENT, Vec::new()),
+        }
+    }
+}
+
+/// Reads the entries of a `RateLimitDescriptor`.
+fn descriptor(message: &[u8]) -> Option<Descriptor> {
+    let mut entries = Vec::new();
+    for (field, value) in fields(message)? {
+        if let (1, Field::Bytes(entry)) = (field, value) {
+            let (mut key, mut value) = (String::new(), String::new());
+            for (field, part) in fields(entry)? {
+                match (field, part) {
+                    (1, Field::Bytes(bytes)) => key = String::from_utf8(bytes.to_vec()).ok()?,
+                    (2, Field::Bytes(bytes)) => value = String::from_utf8(bytes.to_vec()).ok()?,
+                    _ => {}
+                }
+            }
+            entries.push((key, value));
+        }
+    }
+    Some(entries)
+}
+
+enum Field<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+}
+
+/// Splits a message into its fields, or `None` if it is malformed or uses a wire type
+/// the service never sends.
+fn fields(mut buf: &[u8]) -> Option<Vec<(u32, Field<'_>)>> {
+    let mut fields = Vec::new();
+    while !buf.is_empty() {
+        let key = get_varint(&mut buf)?;
+        let value = match key & 7 {
+            0 => Field::Varint(get_varint(&mut buf)?),
+            2 => {
+                let len = usize::try_from(get_varint(&mut buf)?).ok()?;
+                let bytes = buf.get(..len)?;
+                buf = &buf[len..];
+                Field::Bytes(bytes)
+            }
+            _ => return None,
+        };
+        fields.push((u32::try_from(key >> 3).ok()?, value));
+    }
+    Some(fields)
+}
+
+fn get_varint(buf: &mut &[u8]) -> Option<u64> {
+    let mut value = 0;
+    for (i, byte) in buf.iter().enumerate().take(10) {
+        value |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            *buf = &buf[i + 1..];
+            return Some(value);
+        }
+    }
+    None
+}
+
+fn varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn put_varint(buf: &mut Vec<u8>, field: u32, value: u64) {
+    varint(buf, u64::from(field) << 3);
+    varint(buf, value);
+}
+
+fn put_bytes(buf: &mut Vec<u8>, field: u32, bytes: &[u8]) {
+    varint(buf, (u64::from(field) << 3) | 2);
+    varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
@@ -0,0 +1,128 @@
+//! Scripted exchanges replayed against the release build of the module.
+
+use wasmup_integration::{Exchange, Plugin};
+
+fn get(path: &str) -> Exchange<'_> {
+    Exchange {
+        request_headers: vec![
+            (":method", "GET"),
+            (":path", path),
+            (":authority", "www.example.com"),
+        ],
+        response_headers: vec![(":status", "200"), ("content-type", "text/plain")],
+        ..Exchange::default()
+    }
+}
+
+#[test]
+fn default_config_sets_custom_response_header() {
+    let mut plugin = Plugin::new("add_header_root", "").unwrap();
+    let outcome = plugin.replay(&get("/get")).unwrap();
+    assert_eq!(outcome.response_header("x-wasm-custom"), Some("FOO"));
+    assert!(outcome.local_response.is_none());
+}
+
+#[test]
+fn empty_root_id_uses_the_header_filter() {
+    let mut plugin = Plugin::new("", "").unwrap();
+    let outcome = plugin.replay(&get("/get")).unwrap();
+    assert_eq!(outcome.response_header("x-wasm-custom"), Some("FOO"));
+}
+
+#[test]
+fn routes_apply_to_matching_requests_only() {
+    let config = r#"{
+        "stages": [{
+            "type": "headers",
+            "request_headers": [{"op": "set", "name": "x-from", "value": "${header:x-user:-anon}"}],
+            "routes": [{
+                "match": {"path": {"prefix": "/api"}},
+                "response_headers": [{"op": "add", "name": "x-api", "value": "yes"}]
+            }]
+        }]
+    }"#;
+    let mut plugin = Plugin::new("add_header_root", config).unwrap();
+
+    let outcome = plugin.replay(&get("/api/users")).unwrap();
+    assert_eq!(outcome.request_header("x-from"), Some("anon"));
+    assert_eq!(outcome.response_header("x-api"), Some("yes"));
+
+    let outcome = plugin.replay(&get("/other")).unwrap();
+    assert_eq!(outcome.response_header("x-api"), None);
+}
+
+#[test]
+fn bodies_pass_through_unchanged() {
+    let mut plugin = Plugin::new("add_header_root", "").unwrap();
+    let mut exchange = get("/post");
+    exchange.request_headers[0].1 = "POST";
+    exchange.request_body = vec![b"hello, ", b"world"];
+    exchange.response_body = vec![b"ok"];
+    let outcome = plugin.replay(&exchange).unwrap();
+    assert_eq!(outcome.request_body, b"hello, world");
+    assert_eq!(outcome.response_body, b"ok");
+}
+
+#[test]
+fn invalid_config_is_rejected() {
+    let (plugin, accepted) =
+        Plugin::try_new("add_header_root", r#"{"stages": [{"type": "nope"}]}"#).unwrap();
+    assert!(!accepted);
+    assert!(
+        plugin
+            .host()
+            .logged("add_header_root: invalid plugin config"),
+        "{:?}",
+        plugin.host().logs
+    );
+}
+
+#[test]
+fn unknown_root_id_is_rejected() {
+    let (plugin, accepted) = Plugin::try_new("nope_root", "").unwrap();
+    assert!(!accepted);
+    assert!(plugin.host().logged("unknown root id `nope_root`"));
+}
+
+#[test]
+fn auth_root_challenges_requests_without_a_token() {
+    let config = r#"{"jwks": {"keys": [{"kty": "oct", "k": "c2VjcmV0LXNlY3JldC1zZWNyZXQ"}]}}"#;
+    let mut plugin = Plugin::new("auth_root", config).unwrap();
+    let outcome = plugin.replay(&get("/get")).unwrap();
+    let reply = outcome.local_response.unwrap();
+    assert_eq!(reply.status, 401);
+    assert!(reply.headers.contains(&(
+        "www-authenticate".to_string(),
+        "Bearer realm=\"wasmup\"".to_string()
+    )));
+    assert_eq!(
+        plugin
+            .host()
+            .metric("wasmup_rejections_total.stage.jwt.reason.missing_token"),
+        Some(1)
+    );
+}
+
+#[test]
+fn metrics_count_requests_and_headers() {
+    let mut plugin = Plugin::new("add_header_root", "").unwrap();
+    for _ in 0..2 {
+        plugin.replay(&get("/get")).unwrap();
+    }
+    assert_eq!(plugin.host().metric("wasmup_requests_total"), Some(2));
+    assert_eq!(
+        plugin.host().metric("wasmup_headers_injected_total"),
+        Some(2)
+    );
+}
+
+#[test]
+fn request_ids_are_drawn_from_wasi_random() {
+    let config = r#"{"stages": [{"type": "request_id"}]}"#;
+    let mut plugin = Plugin::new("add_header_root", config).unwrap();
+    let outcome = plugin.replay(&get("/get")).unwrap();
+    let id = outcome.request_header("x-request-id").unwrap();
+    assert_eq!(id.len(), 36);
+    assert_eq!(&id[14..15], "4");
+    assert_eq!(outcome.response_header("x-request-id"), Some(id));
+}
@@ -0,0 +1,74 @@
+//! The `global_rate_limit` stage against the fake rate limit service.
+
+use wasmup_integration::fake_rls::FakeRls;
+use wasmup_integration::{Exchange, GrpcCall, Plugin};
+
+const CONFIG: &str = r#"{
+    "stages": [{
+        "type": "global_rate_limit",
+        "cluster": "ratelimit",
+        "domain": "gateway",
+        "descriptors": [{"entries": [{"key": "user", "from": {"header": "x-user"}}]}],
+        "fallback": {"max_tokens": 1, "fill_interval_ms": 60000}
+    }]
+}"#;
+
+fn get(user: &str) -> Exchange<'_> {
+    Exchange {
+        request_headers: vec![(":method", "GET"), (":path", "/"), ("x-user", user)],
+        response_headers: vec![(":status", "200")],
+        ..Exchange::default()
+    }
+}
+
+#[test]
+fn limits_are_kept_by_the_service() {
+    let mut plugin = Plugin::new("add_header_root", CONFIG).unwrap();
+    plugin.serve_grpc("ratelimit", FakeRls::new(2));
+
+    for remaining in ["1", "0"] {
+        let outcome = plugin.replay(&get("alice")).unwrap();
+        assert!(outcome.local_response.is_none());
+        assert_eq!(
+            outcome.response_header("ratelimit-remaining"),
+            Some(remaining)
+        );
+        assert_eq!(
+            outcome.response_header("x-rate-limited-by"),
+            Some("fake-rls")
+        );
+    }
+    let reply = plugin
+        .replay(&get("alice"))
+        .unwrap()
+        .local_response
+        .unwrap();
+    assert_eq!(reply.status, 429);
+    assert!(reply
+        .headers
+        .contains(&("retry-after".to_string(), "60".to_string())));
+
+    let outcome = plugin.replay(&get("bob")).unwrap();
+    assert_eq!(outcome.response_header("ratelimit-remaining"), Some("1"));
+    assert_eq!(plugin.host().grpc_calls.len(), 4);
+}
+
+#[test]
+fn unreachable_service_falls_back_to_the_local_limiter() {
+    let mut plugin = Plugin::new("add_header_root", CONFIG).unwrap();
+    // UNAVAILABLE, as Envoy reports a cluster without healthy hosts.
+    plugin.serve_grpc("ratelimit", |_: &GrpcCall| (14, Vec::new()));
+
+    let outcome = plugin.replay(&get("alice")).unwrap();
+    assert!(outcome.local_response.is_none());
+    assert_eq!(outcome.response_header("ratelimit-remaining"), Some("0"));
+    let reply = plugin
+        .replay(&get("alice"))
+        .unwrap()
+        .local_response
+        .unwrap();
+    assert_eq!(reply.status, 429);
+    assert!(plugin
+        .host()
+        .logged("rate limit service ratelimit: gRPC status 14"));
+}
@@ -0,0 +1,310 @@
+//! A JSON line per request, written through proxy-wasm logging when the stream ends, to
+//! see what the filter did without turning on Envoy's access logs.
+
+use crate::config::{field, validate_header_name, ConfigError};
+use log::info;
+use proxy_wasm::hostcalls;
+use proxy_wasm::types::MapType;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::cell::Cell;
+
+/// The `access_log` section of the plugin configuration.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccessLogConfig {
+    #[serde(default = "default_fields")]
+    pub fields: Vec<LogField>,
+    /// Request headers to log, with the values sent upstream.
+    #[serde(default)]
+    pub request_headers: Vec<String>,
+    /// Response headers to log, with the values sent downstream.
+    #[serde(default)]
+    pub response_headers: Vec<String>,
+    /// Share of the requests to log, from 0 to 1.
+    #[serde(default = "default_sample_rate")]
+    pub sample_rate: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogField {
+    Method,
+    /// The path without its query string, which may carry credentials.
+    Path,
+    Status,
+    /// `duration_ms` of the whole stream.
+    Duration,
+    /// `request_bytes` and `response_bytes` of the bodies.
+    Bytes,
+    /// The stages that ran, with their time and local reply.
+    Stages,
+}
+
+fn default_fields() -> Vec<LogField> {
+    vec![
+        LogField::Method,
+        LogField::Path,
+        LogField::Status,
+        LogField::Duration,
+        LogField::Bytes,
+        LogField::Stages,
+    ]
+}
+
+fn default_sample_rate() -> f64 {
+    1.0
+}
+
+impl AccessLogConfig {
+    pub fn validate(&self, parent: &str) -> Result<(), ConfigError> {
+        for (name, headers) in [
+            ("request_headers", &self.request_headers),
+            ("response_headers", &self.response_headers),
+        ] {
+            for (i, header) in headers.iter().enumerate() {
+                validate_header_name(&field(parent, &format!("{name}[{i}]")), header)?;
+            }
+        }
+        if !(0.0..=1.0).contains(&self.sample_rate) {
+            return Err(ConfigError::new(
+                field(parent, "sample_rate"),
+                "must be between 0 and 1",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The access log of one configured root.
+pub struct AccessLog {
+    config: AccessLogConfig,
+    stage_types: Vec<&'static str>,
+    /// Requests owed to the log, so each worker logs exactly `sample_rate` of its requests.
+    credit: Cell<f64>,
+}
+
+/// What the pipeline saw of one request.
+#[derive(Debug, Default)]
+pub struct RequestLog {
+    pub start_ms: u64,
+    pub method: Option<String>,
+    pub path: Option<String>,
+    pub status: Option<u32>,
+    /// Microseconds each stage that ran has spent in its hooks.
+    pub durations: Vec<Option<u64>>,
+    /// The stage that answered the request and its status.
+    pub reply: Option<(usize, u32)>,
+    /// The stage the stream was paused on when it ended.
+    pub paused: Option<usize>,
+    /// Set by the `request_id` stage, and logged whatever the selected fields.
+    pub request_id: Option<String>,
+}
+
+impl AccessLog {
+    pub fn new(config: AccessLogConfig, stage_types: Vec<&'static str>) -> AccessLog {
+        AccessLog {
+            config,
+            stage_types,
+            credit: Cell::new(0.0),
+        }
+    }
+
+    fn sampled(&self) -> bool {
+        let credit = self.credit.get() + self.config.sample_rate;
+        let sampled = credit >= 1.0;
+        self.credit.set(if sampled { credit - 1.0 } else { credit });
+        sampled
+    }
+
+    /// Writes the line of `request`, if it is sampled.
+    pub fn write(&self, request: &RequestLog, now_ms: u64) {
+        if !self.sampled() {
+            return;
+        }
+        let mut line = Map::new();
+        for field in &self.config.fields {
+            match field {
+                LogField::Method => {
+                    line.insert("method".into(), json!(request.method));
+                }
+                LogField::Path => {
+                    let path = request
+                        .path
+                        .as_deref()
+                        .map(|path| path.split('?').next().unwrap_or_default());
+                    line.insert("path".into(), json!(path));
+                }
+                LogField::Status => {
+                    line.insert("status".into(), json!(request.status));
+                }
+                LogField::Duration => {
+                    let duration = now_ms.saturating_sub(request.start_ms);
+                    line.insert("duration_ms".into(), json!(duration));
+                }
+                LogField::Bytes => {
+                    line.insert("request_bytes".into(), json!(size(&["request", "size"])));
+                    line.insert("response_bytes".into(), json!(size(&["response", "size"])));
+                }
+                LogField::Stages => {
+                    line.insert("stages".into(), self.stages(request));
+                }
+            }
+        }
+        if let Some(id) = &request.request_id {
+            line.insert("request_id".into(), json!(id));
+        }
+        for (name, map_type, headers) in [
+            (
+                "request_headers",
+                MapType::HttpRequestHeaders,
+                &self.config.request_headers,
+            ),
+            (
+                "response_headers",
+                MapType::HttpResponseHeaders,
+                &self.config.response_headers,
+            ),
+        ] {
+            if headers.is_empty() {
+                continue;
+            }
+            let values: Map<String, Value> = headers
+                .iter()
+                .map(|header| {
+                    let value = hostcalls::get_map_value(map_type, header).ok().flatten();
+                    (header.clone(), json!(value))
+                })
+                .collect();
+            line.insert(name.into(), Value::Object(values));
+        }
+        info!("{}", Value::Object(line));
+    }
+
+    fn stages(&self, request: &RequestLog) -> Value {
+        let stages = request
+            .durations
+            .iter()
+            .enumerate()
+            .filter_map(|(i, duration)| {
+                let mut stage = json!({"type": self.stage_types[i], "duration_us": (*duration)?});
+                match request.reply {
+                    Some((stage_index, status)) if stage_index == i => {
+                        stage["reply"] = json!(status);
+                    }
+                    _ if request.paused == Some(i) => stage["paused"] = json!(true),
+                    _ => {}
+                }
+                Some(stage)
+            })
+            .collect();
+        Value::Array(stages)
+    }
+}
+
+/// An integer attribute of the stream, such as `request.size`.
+fn size(path: &[&str]) -> Option<u64> {
+    let value = hostcalls::get_property(path.to_vec()).ok().flatten()?;
+    Some(u64::from_le_bytes(value.try_into().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mock::{with_host, Plugin};
+    use serde_json::{json, Value};
+    use std::time::Duration;
+
+    fn lines() -> Vec<Value> {
+        with_host(|host| {
+            host.logs
+                .iter()
+                .filter_map(|(_, line)| serde_json::from_str(line).ok())
+                .collect()
+        })
+    }
+
+    #[test]
+    fn logs_what_the_stages_did() {
+        with_host(|host| host.now = Duration::from_secs(1_700_000_000));
+        let config = json!({
+            "access_log": {"request_headers": ["x-user"], "response_headers": ["x-wasm-custom"]},
+            "stages": [
+                {"type": "headers", "request_headers": [{"op": "set", "name": "x-user", "value": "anon"}],
+                 "response_headers": [{"op": "set", "name": "x-wasm-custom", "value": "FOO"}]},
+                {"type": "basic_auth", "htpasswd": "alice:{SHA}5en6G6MezRroT3XKqkdPOmY/BfQ="}
+            ]
+        });
+        let mut plugin = Plugin::new("add_header_root", &config.to_string());
+        let stream = plugin.stream();
+        stream.request_headers(&[(":method", "POST"), (":path", "/login?token=abc")], false);
+        with_host(|host| {
+            host.now += Duration::from_millis(25);
+            host.set_property(&["request", "size"], &12u64.to_le_bytes());
+        });
+        stream.log();
+
+        let [line] = lines().try_into().unwrap();
+        assert_eq!(
+            line,
+            json!({
+                "method": "POST",
+                "path": "/login",
+                "status": 401,
+                "duration_ms": 25,
+                "request_bytes": 12,
+                "response_bytes": null,
+                "stages": [
+                    {"type": "headers", "duration_us": 0},
+                    {"type": "basic_auth", "duration_us": 0, "reply": 401}
+                ],
+                "request_headers": {"x-user": "anon"},
+                "response_headers": {"x-wasm-custom": null}
+            })
+        );
+    }
+
+    #[test]
+    fn samples_and_selects_fields() {
+        let config = json!({
+            "access_log": {"fields": ["status"], "sample_rate": 0.5},
+            "response_headers": [{"op": "set", "name": "x-wasm-custom", "value": "FOO"}]
+        });
+        let mut plugin = Plugin::new("add_header_root", &config.to_string());
+        for status in ["200", "201", "202", "203"] {
+            let stream = plugin.stream();
+            stream.request_headers(&[(":path", "/")], true);
+            stream.response_headers(&[(":status", status)], true);
+            stream.log();
+        }
+        assert_eq!(lines(), [json!({"status": 201}), json!({"status": 203})]);
+    }
+
+    #[test]
+    fn buffered_bodies_are_not_left_paused() {
+        let config = json!({
+            "access_log": {"fields": ["stages"]},
+            "stages": [{"type": "json_transform", "request": [{"op": "set", "path": "$.a", "value": 1}]}]
+        });
+        let mut plugin = Plugin::new("add_header_root", &config.to_string());
+        let stream = plugin.stream();
+        stream.request_headers(
+            &[(":path", "/"), ("content-type", "application/json")],
+            false,
+        );
+        stream.request_body(b"{", false);
+        stream.request_body(b"}", true);
+        stream.log();
+
+        let stream = plugin.stream();
+        stream.request_headers(
+            &[(":path", "/"), ("content-type", "application/json")],
+            false,
+        );
+        stream.request_body(b"{", false);
+        stream.log();
+
+        let [done, cut_off] = lines().try_into().unwrap();
+        assert_eq!(done["stages"][0].get("paused"), None);
+        assert_eq!(cut_off["stages"][0]["paused"], json!(true));
+    }
+}
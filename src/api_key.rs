@@ -0,0 +1,304 @@
+//! API key authentication.
+//!
+//! Keys are never stored in the clear: the configuration, and optionally a shared data
+//! entry maintained by another plugin, list the SHA-256 hash of each key with the name of
+//! the consumer it belongs to.
+
+use crate::config::{field, validate_header_name, ConfigError};
+use crate::matcher::RequestInfo;
+use crate::pipeline::{Flow, LocalReply, Stage};
+use log::warn;
+use proxy_wasm::hostcalls;
+use proxy_wasm::types::MapType;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::rc::Rc;
+use subtle::ConstantTimeEq;
+
+/// Configuration of the `api_key` stage.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApiKeyConfig {
+    /// Where the key is looked for, in order.
+    #[serde(default = "default_sources")]
+    pub sources: Vec<KeySource>,
+    #[serde(default)]
+    pub keys: Vec<ApiKey>,
+    /// Shared data entry with more keys, as a JSON list in the format of `keys`.
+    pub shared_data_key: Option<String>,
+    /// Upstream request header that receives the consumer name.
+    #[serde(default = "default_consumer_header")]
+    pub consumer_header: String,
+    #[serde(default = "default_missing_status")]
+    pub missing_key_status: u32,
+    #[serde(default = "default_invalid_status")]
+    pub invalid_key_status: u32,
+    #[serde(skip)]
+    shared: RefCell<Option<(u32, Rc<Vec<ApiKey>>)>>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum KeySource {
+    Header(String),
+    Query(String),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApiKey {
+    pub consumer: String,
+    /// Hex-encoded SHA-256 hash of the key.
+    pub sha256: KeyHash,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct KeyHash([u8; 32]);
+
+impl TryFrom<String> for KeyHash {
+    type Error = String;
+
+    fn try_from(raw: String) -> Result<Self, Self::Error> {
+        let mut hash = [0; 32];
+        hex::decode_to_slice(&raw, &mut hash)
+            .map_err(|_| format!("`{raw}` is not a hex-encoded SHA-256 hash"))?;
+        Ok(KeyHash(hash))
+    }
+}
+
+fn default_sources() -> Vec<KeySource> {
+    vec![KeySource::Header("x-api-key".to_string())]
+}
+
+fn default_consumer_header() -> String {
+    "x-consumer".to_string()
+}
+
+fn default_missing_status() -> u32 {
+    401
+}
+
+fn default_invalid_status() -> u32 {
+    403
+}
+
+impl ApiKeyConfig {
+    pub fn validate(&self, parent: &str) -> Result<(), ConfigError> {
+        if self.sources.is_empty() {
+            return Err(ConfigError::new(
+                field(parent, "sources"),
+                "at least one source is required",
+            ));
+        }
+        for (i, source) in self.sources.iter().enumerate() {
+            let source_field = field(parent, &format!("sources[{i}]"));
+            match source {
+                KeySource::Header(name) => {
+                    validate_header_name(&field(&source_field, "header"), name)?
+                }
+                KeySource::Query(name) if name.is_empty() => {
+                    return Err(ConfigError::new(
+                        field(&source_field, "query"),
+                        "query parameter name must not be empty",
+                    ));
+                }
+                KeySource::Query(_) => {}
+            }
+        }
+        if self.keys.is_empty() && self.shared_data_key.is_none() {
+            return Err(ConfigError::new(
+                field(parent, "keys"),
+                "at least one key is required unless `shared_data_key` is set",
+            ));
+        }
+        for (i, key) in self.keys.iter().enumerate() {
+            if key.consumer.is_empty() {
+                return Err(ConfigError::new(
+                    field(parent, &format!("keys[{i}].consumer")),
+                    "consumer must not be empty",
+                ));
+            }
+        }
+        validate_header_name(&field(parent, "consumer_header"), &self.consumer_header)?;
+        for (name, status) in [
+            ("missing_key_status", self.missing_key_status),
+            ("invalid_key_status", self.invalid_key_status),
+        ] {
+            if status != 401 && status != 403 {
+                return Err(ConfigError::new(
+                    field(parent, name),
+                    format!("status must be 401 or 403, not {status}"),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Keys from the shared data entry, parsed again only when the entry changes.
+    fn shared_keys(&self) -> Rc<Vec<ApiKey>> {
+        let Some(key) = &self.shared_data_key else {
+            return Rc::default();
+        };
+        let (Some(raw), Some(cas)) = hostcalls::get_shared_data(key).unwrap() else {
+            return Rc::default();
+        };
+        let mut shared = self.shared.borrow_mut();
+        if let Some((cached_cas, keys)) = &*shared {
+            if *cached_cas == cas {
+                return Rc::clone(keys);
+            }
+        }
+        let keys = Rc::new(serde_json::from_slice(&raw).unwrap_or_else(|err| {
+            warn!("ignoring API keys in shared data `{key}`: {err}");
+            Vec::new()
+        }));
+        *shared = Some((cas, Rc::clone(&keys)));
+        keys
+    }
+
+    /// The consumer owning `key`. Every known hash is compared, in constant time, so the
+    /// time taken does not depend on which of them matches or how closely.
+    fn consumer(&self, key: &str) -> Option<String> {
+        let hash: [u8; 32] = Sha256::digest(key.as_bytes()).into();
+        let shared = self.shared_keys();
+        let mut consumer = None;
+        for known in self.keys.iter().chain(shared.iter()) {
+            if bool::from(hash.ct_eq(&known.sha256.0)) {
+                consumer = Some(&known.consumer);
+            }
+        }
+        consumer.cloned()
+    }
+}
+
+/// Authenticates requests with an API key.
+pub struct ApiKeyStage {
+    config: Rc<ApiKeyConfig>,
+}
+
+impl ApiKeyStage {
+    pub fn new(config: Rc<ApiKeyConfig>) -> ApiKeyStage {
+        ApiKeyStage { config }
+    }
+}
+
+impl Stage for ApiKeyStage {
+    fn on_request_headers(&mut self, _num_headers: usize, _end_of_stream: bool) -> Flow {
+        let request = RequestInfo::new(hostcalls::get_map(MapType::HttpRequestHeaders).unwrap());
+        let key = self.config.sources.iter().find_map(|source| match source {
+            KeySource::Header(name) => request.header(name),
+            KeySource::Query(name) => request.query_param(name),
+        });
+        let Some(key) = key.filter(|key| !key.is_empty()) else {
+            return Flow::Respond(
+                LocalReply::new(self.config.missing_key_status)
+                    .body("missing API key")
+                    .reason("missing_key"),
+            );
+        };
+        let Some(consumer) = self.config.consumer(key) else {
+            return Flow::Respond(
+                LocalReply::new(self.config.invalid_key_status)
+                    .body("invalid API key")
+                    .reason("invalid_key"),
+            );
+        };
+        hostcalls::set_map_value(
+            MapType::HttpRequestHeaders,
+            &self.config.consumer_header,
+            Some(&consumer),
+        )
+        .unwrap();
+        Flow::Continue
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mock::{with_host, Plugin};
+    use proxy_wasm::types::Action;
+    use serde_json::json;
+    use sha2::{Digest, Sha256};
+
+    fn sha256(key: &str) -> String {
+        hex::encode(Sha256::digest(key))
+    }
+
+    fn plugin(stage: serde_json::Value) -> Plugin {
+        Plugin::new("add_header_root", &json!({"stages": [stage]}).to_string())
+    }
+
+    fn status(plugin: &mut Plugin, headers: &[(&str, &str)]) -> Option<u32> {
+        let stream = plugin.stream();
+        stream.request_headers(headers, true);
+        stream.local_response().map(|reply| reply.status)
+    }
+
+    #[test]
+    fn maps_keys_to_consumers() {
+        let mut plugin = plugin(json!({
+            "type": "api_key",
+            "sources": [{"header": "x-api-key"}, {"query": "api_key"}],
+            "keys": [
+                {"consumer": "alice", "sha256": sha256("alice-key")},
+                {"consumer": "bob", "sha256": sha256("bob-key")}
+            ]
+        }));
+
+        let stream = plugin.stream();
+        let action = stream.request_headers(
+            &[
+                (":path", "/"),
+                ("x-api-key", "bob-key"),
+                ("x-consumer", "alice"),
+            ],
+            true,
+        );
+        assert_eq!(action, Action::Continue);
+        assert_eq!(stream.request_header("x-consumer").as_deref(), Some("bob"));
+
+        let stream = plugin.stream();
+        stream.request_headers(&[(":path", "/get?api_key=alice-key")], true);
+        assert_eq!(
+            stream.request_header("x-consumer").as_deref(),
+            Some("alice")
+        );
+
+        assert_eq!(status(&mut plugin, &[(":path", "/")]), Some(401));
+        assert_eq!(
+            status(&mut plugin, &[(":path", "/"), ("x-api-key", "nope")]),
+            Some(403)
+        );
+    }
+
+    #[test]
+    fn reads_keys_from_shared_data() {
+        let mut plugin = plugin(json!({
+            "type": "api_key",
+            "shared_data_key": "api-keys",
+            "invalid_key_status": 401
+        }));
+        let request = [(":path", "/"), ("x-api-key", "carol-key")];
+        assert_eq!(status(&mut plugin, &request), Some(401));
+
+        let keys = json!([{"consumer": "carol", "sha256": sha256("carol-key")}]);
+        with_host(|host| {
+            let entry = host.shared_data.entry("api-keys".to_string()).or_default();
+            *entry = (keys.to_string().into_bytes(), entry.1 + 1);
+        });
+        assert_eq!(status(&mut plugin, &request), None);
+    }
+
+    #[test]
+    fn rejects_malformed_hashes() {
+        let config =
+            json!({"stages": [{"type": "api_key", "keys": [{"consumer": "a", "sha256": "abc"}]}]});
+        let (_, accepted) = Plugin::try_new("add_header_root", &config.to_string());
+        assert!(!accepted);
+        assert!(with_host(
+            |host| host.logged("`abc` is not a hex-encoded SHA-256 hash")
+        ));
+    }
+}
@@ -0,0 +1,380 @@
+//! HTTP Basic authentication against an htpasswd user list.
+//!
+//! Supported password hashes are bcrypt (`$2y$`, `$2b$`, `$2a$`), SHA-256 and SHA-512 crypt
+//! (`$5$`, `$6$`) and unsalted SHA-1 (`{SHA}`), as written by `htpasswd -B`, `mkpasswd` and
+//! `htpasswd -s`.
+
+use crate::config::{field, validate_header_name, validate_header_value, ConfigError};
+use crate::pipeline::{Flow, LocalReply, Stage};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine as _;
+use hmac::{Hmac, Mac};
+use proxy_wasm::hostcalls;
+use proxy_wasm::types::MapType;
+use serde::Deserialize;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::rc::Rc;
+use subtle::ConstantTimeEq;
+
+/// Configuration of the `basic_auth` stage.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BasicAuthConfig {
+    /// `user:hash` lines, as in an htpasswd file.
+    pub htpasswd: Htpasswd,
+    /// `realm` of the `WWW-Authenticate` challenge.
+    #[serde(default = "default_realm")]
+    pub realm: String,
+    /// Upstream request header that receives the authenticated user name.
+    pub user_header: Option<String>,
+    /// Whether the `Authorization` header is passed upstream.
+    #[serde(default = "default_forward")]
+    pub forward: bool,
+    /// Credentials that verified before. Hashes like bcrypt are slow by design, so each
+    /// VM remembers a bounded number of successful checks.
+    #[serde(skip)]
+    verified: RefCell<HashSet<[u8; 32]>>,
+    /// Key of the HMAC that `verified` holds instead of the credentials, so its entries
+    /// are no shortcut for guessing passwords.
+    #[serde(skip, default = "cache_key")]
+    cache_key: [u8; 32],
+}
+
+fn default_realm() -> String {
+    "wasmup".to_string()
+}
+
+fn default_forward() -> bool {
+    true
+}
+
+fn cache_key() -> [u8; 32] {
+    let mut key = [0; 32];
+    getrandom::getrandom(&mut key).unwrap();
+    key
+}
+
+/// Successful checks remembered per VM before the cache starts over.
+const VERIFIED_CACHE_SIZE: usize = 1024;
+
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Htpasswd(Vec<(String, PasswordHash)>);
+
+#[derive(Debug)]
+enum PasswordHash {
+    Bcrypt(String),
+    Sha256Crypt(String),
+    Sha512Crypt(String),
+    Sha1([u8; 20]),
+}
+
+impl TryFrom<String> for Htpasswd {
+    type Error = String;
+
+    fn try_from(raw: String) -> Result<Self, Self::Error> {
+        let mut entries: Vec<(String, PasswordHash)> = Vec::new();
+        for (n, line) in raw.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (user, hash) = line
+                .split_once(':')
+                .ok_or_else(|| format!("line {}: expected `user:hash`", n + 1))?;
+            if user.is_empty() {
+                return Err(format!("line {}: user name must not be empty", n + 1));
+            }
+            if entries.iter().any(|(known, _)| known == user) {
+                return Err(format!("line {}: duplicate user `{user}`", n + 1));
+            }
+            let hash = PasswordHash::parse(hash).map_err(|err| format!("line {}: {err}", n + 1))?;
+            entries.push((user.to_string(), hash));
+        }
+        if entries.is_empty() {
+            return Err("htpasswd lists no users".to_string());
+        }
+        Ok(Htpasswd(entries))
+    }
+}
+
+impl PasswordHash {
+    fn parse(hash: &str) -> Result<PasswordHash, String> {
+        let invalid = |kind: &str| format!("invalid {kind} hash");
+        if ["$2y$", "$2b$", "$2a$"].iter().any(|p| hash.starts_with(p)) {
+            hash.parse::<bcrypt::HashParts>()
+                .map_err(|_| invalid("bcrypt"))?;
+            Ok(PasswordHash::Bcrypt(hash.to_string()))
+        } else if let Some(rest) = hash.strip_prefix("$5$") {
+            if !is_sha_crypt(rest, 43) {
+                return Err(invalid("SHA-256 crypt"));
+            }
+            Ok(PasswordHash::Sha256Crypt(hash.to_string()))
+        } else if let Some(rest) = hash.strip_prefix("$6$") {
+            if !is_sha_crypt(rest, 86) {
+                return Err(invalid("SHA-512 crypt"));
+            }
+            Ok(PasswordHash::Sha512Crypt(hash.to_string()))
+        } else if let Some(digest) = hash.strip_prefix("{SHA}") {
+            STANDARD
+                .decode(digest)
+                .ok()
+                .and_then(|digest| digest.try_into().ok())
+                .map(PasswordHash::Sha1)
+                .ok_or_else(|| invalid("{SHA}"))
+        } else {
+            let scheme = hash.split('$').nth(1).filter(|_| hash.starts_with('$'));
+            Err(match scheme {
+                Some(scheme) => format!("unsupported hash scheme `${scheme}$`"),
+                None => "unsupported hash; use bcrypt, SHA-256/512 crypt or {SHA}".to_string(),
+            })
+        }
+    }
+
+    fn verify(&self, password: &str) -> bool {
+        match self {
+            PasswordHash::Bcrypt(hash) => bcrypt::verify(password, hash).unwrap_or(false),
+            PasswordHash::Sha256Crypt(hash) => sha_crypt::sha256_check(password, hash).is_ok(),
+            PasswordHash::Sha512Crypt(hash) => sha_crypt::sha512_check(password, hash).is_ok(),
+            PasswordHash::Sha1(digest) => {
+                bool::from(Sha1::digest(password.as_bytes()).as_slice().ct_eq(digest))
+            }
+        }
+    }
+}
+
+/// Whether `rest`, a crypt hash after its `$5$` or `$6$` prefix, has the shape
+/// `[rounds=N$]salt$hash` with a hash of `hash_len` characters.
+fn is_sha_crypt(rest: &str, hash_len: usize) -> bool {
+    let rest = match rest.strip_prefix("rounds=") {
+        Some(rounds) => match rounds.split_once('$') {
+            Some((n, rest)) if n.parse::<u32>().is_ok() => rest,
+            _ => return false,
+        },
+        None => rest,
+    };
+    let crypt_b64 = |s: &str| {
+        s.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'.' || b == b'/')
+    };
+    matches!(
+        rest.split_once('$'),
+        Some((salt, hash)) if salt.len() <= 16 && crypt_b64(salt) && hash.len() == hash_len && crypt_b64(hash)
+    )
+}
+
+impl BasicAuthConfig {
+    pub fn validate(&self, parent: &str) -> Result<(), ConfigError> {
+        if self.realm.contains('"') || self.realm.contains('\\') {
+            return Err(ConfigError::new(
+                field(parent, "realm"),
+                "realm must not contain quotes or backslashes",
+            ));
+        }
+        validate_header_value(&field(parent, "realm"), &self.realm)?;
+        if let Some(header) = &self.user_header {
+            validate_header_name(&field(parent, "user_header"), header)?;
+        }
+        Ok(())
+    }
+
+    /// Checks `user` and `password` against the user list. An unknown user is checked
+    /// against the first entry's hash all the same, so the time taken does not tell
+    /// which users exist.
+    fn authenticate(&self, user: &str, password: &str) -> bool {
+        let key: [u8; 32] = Hmac::<Sha256>::new_from_slice(&self.cache_key)
+            .unwrap()
+            .chain_update(user)
+            .chain_update([0])
+            .chain_update(password)
+            .finalize()
+            .into_bytes()
+            .into();
+        if self.verified.borrow().contains(&key) {
+            return true;
+        }
+        let entry = self.htpasswd.0.iter().find(|(known, _)| known == user);
+        let hash = entry.map_or(&self.htpasswd.0[0].1, |(_, hash)| hash);
+        if !hash.verify(password) || entry.is_none() {
+            return false;
+        }
+        let mut verified = self.verified.borrow_mut();
+        if verified.len() >= VERIFIED_CACHE_SIZE {
+            verified.clear();
+        }
+        verified.insert(key);
+        true
+    }
+}
+
+/// Authenticates requests with a user name and password.
+pub struct BasicAuthStage {
+    config: Rc<BasicAuthConfig>,
+}
+
+impl BasicAuthStage {
+    pub fn new(config: Rc<BasicAuthConfig>) -> BasicAuthStage {
+        BasicAuthStage { config }
+    }
+}
+
+impl Stage for BasicAuthStage {
+    fn on_request_headers(&mut self, _num_headers: usize, _end_of_stream: bool) -> Flow {
+        let map = MapType::HttpRequestHeaders;
+        let credentials = hostcalls::get_map_value(map, "authorization")
+            .unwrap()
+            .and_then(|value| basic_credentials(&value));
+        let user = match credentials {
+            Some((user, password)) if self.config.authenticate(&user, &password) => user,
+            credentials => {
+                let reason = match credentials {
+                    Some(_) => "invalid_credentials",
+                    None => "missing_credentials",
+                };
+                let challenge = format!("Basic realm=\"{}\", charset=\"UTF-8\"", self.config.realm);
+                return Flow::Respond(
+                    LocalReply::new(401)
+                        .header("www-authenticate", challenge)
+                        .body("unauthorized")
+                        .reason(reason),
+                );
+            }
+        };
+        if let Some(header) = &self.config.user_header {
+            hostcalls::set_map_value(map, header, Some(&user)).unwrap();
+        }
+        if !self.config.forward {
+            hostcalls::remove_map_value(map, "authorization").unwrap();
+        }
+        Flow::Continue
+    }
+}
+
+fn basic_credentials(value: &str) -> Option<(String, String)> {
+    let (scheme, encoded) = value.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(STANDARD.decode(encoded.trim()).ok()?).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some((user.to_string(), password.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BasicAuthConfig;
+    use crate::mock::{with_host, Plugin};
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine as _;
+    use serde_json::json;
+    use sha1::{Digest, Sha1};
+    use sha2::Sha256;
+
+    fn authorization(user: &str, password: &str) -> String {
+        format!("Basic {}", STANDARD.encode(format!("{user}:{password}")))
+    }
+
+    fn status(plugin: &mut Plugin, authorization: &str) -> Option<u32> {
+        let stream = plugin.stream();
+        stream.request_headers(&[(":path", "/"), ("authorization", authorization)], true);
+        stream.local_response().map(|reply| reply.status)
+    }
+
+    #[test]
+    fn verifies_each_hash_scheme() {
+        let htpasswd = [
+            format!("ann:{}", bcrypt::hash("ann-pw", 4).unwrap()),
+            format!(
+                "ben:{}",
+                sha_crypt::sha256_simple("ben-pw", &sha_crypt::Sha256Params::new(1000).unwrap())
+                    .unwrap()
+            ),
+            format!(
+                "cat:{}",
+                sha_crypt::sha512_simple("cat-pw", &sha_crypt::Sha512Params::new(1000).unwrap())
+                    .unwrap()
+            ),
+            format!("dan:{{SHA}}{}", STANDARD.encode(Sha1::digest("dan-pw"))),
+        ]
+        .join("\n");
+        let config = json!({"stages": [{
+            "type": "basic_auth",
+            "htpasswd": format!("# staging users\n{htpasswd}\n"),
+            "user_header": "x-user",
+            "forward": false
+        }]});
+        let mut plugin = Plugin::new("add_header_root", &config.to_string());
+
+        for user in ["ann", "ben", "cat", "dan"] {
+            let stream = plugin.stream();
+            let valid = authorization(user, &format!("{user}-pw"));
+            stream.request_headers(&[(":path", "/"), ("authorization", &valid)], true);
+            assert!(stream.local_response().is_none(), "{user}");
+            assert_eq!(stream.request_header("x-user").as_deref(), Some(user));
+            assert_eq!(stream.request_header("authorization"), None);
+
+            assert_eq!(
+                status(&mut plugin, &authorization(user, "wrong")),
+                Some(401)
+            );
+        }
+    }
+
+    #[test]
+    fn challenges_without_valid_credentials() {
+        let config = json!({"stages": [{
+            "type": "basic_auth",
+            "htpasswd": format!("dan:{{SHA}}{}", STANDARD.encode(Sha1::digest("pw"))),
+            "realm": "staging"
+        }]});
+        let mut plugin = Plugin::new("add_header_root", &config.to_string());
+        for authorization in ["", "Bearer abc", "Basic !!!", &authorization("eve", "pw")] {
+            let stream = plugin.stream();
+            stream.request_headers(&[(":path", "/"), ("authorization", authorization)], true);
+            let reply = stream.local_response().unwrap();
+            assert_eq!(reply.status, 401);
+            assert_eq!(
+                reply.headers,
+                vec![(
+                    "www-authenticate".to_string(),
+                    "Basic realm=\"staging\", charset=\"UTF-8\"".to_string()
+                )]
+            );
+        }
+    }
+
+    #[test]
+    fn remembers_credentials_under_a_per_vm_key() {
+        let htpasswd = format!("dan:{{SHA}}{}", STANDARD.encode(Sha1::digest("pw")));
+        let configs: Vec<BasicAuthConfig> = (0..2)
+            .map(|_| serde_json::from_value(json!({ "htpasswd": htpasswd })).unwrap())
+            .collect();
+        for config in &configs {
+            assert!(config.authenticate("dan", "pw"));
+            assert!(!config.authenticate("eve", "pw"));
+        }
+        let unkeyed: [u8; 32] = Sha256::new()
+            .chain_update("dan")
+            .chain_update([0])
+            .chain_update("pw")
+            .finalize()
+            .into();
+        let verified: Vec<_> = configs.iter().map(|c| c.verified.take()).collect();
+        assert_eq!(verified[0].len(), 1);
+        assert!(!verified[0].contains(&unkeyed));
+        assert_ne!(verified[0], verified[1]);
+    }
+
+    #[test]
+    fn rejects_unsupported_hashes() {
+        let config = json!({"stages": [{"type": "basic_auth", "htpasswd": "a:$apr1$xyz$abc"}]});
+        let (_, accepted) = Plugin::try_new("add_header_root", &config.to_string());
+        assert!(!accepted);
+        assert!(with_host(
+            |host| host.logged("line 1: unsupported hash scheme `$apr1$`")
+        ));
+    }
+}
@@ -0,0 +1,262 @@
+//! Request and response body size limits, enforced while the bodies stream through.
+//!
+//! A `content-length` over the limit is answered at the headers, before the upstream
+//! sees the request or the client sees the response. Bodies without one are counted
+//! chunk by chunk and cut off as soon as they go over. The headers of such a request are
+//! held, and its body buffered, until it ends within the limit, so the upstream is never
+//! reached by a request that turns out too large. Responses are not held: one without a
+//! `content-length` that goes over after its headers were sent is reset instead.
+
+use crate::config::{field, ConfigError};
+use crate::matcher::{Match, RequestInfo};
+use crate::pipeline::{BodyBytes, Flow, LocalReply, Stage};
+use log::warn;
+use proxy_wasm::hostcalls;
+use proxy_wasm::types::MapType;
+use serde::Deserialize;
+use std::rc::Rc;
+
+/// Configuration of the `body_limit` stage.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BodyLimitConfig {
+    pub max_request_bytes: Option<u64>,
+    pub max_response_bytes: Option<u64>,
+    /// The first route that matches a request overrides the limits it sets.
+    #[serde(default)]
+    pub routes: Vec<BodyLimitRoute>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BodyLimitRoute {
+    #[serde(rename = "match", default)]
+    pub matches: Match,
+    pub max_request_bytes: Option<u64>,
+    pub max_response_bytes: Option<u64>,
+}
+
+impl BodyLimitConfig {
+    pub fn validate(&self, parent: &str) -> Result<(), ConfigError> {
+        let limited =
+            |request: Option<u64>, response: Option<u64>| request.is_some() || response.is_some();
+        if !limited(self.max_request_bytes, self.max_response_bytes)
+            && !self
+                .routes
+                .iter()
+                .any(|route| limited(route.max_request_bytes, route.max_response_bytes))
+        {
+            return Err(ConfigError::new(
+                parent.to_string(),
+                "at least one of `max_request_bytes` and `max_response_bytes` is required",
+            ));
+        }
+        for (i, route) in self.routes.iter().enumerate() {
+            let route_field = field(parent, &format!("routes[{i}]"));
+            route.matches.validate(&field(&route_field, "match"))?;
+        }
+        Ok(())
+    }
+}
+
+/// Rejects bodies over the limits of the request's route.
+pub struct BodyLimitStage {
+    config: Rc<BodyLimitConfig>,
+    body_bytes: Rc<BodyBytes>,
+    /// Request and response limits of this request.
+    limits: (Option<u64>, Option<u64>),
+    /// The request headers wait for the end of a body of unknown size.
+    holding: bool,
+}
+
+impl BodyLimitStage {
+    pub fn new(config: Rc<BodyLimitConfig>, body_bytes: Rc<BodyBytes>) -> BodyLimitStage {
+        BodyLimitStage {
+            config,
+            body_bytes,
+            limits: (None, None),
+            holding: false,
+        }
+    }
+}
+
+fn content_length(map_type: MapType) -> Option<u64> {
+    hostcalls::get_map_value(map_type, "content-length")
+        .unwrap()
+        .and_then(|length| length.trim().parse().ok())
+}
+
+fn request_too_large() -> Flow {
+    Flow::Respond(
+        LocalReply::new(413)
+            .body("request body too large")
+            .reason("request_too_large"),
+    )
+}
+
+/// A 502 for a response over its limit. Once the response headers went out, Envoy
+/// resets the stream instead.
+fn response_too_large(limit: u64) -> Flow {
+    warn!("response body over the limit of {limit} bytes");
+    Flow::Respond(
+        LocalReply::new(502)
+            .body("response body too large")
+            .reason("response_too_large"),
+    )
+}
+
+impl Stage for BodyLimitStage {
+    fn on_request_headers(&mut self, _num_headers: usize, end_of_stream: bool) -> Flow {
+        let headers = hostcalls::get_map(MapType::HttpRequestHeaders).unwrap();
+        let request = RequestInfo::new(headers);
+        let route = self
+            .config
+            .routes
+            .iter()
+            .find(|route| route.matches.matches(&request));
+        self.limits = (
+            route
+                .and_then(|route| route.max_request_bytes)
+                .or(self.config.max_request_bytes),
+            route
+                .and_then(|route| route.max_response_bytes)
+                .or(self.config.max_response_bytes),
+        );
+        match (self.limits.0, content_length(MapType::HttpRequestHeaders)) {
+            (Some(limit), Some(length)) if length > limit => request_too_large(),
+            (Some(_), None) if !end_of_stream => {
+                self.holding = true;
+                Flow::HoldHeaders
+            }
+            _ => Flow::Continue,
+        }
+    }
+
+    fn on_request_body(&mut self, _body_size: usize, end_of_stream: bool) -> Flow {
+        match self.limits.0 {
+            Some(limit) if self.body_bytes.request.get() > limit => {
+                self.holding = false;
+                request_too_large()
+            }
+            _ if self.holding && !end_of_stream => Flow::Pause,
+            _ => {
+                self.holding = false;
+                Flow::Continue
+            }
+        }
+    }
+
+    fn on_response_headers(&mut self, _num_headers: usize, _end_of_stream: bool) -> Flow {
+        match (self.limits.1, content_length(MapType::HttpResponseHeaders)) {
+            (Some(limit), Some(length)) if length > limit => response_too_large(limit),
+            _ => Flow::Continue,
+        }
+    }
+
+    fn on_response_body(&mut self, _body_size: usize, _end_of_stream: bool) -> Flow {
+        match self.limits.1 {
+            Some(limit) if self.body_bytes.response.get() > limit => response_too_large(limit),
+            _ => Flow::Continue,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mock::Plugin;
+    use proxy_wasm::types::Action;
+    use serde_json::json;
+
+    fn plugin() -> Plugin {
+        let config = json!({"stages": [{
+            "type": "body_limit",
+            "max_request_bytes": 8,
+            "max_response_bytes": 16,
+            "routes": [{"match": {"path": {"prefix": "/upload"}}, "max_request_bytes": 32}]
+        }]});
+        Plugin::new("add_header_root", &config.to_string())
+    }
+
+    #[test]
+    fn rejects_declared_lengths_at_the_headers() {
+        let mut plugin = plugin();
+        let stream = plugin.stream();
+        stream.request_headers(&[(":path", "/"), ("content-length", "9")], false);
+        assert_eq!(stream.local_response().unwrap().status, 413);
+
+        let stream = plugin.stream();
+        stream.request_headers(&[(":path", "/upload"), ("content-length", "9")], false);
+        assert!(stream.local_response().is_none());
+        stream.request_body(b"123456789", true);
+        stream.response_headers(&[(":status", "200"), ("content-length", "17")], false);
+        assert_eq!(stream.local_response().unwrap().status, 502);
+    }
+
+    #[test]
+    fn counts_streamed_chunks_across_buffering() {
+        let config = json!({"stages": [
+            {"type": "body_limit", "max_request_bytes": 10},
+            // Buffers the whole body, so the host hands over every chunk received so far.
+            {"type": "hmac_signature", "tenants": [{"id": "acme", "secrets": ["s"]}]}
+        ]});
+        let mut plugin = Plugin::new("add_header_root", &config.to_string());
+        let stream = plugin.stream();
+        let signature = format!("sha256={}", "0".repeat(64));
+        stream.request_headers(
+            &[
+                (":path", "/"),
+                ("x-tenant-id", "acme"),
+                ("x-timestamp", "0"),
+                ("x-signature", &signature),
+            ],
+            false,
+        );
+        assert_eq!(stream.request_body(b"1234", false), Action::Pause);
+        assert_eq!(stream.request_body(b"5678", false), Action::Pause);
+        assert!(stream.local_response().is_none());
+        stream.request_body(b"9ab", false);
+        assert_eq!(stream.local_response().unwrap().status, 413);
+
+        let mut plugin = self::plugin();
+        let stream = plugin.stream();
+        stream.request_headers(&[(":path", "/")], false);
+        stream.request_body(b"1234", false);
+        stream.request_body(b"5678", false);
+        assert!(stream.local_response().is_none());
+        stream.request_body(b"9", true);
+        assert_eq!(stream.local_response().unwrap().status, 413);
+    }
+
+    #[test]
+    fn holds_unsized_requests_until_they_end_within_the_limit() {
+        let mut plugin = plugin();
+        let stream = plugin.stream();
+        assert_eq!(
+            stream.request_headers(&[(":path", "/")], false),
+            Action::Pause
+        );
+        assert_eq!(stream.request_body(b"12345", false), Action::Pause);
+        assert_eq!(stream.request_body(b"6789", false), Action::Pause);
+        assert_eq!(stream.local_response().unwrap().status, 413);
+        assert!(!stream.data(|data| data.request_resumed));
+
+        let stream = plugin.stream();
+        assert_eq!(
+            stream.request_headers(&[(":path", "/")], false),
+            Action::Pause
+        );
+        assert_eq!(stream.request_body(b"1234", false), Action::Pause);
+        assert_eq!(stream.request_body(b"5678", true), Action::Continue);
+        assert!(stream.local_response().is_none());
+
+        // Without a request limit, nothing waits.
+        let config = json!({"stages": [{"type": "body_limit", "max_response_bytes": 16}]});
+        let mut plugin = Plugin::new("add_header_root", &config.to_string());
+        let stream = plugin.stream();
+        assert_eq!(
+            stream.request_headers(&[(":path", "/")], false),
+            Action::Continue
+        );
+        assert_eq!(stream.request_body(b"123456789", false), Action::Continue);
+    }
+}
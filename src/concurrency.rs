@@ -0,0 +1,493 @@
+//! Load shedding by the number of requests in flight per route.
+//!
+//! Requests are counted in shared data from their headers until Envoy logs them, so the
+//! count covers every worker thread of a replica. A route's limit is either fixed or
+//! adapted to the latency the backend shows, in the spirit of Netflix's gradient limiter:
+//! the limit shrinks while recent latency rises above its long-term average.
+//!
+//! A request whose stream is never logged, say because its VM was replaced, would hold
+//! its count forever. Counts are therefore kept per epoch of `stale_after_ms`, and a
+//! request stops counting once the epoch after its own is over.
+
+use crate::config::{field, ConfigError};
+use crate::matcher::{Match, RequestInfo};
+use crate::pipeline::{Flow, LocalReply, Stage};
+use crate::ratelimit::update_shared;
+use crate::util::now_micros;
+use proxy_wasm::hostcalls;
+use proxy_wasm::types::MapType;
+use serde::Deserialize;
+use std::rc::Rc;
+
+/// Configuration of the `concurrency_limit` stage.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConcurrencyLimitConfig {
+    /// The first route that matches a request counts it; other requests are not limited.
+    pub routes: Vec<ConcurrencyRoute>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConcurrencyRoute {
+    /// Names the counter in shared data.
+    pub name: String,
+    #[serde(rename = "match", default)]
+    pub matches: Match,
+    /// A fixed limit.
+    pub max_concurrent: Option<u64>,
+    /// A limit adapted to the observed latency.
+    pub adaptive: Option<AdaptiveLimit>,
+    /// How long a request counts at least, when its end is never seen.
+    #[serde(default = "default_stale_after_ms")]
+    pub stale_after_ms: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdaptiveLimit {
+    #[serde(default = "default_initial_limit")]
+    pub initial_limit: u64,
+    #[serde(default = "default_min_limit")]
+    pub min_limit: u64,
+    #[serde(default = "default_max_limit")]
+    pub max_limit: u64,
+    /// How long latency is sampled before the limit is updated.
+    #[serde(default = "default_window_ms")]
+    pub window_ms: u64,
+    /// How far above its long-term average latency may rise before the limit shrinks.
+    #[serde(default = "default_tolerance")]
+    pub tolerance: f64,
+}
+
+fn default_stale_after_ms() -> u64 {
+    300_000
+}
+
+fn default_initial_limit() -> u64 {
+    20
+}
+
+fn default_min_limit() -> u64 {
+    1
+}
+
+fn default_max_limit() -> u64 {
+    1000
+}
+
+fn default_window_ms() -> u64 {
+    1000
+}
+
+fn default_tolerance() -> f64 {
+    1.5
+}
+
+impl ConcurrencyLimitConfig {
+    pub fn validate(&self, parent: &str) -> Result<(), ConfigError> {
+        if self.routes.is_empty() {
+            return Err(ConfigError::new(
+                field(parent, "routes"),
+                "at least one route is required",
+            ));
+        }
+        for (i, route) in self.routes.iter().enumerate() {
+            let route_field = field(parent, &format!("routes[{i}]"));
+            if route.name.is_empty() {
+                return Err(ConfigError::new(
+                    field(&route_field, "name"),
+                    "name must not be empty",
+                ));
+            }
+            if self.routes[..i]
+                .iter()
+                .any(|other| other.name == route.name)
+            {
+                return Err(ConfigError::new(
+                    field(&route_field, "name"),
+                    format!("duplicate route `{}`", route.name),
+                ));
+            }
+            route.matches.validate(&field(&route_field, "match"))?;
+            if route.stale_after_ms == 0 {
+                return Err(ConfigError::new(
+                    field(&route_field, "stale_after_ms"),
+                    "must be positive",
+                ));
+            }
+            match (route.max_concurrent, &route.adaptive) {
+                (Some(0), None) => {
+                    return Err(ConfigError::new(
+                        field(&route_field, "max_concurrent"),
+                        "must be positive",
+                    ))
+                }
+                (Some(_), None) => {}
+                (None, Some(adaptive)) => adaptive.validate(&field(&route_field, "adaptive"))?,
+                _ => {
+                    return Err(ConfigError::new(
+                        route_field,
+                        "exactly one of `max_concurrent` and `adaptive` is required",
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl AdaptiveLimit {
+    fn validate(&self, parent: &str) -> Result<(), ConfigError> {
+        if self.min_limit == 0 {
+            return Err(ConfigError::new(
+                field(parent, "min_limit"),
+                "must be positive",
+            ));
+        }
+        if !(self.min_limit..=self.max_limit).contains(&self.initial_limit) {
+            return Err(ConfigError::new(
+                field(parent, "initial_limit"),
+                "must be between `min_limit` and `max_limit`",
+            ));
+        }
+        if self.window_ms == 0 {
+            return Err(ConfigError::new(
+                field(parent, "window_ms"),
+                "must be positive",
+            ));
+        }
+        if self.tolerance < 1.0 {
+            return Err(ConfigError::new(
+                field(parent, "tolerance"),
+                "must be at least 1",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Updates the limit from the latency sampled over the window that just ended.
+    fn update(&self, state: &mut State) {
+        let short = state.latency_sum as f64 / state.samples as f64;
+        state.long_latency = if state.long_latency == 0.0 {
+            short
+        } else {
+            state.long_latency + (short - state.long_latency) / 10.0
+        };
+        let gradient = (self.tolerance * state.long_latency / short.max(1.0)).clamp(0.5, 1.0);
+        let limit = if gradient < 1.0 {
+            state.limit * gradient
+        } else if state.in_flight() * 2 >= state.limit as u64 {
+            // Only a backend kept busy tells whether it could take more.
+            state.limit + state.limit.sqrt()
+        } else {
+            state.limit
+        };
+        state.limit = limit.clamp(self.min_limit as f64, self.max_limit as f64);
+    }
+}
+
+/// What a route keeps in shared data.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct State {
+    /// Requests in flight that were counted in during the current epoch.
+    current: u64,
+    /// Requests in flight that were counted in during the epoch before.
+    previous: u64,
+    /// The current epoch, in units of `stale_after_ms`.
+    epoch: u64,
+    /// The adaptive limit; unused for fixed limits.
+    limit: f64,
+    /// Exponential average of the latency of past windows, in microseconds.
+    long_latency: f64,
+    window_start: u64,
+    latency_sum: u64,
+    samples: u64,
+}
+
+impl State {
+    const SIZE: usize = 64;
+
+    fn decode(value: Option<&[u8]>) -> Option<State> {
+        let value = value.filter(|value| value.len() == State::SIZE)?;
+        let word = |i: usize| u64::from_le_bytes(value[i * 8..][..8].try_into().unwrap());
+        Some(State {
+            current: word(0),
+            previous: word(1),
+            epoch: word(2),
+            limit: f64::from_bits(word(3)),
+            long_latency: f64::from_bits(word(4)),
+            window_start: word(5),
+            latency_sum: word(6),
+            samples: word(7),
+        })
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        [
+            self.current,
+            self.previous,
+            self.epoch,
+            self.limit.to_bits(),
+            self.long_latency.to_bits(),
+            self.window_start,
+            self.latency_sum,
+            self.samples,
+        ]
+        .iter()
+        .flat_map(|word| word.to_le_bytes())
+        .collect()
+    }
+
+    fn in_flight(&self) -> u64 {
+        self.current + self.previous
+    }
+
+    /// Moves on to `epoch`, dropping the counts of requests from before the last one.
+    fn advance(&mut self, epoch: u64) {
+        if epoch <= self.epoch {
+            return;
+        }
+        self.previous = if epoch == self.epoch + 1 {
+            self.current
+        } else {
+            0
+        };
+        self.current = 0;
+        self.epoch = epoch;
+    }
+}
+
+impl ConcurrencyRoute {
+    fn key(&self) -> String {
+        format!("wasmup.concurrency.{}", self.name)
+    }
+
+    fn epoch(&self, micros: u64) -> u64 {
+        micros / (self.stale_after_ms * 1000)
+    }
+
+    /// Counts a request in, unless the route is at its limit. `None` if shared data
+    /// could not be updated.
+    fn acquire(&self, now: u64) -> Option<bool> {
+        update_shared(&self.key(), |value| {
+            let mut state = State::decode(value).unwrap_or_else(|| State {
+                limit: self
+                    .adaptive
+                    .as_ref()
+                    .map_or(0.0, |adaptive| adaptive.initial_limit as f64),
+                window_start: now,
+                ..State::default()
+            });
+            state.advance(self.epoch(now));
+            let limit = self.max_concurrent.unwrap_or(state.limit as u64);
+            let admitted = state.in_flight() < limit;
+            state.current += u64::from(admitted);
+            (admitted, state.encode())
+        })
+    }
+
+    /// Counts a request out that was counted in at `start`.
+    fn release(&self, now: u64, start: u64) {
+        update_shared(&self.key(), |value| {
+            let mut state = State::decode(value).unwrap_or_default();
+            state.advance(self.epoch(now));
+            let epoch = self.epoch(start);
+            if epoch == state.epoch {
+                state.current = state.current.saturating_sub(1);
+            } else if epoch + 1 == state.epoch {
+                state.previous = state.previous.saturating_sub(1);
+            }
+            if let Some(adaptive) = &self.adaptive {
+                state.latency_sum += now.saturating_sub(start);
+                state.samples += 1;
+                if now.saturating_sub(state.window_start) >= adaptive.window_ms * 1000 {
+                    adaptive.update(&mut state);
+                    state.window_start = now;
+                    state.latency_sum = 0;
+                    state.samples = 0;
+                }
+            }
+            ((), state.encode())
+        });
+    }
+}
+
+/// Sheds requests to routes that have too many in flight.
+pub struct ConcurrencyLimitStage {
+    config: Rc<ConcurrencyLimitConfig>,
+    /// The route that counted the request in, and when.
+    admitted: Option<(usize, u64)>,
+}
+
+impl ConcurrencyLimitStage {
+    pub fn new(config: Rc<ConcurrencyLimitConfig>) -> ConcurrencyLimitStage {
+        ConcurrencyLimitStage {
+            config,
+            admitted: None,
+        }
+    }
+}
+
+impl Stage for ConcurrencyLimitStage {
+    fn on_request_headers(&mut self, _num_headers: usize, _end_of_stream: bool) -> Flow {
+        let request = RequestInfo::new(hostcalls::get_map(MapType::HttpRequestHeaders).unwrap());
+        let Some(i) = self
+            .config
+            .routes
+            .iter()
+            .position(|route| route.matches.matches(&request))
+        else {
+            return Flow::Continue;
+        };
+        let now = now_micros();
+        match self.config.routes[i].acquire(now) {
+            Some(true) => {
+                self.admitted = Some((i, now));
+                Flow::Continue
+            }
+            Some(false) => Flow::Respond(
+                LocalReply::new(503)
+                    .body("concurrency limit reached")
+                    .reason("concurrency_limited"),
+            ),
+            None => Flow::Continue,
+        }
+    }
+
+    fn on_log(&mut self) {
+        if let Some((i, start)) = self.admitted.take() {
+            self.config.routes[i].release(now_micros(), start);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::State;
+    use crate::mock::{with_host, Plugin, Stream};
+    use serde_json::json;
+    use std::time::Duration;
+
+    fn state(route: &str) -> State {
+        let key = format!("wasmup.concurrency.{route}");
+        with_host(|host| State::decode(host.shared_data.get(&key).map(|(value, _)| &value[..])))
+            .unwrap()
+    }
+
+    fn advance(millis: u64) {
+        with_host(|host| host.now += Duration::from_millis(millis));
+    }
+
+    /// Starts a request and returns its stream if it was admitted.
+    fn start(plugin: &mut Plugin, path: &str) -> Option<Stream> {
+        let stream = plugin.stream();
+        stream.request_headers(&[(":method", "GET"), (":path", path)], true);
+        match stream.local_response() {
+            Some(reply) => {
+                assert_eq!(reply.status, 503);
+                None
+            }
+            None => Some(stream),
+        }
+    }
+
+    #[test]
+    fn fixed_limit_sheds_until_a_request_finishes() {
+        let config = json!({"stages": [{
+            "type": "concurrency_limit",
+            "routes": [{"name": "httpbin", "match": {"path": {"prefix": "/get"}}, "max_concurrent": 2}]
+        }]});
+        let mut plugin = Plugin::new("add_header_root", &config.to_string());
+        let first = start(&mut plugin, "/get").unwrap();
+        let _second = start(&mut plugin, "/get").unwrap();
+        assert!(start(&mut plugin, "/get").is_none());
+        // Other routes are not counted.
+        assert!(start(&mut plugin, "/status/200").is_some());
+        assert_eq!(state("httpbin").in_flight(), 2);
+
+        first.log();
+        first.done();
+        assert_eq!(state("httpbin").in_flight(), 1);
+        assert!(start(&mut plugin, "/get").is_some());
+    }
+
+    #[test]
+    fn adaptive_limit_shrinks_when_latency_rises() {
+        with_host(|host| host.now = Duration::from_secs(1_700_000_000));
+        let config = json!({"stages": [{
+            "type": "concurrency_limit",
+            "routes": [{"name": "fragile", "adaptive": {"initial_limit": 10, "min_limit": 2, "window_ms": 100}}]
+        }]});
+        let mut plugin = Plugin::new("add_header_root", &config.to_string());
+        // Runs windows of `concurrency` requests taking `latency` milliseconds each.
+        let mut run = |windows: usize, concurrency: usize, latency: u64| {
+            for _ in 0..windows {
+                let streams: Vec<_> = (0..concurrency)
+                    .map(|_| start(&mut plugin, "/").unwrap())
+                    .collect();
+                advance(latency);
+                for stream in streams {
+                    stream.log();
+                }
+            }
+        };
+
+        // A busy backend with steady latency lets the limit grow.
+        run(5, 8, 100);
+        let steady = state("fragile");
+        assert_eq!(steady.in_flight(), 0);
+        assert_eq!(steady.long_latency, 100_000.0);
+        assert!(steady.limit > 10.0, "{steady:?}");
+
+        // A backend slowing down makes it shrink, until the slower latency becomes the
+        // long-term average.
+        run(4, 2, 500);
+        let slow = state("fragile");
+        assert!(slow.limit < 3.0, "{slow:?}");
+        let admitted: Vec<_> = (0..10).map_while(|_| start(&mut plugin, "/")).collect();
+        assert_eq!(admitted.len(), slow.limit as usize);
+    }
+
+    #[test]
+    fn counts_of_lost_requests_expire() {
+        let config = json!({"stages": [{
+            "type": "concurrency_limit",
+            "routes": [{"name": "lossy", "max_concurrent": 2, "stale_after_ms": 60_000}]
+        }]});
+        let mut plugin = Plugin::new("add_header_root", &config.to_string());
+        // Neither stream is ever logged, as when their VM goes away.
+        start(&mut plugin, "/").unwrap();
+        advance(30_000);
+        start(&mut plugin, "/").unwrap();
+        assert!(start(&mut plugin, "/").is_none());
+
+        // Requests count until the epoch after their own is over.
+        advance(60_000);
+        assert!(start(&mut plugin, "/").is_none());
+        advance(30_000);
+        let late = start(&mut plugin, "/").unwrap();
+        assert_eq!(state("lossy").in_flight(), 1);
+
+        // A request logged after its count expired does not free anyone else's.
+        advance(120_000);
+        let fresh = start(&mut plugin, "/").unwrap();
+        late.log();
+        assert_eq!(state("lossy").in_flight(), 1);
+        fresh.log();
+        assert_eq!(state("lossy").in_flight(), 0);
+    }
+
+    #[test]
+    fn rejects_routes_with_both_limits() {
+        let config = json!({"stages": [{
+            "type": "concurrency_limit",
+            "routes": [{"name": "a", "max_concurrent": 1, "adaptive": {}}]
+        }]});
+        let (_, accepted) = Plugin::try_new("add_header_root", &config.to_string());
+        assert!(!accepted);
+        assert!(with_host(|host| host.logged(
+            "exactly one of `max_concurrent` and `adaptive` is required"
+        )));
+    }
+}
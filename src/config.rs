@@ -0,0 +1,330 @@
+use crate::access_log::AccessLogConfig;
+use crate::api_key::ApiKeyConfig;
+use crate::basic_auth::BasicAuthConfig;
+use crate::body_limit::BodyLimitConfig;
+use crate::concurrency::ConcurrencyLimitConfig;
+use crate::ext_authz::ExtAuthzConfig;
+use crate::headers::HeadersConfig;
+use crate::json_transform::JsonTransformConfig;
+use crate::jwt::JwtConfig;
+use crate::metrics::MetricsConfig;
+use crate::quota::QuotaConfig;
+use crate::ratelimit::LocalRateLimitConfig;
+use crate::request_id::RequestIdConfig;
+use crate::rls::GlobalRateLimitConfig;
+use crate::rules::HeaderRule;
+use crate::signature::SignatureConfig;
+use crate::template::Template;
+use crate::tracing::TracingConfig;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::fmt;
+use std::rc::Rc;
+
+/// Plugin configuration, taken from the `config` field of the EnvoyExtensionPolicy.
+///
+/// The filter runs `stages` in order. A configuration without a `stages` key is
+/// read as the configuration of a single `headers` stage.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub stages: Vec<StageConfig>,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+    pub access_log: Option<AccessLogConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StageConfig {
+    Headers(Rc<HeadersConfig>),
+    Jwt(Rc<JwtConfig>),
+    ApiKey(Rc<ApiKeyConfig>),
+    BasicAuth(Rc<BasicAuthConfig>),
+    HmacSignature(Rc<SignatureConfig>),
+    ExtAuthz(Rc<ExtAuthzConfig>),
+    LocalRateLimit(Rc<LocalRateLimitConfig>),
+    Quota(Rc<QuotaConfig>),
+    GlobalRateLimit(Rc<GlobalRateLimitConfig>),
+    ConcurrencyLimit(Rc<ConcurrencyLimitConfig>),
+    Tracing(Rc<TracingConfig>),
+    RequestId(Rc<RequestIdConfig>),
+    BodyLimit(Rc<BodyLimitConfig>),
+    JsonTransform(Rc<JsonTransformConfig>),
+}
+
+impl Default for Config {
+    /// Without any configuration the filter keeps its original behaviour.
+    fn default() -> Self {
+        let headers = HeadersConfig {
+            response_headers: vec![HeaderRule::Set {
+                name: "x-wasm-custom".to_string(),
+                value: Template::literal("FOO"),
+            }],
+            ..HeadersConfig::default()
+        };
+        Config {
+            stages: vec![StageConfig::Headers(Rc::new(headers))],
+            metrics: MetricsConfig::default(),
+            access_log: None,
+        }
+    }
+}
+
+/// A configuration error, pointing at the offending field.
+#[derive(Debug)]
+pub struct ConfigError {
+    pub field: String,
+    pub message: String,
+}
+
+impl ConfigError {
+    pub fn new(field: impl Into<String>, message: impl Into<String>) -> Self {
+        ConfigError {
+            field: field.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "invalid plugin config at `{}`: {}",
+            self.field, self.message
+        )
+    }
+}
+
+impl Config {
+    /// Parses and validates the raw plugin configuration.
+    /// An empty buffer yields the default configuration.
+    pub fn parse(raw: &[u8]) -> Result<Config, ConfigError> {
+        if raw.iter().all(u8::is_ascii_whitespace) {
+            return Ok(Config::default());
+        }
+        Config::parse_flat(raw, |value| {
+            let headers: HeadersConfig = deserialize(value)?;
+            headers.validate("")?;
+            Ok(StageConfig::Headers(Rc::new(headers)))
+        })
+    }
+
+    /// Parses a configuration that either lists its `stages` or is the flat
+    /// configuration of the single stage that `single` reads.
+    pub fn parse_flat(
+        raw: &[u8],
+        single: impl FnOnce(serde_json::Value) -> Result<StageConfig, ConfigError>,
+    ) -> Result<Config, ConfigError> {
+        let mut value = if raw.iter().all(u8::is_ascii_whitespace) {
+            serde_json::Value::Object(serde_json::Map::new())
+        } else {
+            serde_json::from_slice(raw)
+                .map_err(|err| ConfigError::new("config", err.to_string()))?
+        };
+        if value.get("stages").is_some() {
+            let config: Config = deserialize(value)?;
+            config.validate()?;
+            return Ok(config);
+        }
+        let metrics = take_section(&mut value, "metrics")?.unwrap_or_default();
+        let access_log = take_section(&mut value, "access_log")?;
+        let config = Config {
+            stages: vec![single(value)?],
+            metrics,
+            access_log,
+        };
+        config.validate_sections()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        self.stages
+            .iter()
+            .enumerate()
+            .try_for_each(|(i, stage)| stage.validate(&format!("stages[{i}]")))?;
+        for (i, stage) in self.stages.iter().enumerate() {
+            if let StageConfig::Quota(config) = stage {
+                config.validate_consumers(&format!("stages[{i}]"), &self.stages[..i])?;
+            }
+        }
+        self.validate_sections()
+    }
+
+    /// Validates the sections that apply to the whole pipeline.
+    fn validate_sections(&self) -> Result<(), ConfigError> {
+        self.metrics.validate("metrics")?;
+        if let Some(access_log) = &self.access_log {
+            access_log.validate("access_log")?;
+        }
+        Ok(())
+    }
+}
+
+/// Removes and reads the section `name`, which the flat form takes next to the fields
+/// of its stage.
+fn take_section<T: DeserializeOwned>(
+    value: &mut serde_json::Value,
+    name: &str,
+) -> Result<Option<T>, ConfigError> {
+    let Some(section) = value.as_object_mut().and_then(|map| map.remove(name)) else {
+        return Ok(None);
+    };
+    deserialize(section).map(Some).map_err(|err| {
+        let path = match err.field.as_str() {
+            "config" => name.to_string(),
+            path => field(name, path),
+        };
+        ConfigError::new(path, err.message)
+    })
+}
+
+impl StageConfig {
+    /// The stage's `type`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            StageConfig::Headers(_) => "headers",
+            StageConfig::Jwt(_) => "jwt",
+            StageConfig::ApiKey(_) => "api_key",
+            StageConfig::BasicAuth(_) => "basic_auth",
+            StageConfig::HmacSignature(_) => "hmac_signature",
+            StageConfig::ExtAuthz(_) => "ext_authz",
+            StageConfig::LocalRateLimit(_) => "local_rate_limit",
+            StageConfig::Quota(_) => "quota",
+            StageConfig::GlobalRateLimit(_) => "global_rate_limit",
+            StageConfig::ConcurrencyLimit(_) => "concurrency_limit",
+            StageConfig::Tracing(_) => "tracing",
+            StageConfig::RequestId(_) => "request_id",
+            StageConfig::BodyLimit(_) => "body_limit",
+            StageConfig::JsonTransform(_) => "json_transform",
+        }
+    }
+
+    /// Whether the stage sets request header `name`, or removes what the client sent,
+    /// on every request it lets through.
+    pub fn sets_request_header(&self, name: &str) -> bool {
+        let is = |header: &str| header.eq_ignore_ascii_case(name);
+        match self {
+            StageConfig::ApiKey(config) => is(&config.consumer_header),
+            StageConfig::BasicAuth(config) => config.user_header.as_deref().is_some_and(is),
+            StageConfig::Jwt(config) => config
+                .claims_to_headers
+                .iter()
+                .any(|mapping| is(&mapping.header)),
+            StageConfig::ExtAuthz(config) => config.trusted_upstream_headers.iter().any(|h| is(h)),
+            _ => false,
+        }
+    }
+
+    fn validate(&self, field: &str) -> Result<(), ConfigError> {
+        match self {
+            StageConfig::Headers(config) => config.validate(field),
+            StageConfig::Jwt(config) => config.validate(field),
+            StageConfig::ApiKey(config) => config.validate(field),
+            StageConfig::BasicAuth(config) => config.validate(field),
+            StageConfig::HmacSignature(config) => config.validate(field),
+            StageConfig::ExtAuthz(config) => config.validate(field),
+            StageConfig::LocalRateLimit(config) => config.validate(field),
+            StageConfig::Quota(config) => config.validate(field),
+            StageConfig::GlobalRateLimit(config) => config.validate(field),
+            StageConfig::ConcurrencyLimit(config) => config.validate(field),
+            StageConfig::Tracing(config) => config.validate(field),
+            StageConfig::RequestId(config) => config.validate(field),
+            StageConfig::BodyLimit(config) => config.validate(field),
+            StageConfig::JsonTransform(config) => config.validate(field),
+        }
+    }
+}
+
+pub fn deserialize<T: DeserializeOwned>(value: serde_json::Value) -> Result<T, ConfigError> {
+    serde_path_to_error::deserialize(value).map_err(|err| {
+        let path = err.path().to_string();
+        let field = if path == "." {
+            "config".to_string()
+        } else {
+            path
+        };
+        ConfigError::new(field, err.into_inner().to_string())
+    })
+}
+
+/// Joins a parent field path and a child field name for error messages.
+pub fn field(parent: &str, name: &str) -> String {
+    if parent.is_empty() {
+        name.to_string()
+    } else {
+        format!("{parent}.{name}")
+    }
+}
+
+pub fn validate_header_name(field: &str, name: &str) -> Result<(), ConfigError> {
+    if name.is_empty() {
+        return Err(ConfigError::new(field, "header name must not be empty"));
+    }
+    // Envoy rejects mixed-case names on HTTP/2, so require them lowercase up front.
+    let valid = name
+        .bytes()
+        .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b"!#$%&'*+-.^_`|~".contains(&b));
+    if !valid {
+        return Err(ConfigError::new(
+            field,
+            format!("`{name}` is not a valid lowercase header name"),
+        ));
+    }
+    Ok(())
+}
+
+pub fn validate_header_value(field: &str, value: &str) -> Result<(), ConfigError> {
+    if value.bytes().any(|b| b == b'\r' || b == b'\n' || b == 0) {
+        return Err(ConfigError::new(
+            field,
+            "header value must not contain CR, LF or NUL",
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(raw: &str) -> String {
+        Config::parse(raw.as_bytes()).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn empty_config_sets_custom_header() {
+        let config = Config::parse(b"  ").unwrap();
+        let [StageConfig::Headers(headers)] = config.stages.as_slice() else {
+            panic!("expected a single headers stage");
+        };
+        assert!(matches!(
+            headers.response_headers.as_slice(),
+            [HeaderRule::Set { name, .. }] if name == "x-wasm-custom"
+        ));
+    }
+
+    #[test]
+    fn errors_name_the_offending_field() {
+        assert_eq!(
+            error(r#"{"response_headers": [{"op": "set", "name": "X-Team", "value": "a"}]}"#),
+            "invalid plugin config at `response_headers[0].name`: \
+             `X-Team` is not a valid lowercase header name"
+        );
+        assert_eq!(
+            error(
+                r#"{"stages": [{"type": "headers", "routes": [{"match": {"methods": ["get"]}}]}]}"#
+            ),
+            "invalid plugin config at `stages[0].routes[0].match.methods[0]`: \
+             `get` is not an uppercase HTTP method"
+        );
+        assert!(error(r#"{"response_header": {}}"#).starts_with(
+            "invalid plugin config at `response_header`: unknown field `response_header`"
+        ));
+        assert!(
+            error(r#"{"request_headers": [{"op": "set", "name": "a", "value": "${x"}]}"#)
+                .contains("unterminated placeholder")
+        );
+    }
+}
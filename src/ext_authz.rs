@@ -0,0 +1,725 @@
+//! External authorization: each request is checked by an authorization service before
+//! it goes upstream, like Envoy's `ext_authz` filter.
+//!
+//! Over HTTP, the service gets a request with the original method, the original path
+//! behind `path_prefix` and the `allowed_headers`. A 200 lets the request through; any
+//! other status is returned to the client. Over gRPC, the service implements
+//! `envoy.service.auth.v3.Authorization` and its `CheckResponse` decides, including the
+//! header changes. When the service cannot be reached, times out or fails, `fail_open`
+//! decides, like `failOpen` on the policy.
+
+use crate::config::{field, validate_header_name, ConfigError};
+use crate::pipeline::{Flow, LocalReply, Stage};
+use crate::protobuf::{self, DecodeError, Encoder};
+use log::warn;
+use proxy_wasm::hostcalls;
+use proxy_wasm::types::{BufferType, MapType};
+use serde::Deserialize;
+use std::rc::Rc;
+use std::time::Duration;
+
+/// Configuration of the `ext_authz` stage.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExtAuthzConfig {
+    /// Envoy cluster of the authorization service.
+    pub cluster: String,
+    #[serde(default)]
+    pub protocol: Protocol,
+    /// `:authority` of the check request, the cluster name by default.
+    pub authority: Option<String>,
+    /// Prepended to the original path in the check request. HTTP only.
+    #[serde(default)]
+    pub path_prefix: String,
+    #[serde(default = "default_timeout")]
+    pub timeout_ms: u64,
+    /// Whether requests go upstream when the service fails to answer.
+    #[serde(default)]
+    pub fail_open: bool,
+    /// Request headers sent to the service.
+    #[serde(default = "default_allowed_headers")]
+    pub allowed_headers: Vec<String>,
+    /// Headers of an allowing response set on the upstream request. HTTP only; a gRPC
+    /// service lists its header changes in the `CheckResponse`.
+    #[serde(default)]
+    pub allowed_upstream_headers: Vec<String>,
+    /// Request headers only the service may set, such as the consumer a quota counts.
+    /// The client's values never reach the service or the upstream; an allowing HTTP
+    /// response sets them like `allowed_upstream_headers`.
+    #[serde(default)]
+    pub trusted_upstream_headers: Vec<String>,
+    /// Headers of a denying response passed to the client. HTTP only.
+    #[serde(default = "default_allowed_client_headers")]
+    pub allowed_client_headers: Vec<String>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Protocol {
+    #[default]
+    Http,
+    Grpc,
+}
+
+fn default_timeout() -> u64 {
+    200
+}
+
+fn default_allowed_headers() -> Vec<String> {
+    vec!["authorization".to_string(), "cookie".to_string()]
+}
+
+fn default_allowed_client_headers() -> Vec<String> {
+    vec!["www-authenticate".to_string(), "location".to_string()]
+}
+
+impl ExtAuthzConfig {
+    pub fn validate(&self, parent: &str) -> Result<(), ConfigError> {
+        if self.cluster.is_empty() {
+            return Err(ConfigError::new(
+                field(parent, "cluster"),
+                "cluster must not be empty",
+            ));
+        }
+        if !self.path_prefix.is_empty() && !self.path_prefix.starts_with('/') {
+            return Err(ConfigError::new(
+                field(parent, "path_prefix"),
+                format!("`{}` must start with `/`", self.path_prefix),
+            ));
+        }
+        if self.timeout_ms == 0 {
+            return Err(ConfigError::new(
+                field(parent, "timeout_ms"),
+                "must be positive",
+            ));
+        }
+        for (name, headers) in [
+            ("allowed_headers", &self.allowed_headers),
+            ("allowed_upstream_headers", &self.allowed_upstream_headers),
+            ("trusted_upstream_headers", &self.trusted_upstream_headers),
+            ("allowed_client_headers", &self.allowed_client_headers),
+        ] {
+            for (i, header) in headers.iter().enumerate() {
+                validate_header_name(&field(parent, &format!("{name}[{i}]")), header)?;
+            }
+        }
+        if let Some(i) = self
+            .trusted_upstream_headers
+            .iter()
+            .position(|header| !mutable(header))
+        {
+            return Err(ConfigError::new(
+                field(parent, &format!("trusted_upstream_headers[{i}]")),
+                "the service cannot set `host`",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Asks the authorization service about each request.
+pub struct ExtAuthzStage {
+    config: Rc<ExtAuthzConfig>,
+}
+
+impl ExtAuthzStage {
+    pub fn new(config: Rc<ExtAuthzConfig>) -> ExtAuthzStage {
+        ExtAuthzStage { config }
+    }
+
+    /// Lets the request through or rejects it when the service could not decide.
+    fn fail(&self, reason: &str) -> Flow {
+        warn!("ext_authz {}: {reason}", self.config.cluster);
+        if self.config.fail_open {
+            Flow::Continue
+        } else {
+            Flow::Respond(
+                LocalReply::new(503)
+                    .body("authorization service unavailable")
+                    .reason("unavailable"),
+            )
+        }
+    }
+}
+
+impl Stage for ExtAuthzStage {
+    fn on_request_headers(&mut self, _num_headers: usize, _end_of_stream: bool) -> Flow {
+        for name in &self.config.trusted_upstream_headers {
+            hostcalls::remove_map_value(MapType::HttpRequestHeaders, name).unwrap();
+        }
+        let request = |name: &str| {
+            hostcalls::get_map_value(MapType::HttpRequestHeaders, name)
+                .unwrap()
+                .unwrap_or_default()
+        };
+        let allowed: Vec<(&str, String)> = self
+            .config
+            .allowed_headers
+            .iter()
+            .filter_map(|name| {
+                let value = hostcalls::get_map_value(MapType::HttpRequestHeaders, name).unwrap();
+                Some((name.as_str(), value?))
+            })
+            .collect();
+        let timeout = Duration::from_millis(self.config.timeout_ms);
+        let dispatched = match self.config.protocol {
+            Protocol::Http => {
+                let path = format!("{}{}", self.config.path_prefix, request(":path"));
+                let authority = self
+                    .config
+                    .authority
+                    .as_deref()
+                    .unwrap_or(&self.config.cluster);
+                let (method, host) = (request(":method"), request(":authority"));
+                let mut headers = vec![
+                    (":method", method.as_str()),
+                    (":path", path.as_str()),
+                    (":authority", authority),
+                    ("x-forwarded-host", host.as_str()),
+                ];
+                headers.extend(allowed.iter().map(|(name, value)| (*name, value.as_str())));
+                hostcalls::dispatch_http_call(&self.config.cluster, headers, None, vec![], timeout)
+            }
+            Protocol::Grpc => {
+                let check = check_request(&request, &allowed);
+                hostcalls::dispatch_grpc_call(
+                    &self.config.cluster,
+                    "envoy.service.auth.v3.Authorization",
+                    "Check",
+                    vec![],
+                    Some(&check),
+                    timeout,
+                )
+            }
+        };
+        match dispatched {
+            Ok(_) => Flow::Pause,
+            Err(status) => self.fail(&format!("{status:?}")),
+        }
+    }
+
+    fn on_http_call_response(
+        &mut self,
+        _token_id: u32,
+        _num_headers: usize,
+        body_size: usize,
+    ) -> Flow {
+        let response = hostcalls::get_map(MapType::HttpCallResponseHeaders).unwrap();
+        let header = |name: &str| response.iter().find(|(key, _)| key == name);
+        // A call that timed out or was reset arrives without headers.
+        let Some(status) = header(":status").and_then(|(_, value)| value.parse::<u32>().ok())
+        else {
+            return self.fail("no response");
+        };
+        if status >= 500 {
+            return self.fail(&status.to_string());
+        }
+        if status != 200 {
+            let body = hostcalls::get_buffer(BufferType::HttpCallResponseBody, 0, body_size)
+                .unwrap()
+                .unwrap_or_default();
+            let mut reply = LocalReply::new(status)
+                .body(String::from_utf8_lossy(&body))
+                .reason("denied");
+            for name in &self.config.allowed_client_headers {
+                if let Some((_, value)) = header(name) {
+                    reply = reply.header(name, value.clone());
+                }
+            }
+            return Flow::Respond(reply);
+        }
+        let upstream_headers = self.config.allowed_upstream_headers.iter();
+        for name in upstream_headers.chain(&self.config.trusted_upstream_headers) {
+            if let Some((_, value)) = header(name) {
+                hostcalls::set_map_value(MapType::HttpRequestHeaders, name, Some(value)).unwrap();
+            }
+        }
+        Flow::Continue
+    }
+
+    fn on_grpc_call_response(
+        &mut self,
+        _token_id: u32,
+        status_code: u32,
+        response_size: usize,
+    ) -> Flow {
+        if status_code != 0 {
+            return self.fail(&format!("gRPC status {status_code}"));
+        }
+        let message = hostcalls::get_buffer(BufferType::GrpcReceiveBuffer, 0, response_size)
+            .unwrap()
+            .unwrap_or_default();
+        let response = match CheckResponse::decode(&message) {
+            Ok(response) => response,
+            Err(err) => return self.fail(&format!("CheckResponse: {err}")),
+        };
+        if response.code != 0 {
+            let mut reply = LocalReply::new(response.denied_status.unwrap_or(403))
+                .body(response.denied_body)
+                .reason("denied");
+            for header in &response.headers {
+                reply = reply.header(&header.name, header.value.clone());
+            }
+            return Flow::Respond(reply);
+        }
+        let map = MapType::HttpRequestHeaders;
+        for name in response
+            .headers_to_remove
+            .iter()
+            .filter(|name| mutable(name))
+        {
+            hostcalls::remove_map_value(map, name).unwrap();
+        }
+        for header in response
+            .headers
+            .iter()
+            .filter(|header| mutable(&header.name))
+        {
+            let (name, value) = (header.name.as_str(), header.value.as_str());
+            let present = hostcalls::get_map_value(map, name).unwrap().is_some();
+            match header.action {
+                AppendAction::Append => hostcalls::add_map_value(map, name, value).unwrap(),
+                AppendAction::AddIfAbsent if present => {}
+                AppendAction::OverwriteIfExists if !present => {}
+                _ => hostcalls::set_map_value(map, name, Some(value)).unwrap(),
+            }
+        }
+        Flow::Continue
+    }
+}
+
+/// Whether the service may change the request header `name`. Like Envoy, the module
+/// keeps pseudo-headers and `host` out of its reach.
+fn mutable(name: &str) -> bool {
+    !name.starts_with(':') && name != "host"
+}
+
+/// Encodes the `CheckRequest` for the current request, with the `allowed` headers.
+fn check_request(request: &impl Fn(&str) -> String, allowed: &[(&str, String)]) -> Vec<u8> {
+    let path = request(":path");
+    let query = path.split_once('?').map_or("", |(_, query)| query);
+    Encoder::new()
+        // CheckRequest.attributes: AttributeContext
+        .message(1, |attributes| {
+            // AttributeContext.request: Request
+            attributes.message(4, |attribute_request| {
+                // Request.http: HttpRequest
+                attribute_request.message(2, |http| {
+                    http.string(1, &request("x-request-id"))
+                        .string(2, &request(":method"));
+                    for (name, value) in allowed {
+                        http.message(3, |entry| {
+                            entry.string(1, name).string(2, value);
+                        });
+                    }
+                    http.string(4, &path)
+                        .string(5, &request(":authority"))
+                        .string(6, &request(":scheme"))
+                        .string(7, query)
+                        .uint64(9, request("content-length").parse().unwrap_or(0));
+                });
+            });
+        })
+        .finish()
+}
+
+/// The parts of a `CheckResponse` the filter acts on.
+#[derive(Debug, Default)]
+struct CheckResponse {
+    /// `google.rpc.Code` of the decision; anything but `OK` denies the request.
+    code: u64,
+    /// Headers of the denied reply, or changes to the request when allowed.
+    headers: Vec<HeaderMutation>,
+    headers_to_remove: Vec<String>,
+    denied_status: Option<u32>,
+    denied_body: String,
+}
+
+#[derive(Debug)]
+struct HeaderMutation {
+    name: String,
+    value: String,
+    action: AppendAction,
+}
+
+/// `HeaderValueOption.HeaderAppendAction`, with the deprecated `append` flag mapped in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum AppendAction {
+    Append,
+    AddIfAbsent,
+    Overwrite,
+    OverwriteIfExists,
+}
+
+impl CheckResponse {
+    fn decode(message: &[u8]) -> Result<CheckResponse, DecodeError> {
+        let mut response = CheckResponse::default();
+        for field in protobuf::fields(message) {
+            match field? {
+                // status: google.rpc.Status
+                (1, status) => {
+                    for field in protobuf::fields(status.as_bytes()?) {
+                        if let (1, code) = field? {
+                            response.code = code.as_u64()?;
+                        }
+                    }
+                }
+                // denied_response: DeniedHttpResponse
+                (2, denied) => {
+                    for field in protobuf::fields(denied.as_bytes()?) {
+                        match field? {
+                            (1, status) => {
+                                for field in protobuf::fields(status.as_bytes()?) {
+                                    if let (1, code) = field? {
+                                        response.denied_status = Some(code.as_u64()? as u32)
+                                            .filter(|code| (200..600).contains(code));
+                                    }
+                                }
+                            }
+                            (2, header) => response.headers.push(header_mutation(header)?),
+                            (3, body) => response.denied_body = body.as_str()?.to_string(),
+                            _ => {}
+                        }
+                    }
+                }
+                // ok_response: OkHttpResponse
+                (3, ok) => {
+                    for field in protobuf::fields(ok.as_bytes()?) {
+                        match field? {
+                            (2, header) => response.headers.push(header_mutation(header)?),
+                            (5, name) => response
+                                .headers_to_remove
+                                .push(name.as_str()?.to_ascii_lowercase()),
+                            _ => {}
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(response)
+    }
+}
+
+/// Decodes a `HeaderValueOption`.
+fn header_mutation(option: protobuf::Value) -> Result<HeaderMutation, DecodeError> {
+    let (mut name, mut value) = (String::new(), String::new());
+    let (mut append, mut action) = (None, 0);
+    for field in protobuf::fields(option.as_bytes()?) {
+        match field? {
+            // header: HeaderValue
+            (1, header) => {
+                for field in protobuf::fields(header.as_bytes()?) {
+                    match field? {
+                        (1, key) => name = key.as_str()?.to_ascii_lowercase(),
+                        (2, text) => value = text.as_str()?.to_string(),
+                        (3, raw) => value = String::from_utf8_lossy(raw.as_bytes()?).into_owned(),
+                        _ => {}
+                    }
+                }
+            }
+            // append: google.protobuf.BoolValue
+            (2, flag) => {
+                append = Some(false);
+                for field in protobuf::fields(flag.as_bytes()?) {
+                    if let (1, flag) = field? {
+                        append = Some(flag.as_u64()? != 0);
+                    }
+                }
+            }
+            (3, value) => action = value.as_u64()?,
+            _ => {}
+        }
+    }
+    let action = match (append, action) {
+        (Some(true), _) => AppendAction::Append,
+        (Some(false), _) => AppendAction::Overwrite,
+        (None, 0) => AppendAction::Append,
+        (None, 1) => AppendAction::AddIfAbsent,
+        (None, 3) => AppendAction::OverwriteIfExists,
+        (None, _) => AppendAction::Overwrite,
+    };
+    Ok(HeaderMutation {
+        name,
+        value,
+        action,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mock::{with_host, GrpcCall, HttpCall, Plugin, Stream};
+    use crate::protobuf::{self, Encoder, Value};
+    use proxy_wasm::types::Action;
+    use serde_json::json;
+
+    fn plugin(extra: serde_json::Value) -> Plugin {
+        let mut stage = json!({
+            "type": "ext_authz",
+            "cluster": "authz",
+            "path_prefix": "/check",
+            "allowed_upstream_headers": ["x-user"]
+        });
+        stage
+            .as_object_mut()
+            .unwrap()
+            .extend(extra.as_object().unwrap().clone());
+        Plugin::new("add_header_root", &json!({"stages": [stage]}).to_string())
+    }
+
+    fn send(plugin: &mut Plugin) -> (Stream, HttpCall) {
+        let stream = plugin.stream();
+        let action = stream.request_headers(
+            &[
+                (":method", "POST"),
+                (":path", "/orders?id=1"),
+                (":authority", "shop.example.com"),
+                ("authorization", "Bearer abc"),
+                ("x-other", "kept back"),
+            ],
+            true,
+        );
+        assert_eq!(action, Action::Pause);
+        let call = with_host(|host| host.http_calls.last().unwrap().clone());
+        (stream, call)
+    }
+
+    #[test]
+    fn allowed_requests_get_upstream_headers() {
+        let mut plugin = plugin(json!({}));
+        let (stream, call) = send(&mut plugin);
+        assert_eq!(call.upstream, "authz");
+        assert_eq!(call.timeout.as_millis(), 200);
+        let sent = |name: &str| {
+            call.headers
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.as_str())
+        };
+        assert_eq!(sent(":method"), Some("POST"));
+        assert_eq!(sent(":path"), Some("/check/orders?id=1"));
+        assert_eq!(sent(":authority"), Some("authz"));
+        assert_eq!(sent("x-forwarded-host"), Some("shop.example.com"));
+        assert_eq!(sent("authorization"), Some("Bearer abc"));
+        assert_eq!(sent("x-other"), None);
+
+        stream.http_call_response(
+            call.token,
+            &[
+                (":status", "200"),
+                ("x-user", "alice"),
+                ("x-internal", "no"),
+            ],
+            b"",
+        );
+        assert!(stream.data(|data| data.request_resumed));
+        assert_eq!(stream.request_header("x-user").as_deref(), Some("alice"));
+        assert_eq!(stream.request_header("x-internal"), None);
+    }
+
+    #[test]
+    fn only_the_service_sets_trusted_headers() {
+        let mut plugin = plugin(json!({
+            "allowed_headers": ["authorization", "x-consumer"],
+            "trusted_upstream_headers": ["x-consumer"]
+        }));
+        for consumer in [Some("acme"), None] {
+            let stream = plugin.stream();
+            stream.request_headers(&[(":path", "/"), ("x-consumer", "mallory")], true);
+            let call = with_host(|host| host.http_calls.last().unwrap().clone());
+            assert!(!call.headers.iter().any(|(name, _)| name == "x-consumer"));
+            let response: Vec<_> = [(":status", "200")]
+                .into_iter()
+                .chain(consumer.map(|consumer| ("x-consumer", consumer)))
+                .collect();
+            stream.http_call_response(call.token, &response, b"");
+            assert_eq!(stream.request_header("x-consumer").as_deref(), consumer);
+        }
+    }
+
+    #[test]
+    fn bodies_wait_for_the_decision() {
+        let mut plugin = plugin(json!({}));
+        let stream = plugin.stream();
+        let action = stream.request_headers(&[(":method", "POST"), (":path", "/orders")], false);
+        assert_eq!(action, Action::Pause);
+        let call = with_host(|host| host.http_calls.last().unwrap().clone());
+        assert_eq!(stream.request_body(b"{\"id\":", false), Action::Pause);
+        assert_eq!(stream.request_body(b"1}", true), Action::Pause);
+        assert!(!stream.data(|data| data.request_resumed));
+
+        stream.http_call_response(call.token, &[(":status", "403")], b"");
+        assert_eq!(stream.local_response().unwrap().status, 403);
+        assert!(!stream.data(|data| data.request_resumed));
+
+        let stream = plugin.stream();
+        stream.request_headers(&[(":method", "POST"), (":path", "/orders")], false);
+        let call = with_host(|host| host.http_calls.last().unwrap().clone());
+        assert_eq!(stream.request_body(b"{\"id\":1}", true), Action::Pause);
+        stream.http_call_response(call.token, &[(":status", "200")], b"");
+        assert!(stream.data(|data| data.request_resumed));
+        assert_eq!(stream.data(|data| data.request_body.clone()), b"{\"id\":1}");
+    }
+
+    #[test]
+    fn denials_are_returned_to_the_client() {
+        let mut plugin = plugin(json!({}));
+        let (stream, call) = send(&mut plugin);
+        stream.http_call_response(
+            call.token,
+            &[
+                (":status", "401"),
+                ("www-authenticate", "Bearer"),
+                ("x-user", "alice"),
+            ],
+            b"login first",
+        );
+        let reply = stream.local_response().unwrap();
+        assert_eq!(reply.status, 401);
+        assert_eq!(
+            reply.headers,
+            vec![("www-authenticate".to_string(), "Bearer".to_string())]
+        );
+        assert_eq!(reply.body, b"login first");
+        assert!(!stream.data(|data| data.request_resumed));
+    }
+
+    #[test]
+    fn failures_follow_fail_open() {
+        let mut closed = plugin(json!({}));
+        let (stream, call) = send(&mut closed);
+        stream.http_call_response(call.token, &[], b"");
+        assert_eq!(stream.local_response().unwrap().status, 503);
+        assert!(with_host(|host| host.logged("ext_authz authz: no response")));
+
+        let mut open = plugin(json!({"fail_open": true}));
+        let (stream, call) = send(&mut open);
+        stream.http_call_response(call.token, &[(":status", "502")], b"");
+        assert!(stream.local_response().is_none());
+        assert!(stream.data(|data| data.request_resumed));
+    }
+
+    /// Encodes a `HeaderValueOption`, with the `append_action` if given.
+    fn header_option(encoder: &mut Encoder, field: u32, name: &str, value: &str, action: u64) {
+        encoder.message(field, |option| {
+            option
+                .message(1, |header| {
+                    header.string(1, name).string(2, value);
+                })
+                .uint64(3, action);
+        });
+    }
+
+    fn grpc_send(plugin: &mut Plugin) -> (Stream, GrpcCall) {
+        let stream = plugin.stream();
+        let action = stream.request_headers(
+            &[
+                (":method", "GET"),
+                (":path", "/orders?id=1"),
+                (":authority", "shop.example.com"),
+                ("authorization", "Bearer abc"),
+                ("x-user", "forged"),
+                ("x-tags", "a"),
+                ("x-debug", "1"),
+            ],
+            true,
+        );
+        assert_eq!(action, Action::Pause);
+        let call = with_host(|host| host.grpc_calls.last().unwrap().clone());
+        (stream, call)
+    }
+
+    #[test]
+    fn grpc_check_applies_header_changes() {
+        let mut plugin = plugin(json!({"protocol": "grpc"}));
+        let (stream, call) = grpc_send(&mut plugin);
+        assert_eq!(call.upstream, "authz");
+        assert_eq!(call.service, "envoy.service.auth.v3.Authorization");
+        assert_eq!(call.method, "Check");
+
+        // CheckRequest.attributes.request.http
+        let nested = |message: &[u8], field: u32| {
+            protobuf::fields(message)
+                .map(Result::unwrap)
+                .find(|(number, _)| *number == field)
+                .unwrap()
+                .1
+                .as_bytes()
+                .unwrap()
+                .to_vec()
+        };
+        let http = nested(&nested(&nested(&call.message, 1), 4), 2);
+        let http: Vec<_> = protobuf::fields(&http).map(Result::unwrap).collect();
+        assert!(http.contains(&(2, Value::Bytes(b"GET"))));
+        assert!(http.contains(&(4, Value::Bytes(b"/orders?id=1"))));
+        assert!(http.contains(&(5, Value::Bytes(b"shop.example.com"))));
+        assert!(http.contains(&(7, Value::Bytes(b"id=1"))));
+        let entry = Encoder::new()
+            .string(1, "authorization")
+            .string(2, "Bearer abc")
+            .finish();
+        assert!(http.contains(&(3, Value::Bytes(&entry))));
+        assert_eq!(http.iter().filter(|(number, _)| *number == 3).count(), 1);
+
+        let mut response = Encoder::new();
+        response.message(1, |_| {}).message(3, |ok| {
+            header_option(ok, 2, "X-User", "alice", 2);
+            header_option(ok, 2, "x-tags", "b", 0);
+            header_option(ok, 2, ":path", "/admin", 2);
+            ok.string(5, "x-debug");
+        });
+        stream.grpc_call_response(call.token, 0, &response.finish());
+        assert!(stream.data(|data| data.request_resumed));
+        let headers = stream.data(|data| data.request_headers.clone());
+        let values = |name: &str| -> Vec<&str> {
+            headers
+                .iter()
+                .filter(|(key, _)| key == name)
+                .map(|(_, value)| value.as_str())
+                .collect()
+        };
+        assert_eq!(values("x-user"), ["alice"]);
+        assert_eq!(values("x-tags"), ["a", "b"]);
+        assert_eq!(values(":path"), ["/orders?id=1"]);
+        assert!(values("x-debug").is_empty());
+    }
+
+    #[test]
+    fn grpc_denials_and_failures() {
+        let mut plugin = plugin(json!({"protocol": "grpc"}));
+        let (stream, call) = grpc_send(&mut plugin);
+        let mut response = Encoder::new();
+        // PERMISSION_DENIED, answered with a 401 and a challenge.
+        response
+            .message(1, |status| {
+                status.uint64(1, 7);
+            })
+            .message(2, |denied| {
+                denied.message(1, |status| {
+                    status.uint64(1, 401);
+                });
+                header_option(denied, 2, "www-authenticate", "Bearer", 0);
+                denied.string(3, "token expired");
+            });
+        stream.grpc_call_response(call.token, 0, &response.finish());
+        let reply = stream.local_response().unwrap();
+        assert_eq!(reply.status, 401);
+        assert_eq!(
+            reply.headers,
+            vec![("www-authenticate".to_string(), "Bearer".to_string())]
+        );
+        assert_eq!(reply.body, b"token expired");
+
+        // DEADLINE_EXCEEDED
+        let (stream, call) = grpc_send(&mut plugin);
+        stream.grpc_call_response(call.token, 4, b"");
+        assert_eq!(stream.local_response().unwrap().status, 503);
+        assert!(with_host(
+            |host| host.logged("ext_authz authz: gRPC status 4")
+        ));
+
+        let (stream, call) = grpc_send(&mut plugin);
+        stream.grpc_call_response(call.token, 0, &[0x0a, 0x05]);
+        assert_eq!(stream.local_response().unwrap().status, 503);
+    }
+}
@@ -0,0 +1,237 @@
+use crate::config::{field, ConfigError};
+use crate::matcher::{Match, RequestInfo};
+use crate::metrics::Metrics;
+use crate::pipeline::{Flow, Stage};
+use crate::rules::{self, validate_rules, HeaderRule, Phase};
+use proxy_wasm::hostcalls;
+use proxy_wasm::types::MapType;
+use serde::Deserialize;
+use std::rc::Rc;
+
+/// Configuration of the `headers` stage.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HeadersConfig {
+    /// Rules applied to the request headers before they are sent upstream.
+    #[serde(default)]
+    pub request_headers: Vec<HeaderRule>,
+    /// Rules applied to the response headers before they are sent downstream.
+    #[serde(default)]
+    pub response_headers: Vec<HeaderRule>,
+    /// Conditional rules, applied after the unconditional ones for every route that matches.
+    #[serde(default)]
+    pub routes: Vec<Route>,
+}
+
+/// Header rules that only apply to requests matching `match`. The match is evaluated
+/// once against the original request headers; its response rules run in the response phase.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Route {
+    #[serde(rename = "match", default)]
+    pub matches: Match,
+    #[serde(default)]
+    pub request_headers: Vec<HeaderRule>,
+    #[serde(default)]
+    pub response_headers: Vec<HeaderRule>,
+}
+
+impl HeadersConfig {
+    pub fn validate(&self, parent: &str) -> Result<(), ConfigError> {
+        validate_rules(&field(parent, "request_headers"), &self.request_headers)?;
+        validate_rules(&field(parent, "response_headers"), &self.response_headers)?;
+        for (i, route) in self.routes.iter().enumerate() {
+            let route_field = field(parent, &format!("routes[{i}]"));
+            route.matches.validate(&field(&route_field, "match"))?;
+            validate_rules(
+                &field(&route_field, "request_headers"),
+                &route.request_headers,
+            )?;
+            validate_rules(
+                &field(&route_field, "response_headers"),
+                &route.response_headers,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Rewrites request and response headers.
+pub struct HeadersStage {
+    config: Rc<HeadersConfig>,
+    metrics: Rc<Metrics>,
+    /// Indices of the routes whose match held for this request.
+    matched: Vec<usize>,
+}
+
+impl HeadersStage {
+    pub fn new(config: Rc<HeadersConfig>, metrics: Rc<Metrics>) -> HeadersStage {
+        HeadersStage {
+            config,
+            metrics,
+            matched: Vec::new(),
+        }
+    }
+}
+
+impl Stage for HeadersStage {
+    fn on_request_headers(&mut self, _num_headers: usize, _end_of_stream: bool) -> Flow {
+        let headers = hostcalls::get_map(MapType::HttpRequestHeaders).unwrap();
+        let request = RequestInfo::new(headers);
+        self.matched = self
+            .config
+            .routes
+            .iter()
+            .enumerate()
+            .filter(|(_, route)| route.matches.matches(&request))
+            .map(|(i, _)| i)
+            .collect();
+
+        let mut written = rules::apply(&self.config.request_headers, Phase::Request);
+        for &i in &self.matched {
+            written += rules::apply(&self.config.routes[i].request_headers, Phase::Request);
+        }
+        self.metrics.headers_injected(written);
+        Flow::Continue
+    }
+
+    fn on_response_headers(&mut self, _num_headers: usize, _end_of_stream: bool) -> Flow {
+        let mut written = rules::apply(&self.config.response_headers, Phase::Response);
+        for &i in &self.matched {
+            written += rules::apply(&self.config.routes[i].response_headers, Phase::Response);
+        }
+        self.metrics.headers_injected(written);
+        Flow::Continue
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mock::{with_host, Plugin};
+    use proxy_wasm::types::Action;
+
+    const REQUEST: &[(&str, &str)] = &[
+        (":method", "GET"),
+        (":authority", "www.example.com"),
+        (":path", "/get?debug=1"),
+    ];
+
+    #[test]
+    fn default_config_sets_custom_header() {
+        let mut plugin = Plugin::new("add_header_root", "");
+        let stream = plugin.stream();
+        assert_eq!(stream.request_headers(REQUEST, true), Action::Continue);
+        assert_eq!(
+            stream.response_headers(&[(":status", "200")], false),
+            Action::Continue
+        );
+        assert_eq!(
+            stream.response_header("x-wasm-custom").as_deref(),
+            Some("FOO")
+        );
+    }
+
+    #[test]
+    fn applies_rules_in_order() {
+        let mut plugin = Plugin::new(
+            "add_header_root",
+            r#"{
+                "request_headers": [
+                    {"op": "rename", "name": "x-legacy-user", "to": "x-user"},
+                    {"op": "remove", "name": "x-debug"},
+                    {"op": "add", "name": "x-tag", "value": "a"},
+                    {"op": "add", "name": "x-tag", "value": "b"}
+                ],
+                "response_headers": [
+                    {"op": "append", "name": "cache-control", "value": "no-transform"},
+                    {"op": "set", "name": "x-wasm-custom", "value": "BAR"}
+                ]
+            }"#,
+        );
+        let stream = plugin.stream();
+        let mut request = REQUEST.to_vec();
+        request.extend([("x-legacy-user", "alice"), ("x-debug", "1")]);
+        stream.request_headers(&request, true);
+        stream.response_headers(
+            &[(":status", "200"), ("cache-control", "max-age=60")],
+            false,
+        );
+
+        assert_eq!(stream.request_header("x-user").as_deref(), Some("alice"));
+        assert_eq!(stream.request_header("x-legacy-user"), None);
+        assert_eq!(stream.request_header("x-debug"), None);
+        let tags = stream.data(|data| {
+            (data.request_headers.iter())
+                .filter(|(name, _)| name == "x-tag")
+                .count()
+        });
+        assert_eq!(tags, 2);
+        assert_eq!(
+            stream.response_header("cache-control").as_deref(),
+            Some("max-age=60, no-transform")
+        );
+        assert_eq!(
+            stream.response_header("x-wasm-custom").as_deref(),
+            Some("BAR")
+        );
+    }
+
+    #[test]
+    fn renders_templates_with_fallbacks() {
+        let mut plugin = Plugin::new(
+            "add_header_root",
+            r#"{"response_headers": [
+                {"op": "set", "name": "x-request-id", "value": "${request.id}"},
+                {"op": "set", "name": "x-user", "value": "${header:x-user:-anonymous}"},
+                {"op": "set", "name": "x-status", "value": "${response.code}"},
+                {"op": "set", "name": "x-time", "value": "${now}"}
+            ]}"#,
+        );
+        with_host(|host| {
+            host.set_property(&["request", "id"], b"abc-123");
+            host.set_property(&["response", "code"], &200i64.to_le_bytes());
+            host.now = std::time::Duration::from_secs(1_792_030_571);
+        });
+        let stream = plugin.stream();
+        stream.request_headers(REQUEST, true);
+        stream.response_headers(&[(":status", "200")], false);
+
+        assert_eq!(
+            stream.response_header("x-request-id").as_deref(),
+            Some("abc-123")
+        );
+        assert_eq!(
+            stream.response_header("x-user").as_deref(),
+            Some("anonymous")
+        );
+        assert_eq!(stream.response_header("x-status").as_deref(), Some("200"));
+        assert_eq!(
+            stream.response_header("x-time").as_deref(),
+            Some("2026-10-15T02:16:11.000Z")
+        );
+    }
+
+    #[test]
+    fn route_rules_apply_only_to_matching_requests() {
+        let mut plugin = Plugin::new(
+            "add_header_root",
+            r#"{"routes": [{
+                "match": {
+                    "path": {"prefix": "/get"},
+                    "methods": ["GET"],
+                    "query": [{"name": "debug", "value": {"exact": "1"}}]
+                },
+                "response_headers": [{"op": "set", "name": "x-debug", "value": "on"}]
+            }]}"#,
+        );
+        let matching = plugin.stream();
+        matching.request_headers(REQUEST, true);
+        matching.response_headers(&[(":status", "200")], false);
+        assert_eq!(matching.response_header("x-debug").as_deref(), Some("on"));
+
+        let other = plugin.stream();
+        other.request_headers(&[(":method", "POST"), (":path", "/get?debug=1")], true);
+        other.response_headers(&[(":status", "200")], false);
+        assert_eq!(other.response_header("x-debug"), None);
+    }
+}
@@ -0,0 +1,610 @@
+//! JSON body rewrites, to shim legacy APIs at the gateway instead of in adapter services.
+//!
+//! The stage buffers a JSON body, applies its operations in order and rewrites the
+//! `content-length`. The headers are held back until the body is complete, so that they
+//! leave with the length of the rewritten body.
+
+use crate::config::{field, ConfigError};
+use crate::matcher::{Match, RequestInfo};
+use crate::pipeline::{Flow, LocalReply, Stage};
+use log::warn;
+use proxy_wasm::hostcalls;
+use proxy_wasm::types::{BufferType, MapType};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::fmt;
+use std::rc::Rc;
+
+/// Configuration of the `json_transform` stage.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JsonTransformConfig {
+    /// Requests whose bodies are rewritten, along with their responses.
+    #[serde(rename = "match", default)]
+    pub matches: Match,
+    #[serde(default)]
+    pub request: Vec<JsonOp>,
+    #[serde(default)]
+    pub response: Vec<JsonOp>,
+    /// Largest body that is buffered; larger requests get a 413 and responses a 502.
+    #[serde(default = "default_max_body_bytes")]
+    pub max_body_bytes: usize,
+}
+
+/// A single body operation, applied in order with the other operations of its phase.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase", deny_unknown_fields)]
+pub enum JsonOp {
+    /// Sets the value, creating missing objects on the way.
+    Set {
+        path: JsonPath,
+        value: Value,
+    },
+    Delete {
+        path: JsonPath,
+    },
+    /// Renames an object member in place.
+    Rename {
+        path: JsonPath,
+        to: String,
+    },
+    Move {
+        from: JsonPath,
+        to: JsonPath,
+    },
+    /// Replaces the body with an object holding it under `key`.
+    Wrap {
+        key: String,
+    },
+    /// Replaces the body with the value at `path`, if there is one.
+    Unwrap {
+        path: JsonPath,
+    },
+}
+
+fn default_max_body_bytes() -> usize {
+    1024 * 1024
+}
+
+/// A JSONPath without filters or recursion: `$.data.items[0]`, `$['odd key']`,
+/// `$.items[*].id`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct JsonPath {
+    raw: String,
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Key(String),
+    Index(usize),
+    /// Every member of an object or element of an array.
+    Wildcard,
+}
+
+impl TryFrom<String> for JsonPath {
+    type Error = String;
+
+    fn try_from(raw: String) -> Result<Self, Self::Error> {
+        let invalid = |reason: &str| format!("`{raw}` is not a valid path: {reason}");
+        let Some(mut rest) = raw.strip_prefix('$') else {
+            return Err(invalid("it must start with `$`"));
+        };
+        let mut segments = Vec::new();
+        while !rest.is_empty() {
+            if let Some(after) = rest.strip_prefix('.') {
+                let end = after.find(['.', '[']).unwrap_or(after.len());
+                let segment = match &after[..end] {
+                    "" => return Err(invalid("empty member name")),
+                    "*" => Segment::Wildcard,
+                    key => Segment::Key(key.to_string()),
+                };
+                segments.push(segment);
+                rest = &after[end..];
+            } else if let Some(after) = rest.strip_prefix('[') {
+                let Some(end) = after.find(']') else {
+                    return Err(invalid("unclosed `[`"));
+                };
+                let inner = &after[..end];
+                let quoted = ['\'', '"']
+                    .iter()
+                    .find_map(|&q| inner.strip_prefix(q).and_then(|s| s.strip_suffix(q)));
+                let segment = match (inner, quoted) {
+                    (_, Some(key)) => Segment::Key(key.to_string()),
+                    ("*", None) => Segment::Wildcard,
+                    (index, None) => Segment::Index(
+                        index
+                            .parse()
+                            .map_err(|_| invalid(&format!("`[{index}]` is not an index")))?,
+                    ),
+                };
+                segments.push(segment);
+                rest = &after[end + 1..];
+            } else {
+                return Err(invalid("expected `.` or `[`"));
+            }
+        }
+        Ok(JsonPath { raw, segments })
+    }
+}
+
+impl fmt::Display for JsonPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.raw)
+    }
+}
+
+impl JsonPath {
+    /// The segments leading to the parents of the values the path names, and the last one.
+    fn split(&self) -> (&[Segment], &Segment) {
+        let (last, parents) = self.segments.split_last().expect("validated path");
+        (parents, last)
+    }
+
+    fn is_definite(&self) -> bool {
+        !self.segments.contains(&Segment::Wildcard)
+    }
+
+    fn validate(&self, field: &str, definite: bool) -> Result<(), ConfigError> {
+        if self.segments.is_empty() {
+            return Err(ConfigError::new(field, "the path must name a member"));
+        }
+        if definite && !self.is_definite() {
+            return Err(ConfigError::new(
+                field,
+                format!("`{self}` must not contain wildcards"),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl JsonTransformConfig {
+    pub fn validate(&self, parent: &str) -> Result<(), ConfigError> {
+        self.matches.validate(&field(parent, "match"))?;
+        if self.request.is_empty() && self.response.is_empty() {
+            return Err(ConfigError::new(
+                parent.to_string(),
+                "at least one `request` or `response` operation is required",
+            ));
+        }
+        for (phase, ops) in [("request", &self.request), ("response", &self.response)] {
+            for (i, op) in ops.iter().enumerate() {
+                op.validate(&field(parent, &format!("{phase}[{i}]")))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl JsonOp {
+    fn validate(&self, parent: &str) -> Result<(), ConfigError> {
+        let path = |name| field(parent, name);
+        match self {
+            JsonOp::Set { path: target, .. } | JsonOp::Delete { path: target } => {
+                target.validate(&path("path"), false)
+            }
+            JsonOp::Rename { path: target, to } => {
+                target.validate(&path("path"), false)?;
+                if !matches!(target.split().1, Segment::Key(_)) {
+                    return Err(ConfigError::new(
+                        path("path"),
+                        format!("`{target}` must end with a member name"),
+                    ));
+                }
+                if to.is_empty() {
+                    return Err(ConfigError::new(path("to"), "name must not be empty"));
+                }
+                Ok(())
+            }
+            JsonOp::Move { from, to } => {
+                from.validate(&path("from"), true)?;
+                to.validate(&path("to"), true)
+            }
+            JsonOp::Wrap { key } if key.is_empty() => {
+                Err(ConfigError::new(path("key"), "key must not be empty"))
+            }
+            JsonOp::Wrap { .. } => Ok(()),
+            JsonOp::Unwrap { path: target } => target.validate(&path("path"), true),
+        }
+    }
+
+    fn apply(&self, body: &mut Value) {
+        match self {
+            JsonOp::Set { path, value } => set(body, path, value),
+            JsonOp::Delete { path } => {
+                take(body, path);
+            }
+            JsonOp::Rename { path, to } => {
+                let (parents, Segment::Key(key)) = path.split() else {
+                    return;
+                };
+                visit(body, parents, false, &mut |parent| {
+                    let Value::Object(map) = parent else {
+                        return;
+                    };
+                    let Some(index) = map.keys().position(|name| name == key) else {
+                        return;
+                    };
+                    let value = map.shift_remove(key).unwrap();
+                    map.shift_remove(to);
+                    map.shift_insert(index.min(map.len()), to.clone(), value);
+                });
+            }
+            JsonOp::Move { from, to } => {
+                if let Some(value) = take(body, from).pop() {
+                    set(body, to, &value);
+                }
+            }
+            JsonOp::Wrap { key } => {
+                let inner = body.take();
+                *body = Value::Object(Map::from_iter([(key.clone(), inner)]));
+            }
+            JsonOp::Unwrap { path } => {
+                let mut found = None;
+                visit(body, &path.segments, false, &mut |value| {
+                    found = Some(value.take());
+                });
+                if let Some(value) = found {
+                    *body = value;
+                }
+            }
+        }
+    }
+}
+
+/// Calls `f` on every value `segments` lead to. With `create`, missing object members
+/// are created as empty objects.
+fn visit(value: &mut Value, segments: &[Segment], create: bool, f: &mut dyn FnMut(&mut Value)) {
+    let Some((first, rest)) = segments.split_first() else {
+        return f(value);
+    };
+    match (first, value) {
+        (Segment::Key(key), Value::Object(map)) => {
+            if create && !map.contains_key(key) {
+                map.insert(key.clone(), Value::Object(Map::new()));
+            }
+            if let Some(child) = map.get_mut(key) {
+                visit(child, rest, create, f);
+            }
+        }
+        (Segment::Index(index), Value::Array(items)) => {
+            if let Some(child) = items.get_mut(*index) {
+                visit(child, rest, create, f);
+            }
+        }
+        (Segment::Wildcard, Value::Array(items)) => {
+            for child in items {
+                visit(child, rest, create, f);
+            }
+        }
+        (Segment::Wildcard, Value::Object(map)) => {
+            for child in map.values_mut() {
+                visit(child, rest, create, f);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn set(body: &mut Value, path: &JsonPath, value: &Value) {
+    let (parents, last) = path.split();
+    visit(body, parents, true, &mut |parent| match (last, parent) {
+        (Segment::Key(key), Value::Object(map)) => {
+            map.insert(key.clone(), value.clone());
+        }
+        (Segment::Index(index), Value::Array(items)) => {
+            if let Some(item) = items.get_mut(*index) {
+                *item = value.clone();
+            }
+        }
+        (Segment::Wildcard, Value::Array(items)) => items.fill(value.clone()),
+        (Segment::Wildcard, Value::Object(map)) => {
+            map.values_mut().for_each(|item| *item = value.clone())
+        }
+        _ => {}
+    });
+}
+
+/// Removes the values at `path` and returns them.
+fn take(body: &mut Value, path: &JsonPath) -> Vec<Value> {
+    let (parents, last) = path.split();
+    let mut taken = Vec::new();
+    visit(body, parents, false, &mut |parent| match (last, parent) {
+        (Segment::Key(key), Value::Object(map)) => taken.extend(map.shift_remove(key)),
+        (Segment::Index(index), Value::Array(items)) if *index < items.len() => {
+            taken.push(items.remove(*index))
+        }
+        (Segment::Wildcard, Value::Array(items)) => taken.append(items),
+        (Segment::Wildcard, Value::Object(map)) => {
+            taken.extend(std::mem::take(map).into_iter().map(|(_, value)| value))
+        }
+        _ => {}
+    });
+    taken
+}
+
+/// Whether the headers announce an uncompressed JSON body.
+fn is_json(map_type: MapType) -> bool {
+    let header = |name| hostcalls::get_map_value(map_type, name).unwrap();
+    let json = header("content-type").is_some_and(|content_type| {
+        let media_type = content_type.split(';').next().unwrap_or_default().trim();
+        media_type.eq_ignore_ascii_case("application/json")
+            || media_type.to_ascii_lowercase().ends_with("+json")
+    });
+    let encoded = header("content-encoding").is_some_and(|encoding| encoding != "identity");
+    json && !encoded
+}
+
+/// Rewrites the buffered JSON body of one direction.
+fn transform(
+    buffer: BufferType,
+    headers: MapType,
+    ops: &[JsonOp],
+    body_size: usize,
+) -> Result<(), String> {
+    if body_size == 0 {
+        return Ok(());
+    }
+    let raw = hostcalls::get_buffer(buffer, 0, body_size)
+        .unwrap()
+        .unwrap_or_default();
+    let mut body: Value = serde_json::from_slice(&raw).map_err(|err| err.to_string())?;
+    ops.iter().for_each(|op| op.apply(&mut body));
+    let raw = serde_json::to_vec(&body).unwrap();
+    hostcalls::set_buffer(buffer, 0, body_size, &raw).unwrap();
+    // Bodies sent without a length keep their framing.
+    if hostcalls::get_map_value(headers, "content-length")
+        .unwrap()
+        .is_some()
+    {
+        let length = raw.len().to_string();
+        hostcalls::set_map_value(headers, "content-length", Some(&length)).unwrap();
+    }
+    Ok(())
+}
+
+/// Rewrites the JSON bodies of matching requests and their responses.
+pub struct JsonTransformStage {
+    config: Rc<JsonTransformConfig>,
+    matched: bool,
+    /// Whether the request and response bodies are being buffered for a rewrite.
+    buffering: (bool, bool),
+}
+
+impl JsonTransformStage {
+    pub fn new(config: Rc<JsonTransformConfig>) -> JsonTransformStage {
+        JsonTransformStage {
+            config,
+            matched: false,
+            buffering: (false, false),
+        }
+    }
+}
+
+impl Stage for JsonTransformStage {
+    fn on_request_headers(&mut self, _num_headers: usize, end_of_stream: bool) -> Flow {
+        let headers = hostcalls::get_map(MapType::HttpRequestHeaders).unwrap();
+        self.matched = self.config.matches.matches(&RequestInfo::new(headers));
+        self.buffering.0 = self.matched
+            && !self.config.request.is_empty()
+            && !end_of_stream
+            && is_json(MapType::HttpRequestHeaders);
+        if self.buffering.0 {
+            Flow::HoldHeaders
+        } else {
+            Flow::Continue
+        }
+    }
+
+    fn on_request_body(&mut self, body_size: usize, end_of_stream: bool) -> Flow {
+        if !self.buffering.0 {
+            return Flow::Continue;
+        }
+        if body_size > self.config.max_body_bytes {
+            self.buffering.0 = false;
+            return Flow::Respond(
+                LocalReply::new(413)
+                    .body("request body too large")
+                    .reason("request_too_large"),
+            );
+        }
+        if !end_of_stream {
+            return Flow::Pause;
+        }
+        self.buffering.0 = false;
+        let ops = &self.config.request;
+        match transform(
+            BufferType::HttpRequestBody,
+            MapType::HttpRequestHeaders,
+            ops,
+            body_size,
+        ) {
+            Ok(()) => Flow::Continue,
+            Err(reason) => Flow::Respond(
+                LocalReply::new(400)
+                    .body(format!("request body is not valid JSON: {reason}"))
+                    .reason("invalid_request_json"),
+            ),
+        }
+    }
+
+    fn on_response_headers(&mut self, _num_headers: usize, end_of_stream: bool) -> Flow {
+        self.buffering.1 = self.matched
+            && !self.config.response.is_empty()
+            && !end_of_stream
+            && is_json(MapType::HttpResponseHeaders);
+        if self.buffering.1 {
+            Flow::HoldHeaders
+        } else {
+            Flow::Continue
+        }
+    }
+
+    fn on_response_body(&mut self, body_size: usize, end_of_stream: bool) -> Flow {
+        if !self.buffering.1 {
+            return Flow::Continue;
+        }
+        if body_size > self.config.max_body_bytes {
+            self.buffering.1 = false;
+            warn!(
+                "response body over the {} bytes buffered for rewriting",
+                self.config.max_body_bytes
+            );
+            return Flow::Respond(
+                LocalReply::new(502)
+                    .body("response body too large")
+                    .reason("response_too_large"),
+            );
+        }
+        if !end_of_stream {
+            return Flow::Pause;
+        }
+        self.buffering.1 = false;
+        let ops = &self.config.response;
+        match transform(
+            BufferType::HttpResponseBody,
+            MapType::HttpResponseHeaders,
+            ops,
+            body_size,
+        ) {
+            Ok(()) => Flow::Continue,
+            Err(reason) => {
+                warn!("response body is not valid JSON: {reason}");
+                Flow::Respond(
+                    LocalReply::new(502)
+                        .body("response body is not valid JSON")
+                        .reason("invalid_response_json"),
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mock::{with_host, Plugin, Stream};
+    use proxy_wasm::types::Action;
+    use serde_json::{json, Value};
+
+    #[test]
+    fn rewrites_bodies_and_their_length() {
+        let config = json!({"stages": [{
+            "type": "json_transform",
+            "match": {"path": {"prefix": "/v1/"}},
+            "request": [
+                {"op": "rename", "path": "$.user.name", "to": "full_name"},
+                {"op": "move", "from": "$.user.mail", "to": "$.contact.email"},
+                {"op": "delete", "path": "$.items[*].internal"},
+                {"op": "set", "path": "$.meta.version", "value": 2},
+                {"op": "wrap", "key": "data"}
+            ],
+            "response": [{"op": "unwrap", "path": "$['result']"}]
+        }]});
+        let mut plugin = Plugin::new("add_header_root", &config.to_string());
+        let stream = plugin.stream();
+        let body = json!({
+            "user": {"id": 7, "name": "Alice", "mail": "a@example.com"},
+            "items": [{"sku": "a", "internal": true}, {"sku": "b"}]
+        })
+        .to_string();
+        let length = body.len().to_string();
+        let headers = [
+            (":path", "/v1/users"),
+            ("content-type", "application/json; charset=utf-8"),
+            ("content-length", length.as_str()),
+        ];
+        assert_eq!(stream.request_headers(&headers, false), Action::Pause);
+        assert_eq!(
+            stream.request_body(&body.as_bytes()[..10], false),
+            Action::Pause
+        );
+        assert_eq!(
+            stream.request_body(&body.as_bytes()[10..], true),
+            Action::Continue
+        );
+
+        let sent = stream.data(|data| data.request_body.clone());
+        assert_eq!(
+            String::from_utf8(sent.clone()).unwrap(),
+            json!({"data": {
+                "user": {"id": 7, "full_name": "Alice"},
+                "items": [{"sku": "a"}, {"sku": "b"}],
+                "contact": {"email": "a@example.com"},
+                "meta": {"version": 2}
+            }})
+            .to_string()
+        );
+        assert_eq!(
+            stream.request_header("content-length").unwrap(),
+            sent.len().to_string()
+        );
+
+        stream.response_headers(
+            &[(":status", "200"), ("content-type", "application/json")],
+            false,
+        );
+        stream.response_body(br#"{"result": {"ok": true}}"#, true);
+        let received: Value =
+            serde_json::from_slice(&stream.data(|data| data.response_body.clone())).unwrap();
+        assert_eq!(received, json!({"ok": true}));
+    }
+
+    #[test]
+    fn leaves_other_bodies_alone_and_rejects_invalid_json() {
+        let config = json!({"stages": [{
+            "type": "json_transform",
+            "request": [{"op": "set", "path": "$.a", "value": 1}]
+        }]});
+        let mut plugin = Plugin::new("add_header_root", &config.to_string());
+        let stream = plugin.stream();
+        let headers = [(":path", "/"), ("content-type", "text/plain")];
+        assert_eq!(stream.request_headers(&headers, false), Action::Continue);
+        assert_eq!(stream.request_body(b"{}", true), Action::Continue);
+        assert_eq!(stream.data(|data| data.request_body.clone()), b"{}");
+
+        let stream = plugin.stream();
+        let headers = [(":path", "/"), ("content-type", "application/json")];
+        stream.request_headers(&headers, false);
+        stream.request_body(b"{\"a\":", true);
+        assert_eq!(stream.local_response().unwrap().status, 400);
+
+        let config = json!({"stages": [{
+            "type": "json_transform",
+            "request": [{"op": "move", "from": "$.items[*]", "to": "$.first"}]
+        }]});
+        let (_, accepted) = Plugin::try_new("add_header_root", &config.to_string());
+        assert!(!accepted);
+    }
+
+    #[test]
+    fn holds_headers_across_later_callouts() {
+        let config = json!({"stages": [
+            {"type": "json_transform", "request": [{"op": "set", "path": "$.a", "value": 1}]},
+            {"type": "ext_authz", "cluster": "authz"}
+        ]});
+        let mut plugin = Plugin::new("add_header_root", &config.to_string());
+        let headers = [(":path", "/"), ("content-type", "application/json")];
+        let allow = |stream: &Stream| {
+            let token = with_host(|host| host.http_calls.last().unwrap().token);
+            stream.http_call_response(token, &[(":status", "200")], b"");
+        };
+
+        // The decision comes first: the headers still wait for the rewritten body.
+        let stream = plugin.stream();
+        assert_eq!(stream.request_headers(&headers, false), Action::Pause);
+        allow(&stream);
+        assert!(!stream.data(|data| data.request_resumed));
+        assert_eq!(stream.request_body(b"{}", true), Action::Continue);
+        assert_eq!(stream.data(|data| data.request_body.clone()), br#"{"a":1}"#);
+
+        // The body comes first: it is rewritten once the request is allowed.
+        let stream = plugin.stream();
+        stream.request_headers(&headers, false);
+        assert_eq!(stream.request_body(b"{}", true), Action::Pause);
+        allow(&stream);
+        assert!(stream.data(|data| data.request_resumed));
+        assert_eq!(stream.data(|data| data.request_body.clone()), br#"{"a":1}"#);
+    }
+}
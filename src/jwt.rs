@@ -0,0 +1,782 @@
+//! Validation of `Authorization: Bearer` JSON Web Tokens.
+//!
+//! Tokens signed with RS256, ES256 or HS256 are checked against a JSON Web Key Set, then
+//! their `exp`, `nbf`, `iss` and `aud` claims against the configuration. Requests without a
+//! valid token are answered with a 401 and a `WWW-Authenticate: Bearer` challenge.
+
+use crate::config::{field, validate_header_name, validate_header_value, ConfigError};
+use crate::pipeline::{Flow, LocalReply, Stage};
+use crate::remote_jwks::RemoteJwks;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine as _;
+use hmac::{Hmac, Mac};
+use p256::ecdsa::signature::Verifier;
+use proxy_wasm::hostcalls;
+use proxy_wasm::types::MapType;
+use rsa::{BigUint, RsaPublicKey};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use sha2::Sha256;
+use std::fmt;
+use std::rc::Rc;
+use std::time::UNIX_EPOCH;
+
+/// Configuration of the `jwt` stage.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JwtConfig {
+    /// Keys that may sign tokens.
+    #[serde(default)]
+    pub jwks: Jwks,
+    /// A key set fetched from a cluster and refreshed periodically, used next to `jwks`.
+    pub remote_jwks: Option<RemoteJwks>,
+    /// Required value of the `iss` claim.
+    pub issuer: Option<String>,
+    /// Accepted `aud` values. When set, the token must name at least one of them.
+    #[serde(default)]
+    pub audiences: Vec<String>,
+    /// Leeway for `exp` and `nbf`, for clocks that drift apart.
+    #[serde(default = "default_clock_skew")]
+    pub clock_skew_seconds: u64,
+    /// `realm` of the `WWW-Authenticate` challenge.
+    #[serde(default = "default_realm")]
+    pub realm: String,
+    /// Whether the `Authorization` header is passed upstream once the token is verified.
+    #[serde(default = "default_forward")]
+    pub forward: bool,
+    /// Claims copied into upstream request headers. A header whose claim is missing is
+    /// removed, so clients cannot supply it themselves.
+    #[serde(default)]
+    pub claims_to_headers: Vec<ClaimHeader>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClaimHeader {
+    /// Claim name, or a dotted path into nested claims, e.g. `realm_access.roles`.
+    pub claim: String,
+    pub header: String,
+}
+
+fn default_clock_skew() -> u64 {
+    60
+}
+
+fn default_realm() -> String {
+    "wasmup".to_string()
+}
+
+fn default_forward() -> bool {
+    true
+}
+
+impl JwtConfig {
+    pub fn validate(&self, parent: &str) -> Result<(), ConfigError> {
+        match &self.remote_jwks {
+            Some(remote) => remote.validate(&field(parent, "remote_jwks"))?,
+            None if self.jwks.keys.is_empty() => {
+                return Err(ConfigError::new(
+                    field(parent, "jwks.keys"),
+                    "at least one key is required unless `remote_jwks` is set",
+                ));
+            }
+            None => {}
+        }
+        if self.realm.contains('"') || self.realm.contains('\\') {
+            return Err(ConfigError::new(
+                field(parent, "realm"),
+                "realm must not contain quotes or backslashes",
+            ));
+        }
+        validate_header_value(&field(parent, "realm"), &self.realm)?;
+        for (i, mapping) in self.claims_to_headers.iter().enumerate() {
+            let mapping_field = field(parent, &format!("claims_to_headers[{i}]"));
+            if mapping.claim.is_empty() || mapping.claim.split('.').any(str::is_empty) {
+                return Err(ConfigError::new(
+                    field(&mapping_field, "claim"),
+                    format!("`{}` is not a valid claim path", mapping.claim),
+                ));
+            }
+            validate_header_name(&field(&mapping_field, "header"), &mapping.header)?;
+        }
+        Ok(())
+    }
+
+    /// Checks the registered claims of a token whose signature was verified.
+    fn check_claims(&self, claims: &Map<String, Value>, now: f64) -> Result<(), JwtError> {
+        let skew = self.clock_skew_seconds as f64;
+        let time = |name: &str| match claims.get(name) {
+            None => Ok(None),
+            Some(value) => value.as_f64().map(Some).ok_or(JwtError::Malformed),
+        };
+        if time("exp")?.is_some_and(|exp| now >= exp + skew) {
+            return Err(JwtError::Expired);
+        }
+        if time("nbf")?.is_some_and(|nbf| now + skew < nbf) {
+            return Err(JwtError::NotYetValid);
+        }
+        if let Some(issuer) = &self.issuer {
+            if claims.get("iss").and_then(Value::as_str) != Some(issuer) {
+                return Err(JwtError::Issuer);
+            }
+        }
+        if !self.audiences.is_empty() {
+            let allowed = |aud: &Value| {
+                aud.as_str()
+                    .is_some_and(|aud| self.audiences.iter().any(|a| a == aud))
+            };
+            let ok = match claims.get("aud") {
+                Some(Value::Array(auds)) => auds.iter().any(allowed),
+                Some(aud) => allowed(aud),
+                None => false,
+            };
+            if !ok {
+                return Err(JwtError::Audience);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A JSON Web Key Set.
+#[derive(Debug, Default, Deserialize)]
+pub struct Jwks {
+    pub keys: Vec<Jwk>,
+}
+
+impl Jwks {
+    /// Parses a fetched key set. Keys this filter cannot use, such as encryption keys, are
+    /// skipped rather than rejecting the whole set.
+    pub fn parse_fetched(raw: &[u8]) -> Result<Jwks, String> {
+        #[derive(Deserialize)]
+        struct Set {
+            keys: Vec<Value>,
+        }
+        let set: Set = serde_json::from_slice(raw).map_err(|err| format!("invalid JWKS: {err}"))?;
+        let keys: Vec<Jwk> = set
+            .keys
+            .into_iter()
+            .filter_map(|key| serde_json::from_value(key).ok())
+            .collect();
+        if keys.is_empty() {
+            return Err("JWKS has no usable keys".to_string());
+        }
+        Ok(Jwks { keys })
+    }
+}
+
+/// A verification key. Its type fixes the one algorithm it is used with, so a token
+/// cannot ask for an RSA public key to be used as an HMAC secret.
+#[derive(Clone, Deserialize)]
+#[serde(try_from = "RawJwk")]
+pub struct Jwk {
+    pub kid: Option<String>,
+    key: Key,
+}
+
+#[derive(Clone)]
+enum Key {
+    Rsa(rsa::pkcs1v15::VerifyingKey<Sha256>),
+    Ec(p256::ecdsa::VerifyingKey),
+    Hmac(Vec<u8>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    Rs256,
+    Es256,
+    Hs256,
+}
+
+impl Algorithm {
+    fn from_name(name: &str) -> Option<Algorithm> {
+        match name {
+            "RS256" => Some(Algorithm::Rs256),
+            "ES256" => Some(Algorithm::Es256),
+            "HS256" => Some(Algorithm::Hs256),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Algorithm::Rs256 => "RS256",
+            Algorithm::Es256 => "ES256",
+            Algorithm::Hs256 => "HS256",
+        }
+    }
+}
+
+/// A JWK as it appears in the set, before its key material is decoded.
+#[derive(Deserialize)]
+struct RawJwk {
+    kty: String,
+    kid: Option<String>,
+    alg: Option<String>,
+    #[serde(rename = "use")]
+    usage: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+    crv: Option<String>,
+    x: Option<String>,
+    y: Option<String>,
+    k: Option<String>,
+}
+
+/// Smallest RSA modulus accepted, in bits.
+const MIN_RSA_BITS: usize = 2048;
+
+impl TryFrom<RawJwk> for Jwk {
+    type Error = String;
+
+    fn try_from(raw: RawJwk) -> Result<Self, Self::Error> {
+        if raw.usage.as_deref().is_some_and(|usage| usage != "sig") {
+            return Err("key is not a signature key (`use` must be `sig`)".to_string());
+        }
+        let param = |name: &str, value: &Option<String>| -> Result<Vec<u8>, String> {
+            let value = value
+                .as_deref()
+                .ok_or_else(|| format!("`{}` key is missing `{name}`", raw.kty))?;
+            URL_SAFE_NO_PAD
+                .decode(value)
+                .map_err(|err| format!("`{name}` is not base64url: {err}"))
+        };
+        let key = match raw.kty.as_str() {
+            "RSA" => {
+                let n = BigUint::from_bytes_be(&param("n", &raw.n)?);
+                let e = BigUint::from_bytes_be(&param("e", &raw.e)?);
+                if n.bits() < MIN_RSA_BITS {
+                    return Err(format!("RSA keys must have at least {MIN_RSA_BITS} bits"));
+                }
+                let key =
+                    RsaPublicKey::new(n, e).map_err(|err| format!("invalid RSA key: {err}"))?;
+                Key::Rsa(rsa::pkcs1v15::VerifyingKey::new(key))
+            }
+            "EC" => {
+                if raw.crv.as_deref() != Some("P-256") {
+                    return Err("only EC keys on curve `P-256` are supported".to_string());
+                }
+                let (x, y) = (param("x", &raw.x)?, param("y", &raw.y)?);
+                if x.len() != 32 || y.len() != 32 {
+                    return Err("P-256 coordinates must be 32 bytes".to_string());
+                }
+                let point = p256::EncodedPoint::from_affine_coordinates(
+                    x.as_slice().into(),
+                    y.as_slice().into(),
+                    false,
+                );
+                let key = p256::ecdsa::VerifyingKey::from_encoded_point(&point)
+                    .map_err(|_| "EC key is not a point on P-256".to_string())?;
+                Key::Ec(key)
+            }
+            "oct" => {
+                let secret = param("k", &raw.k)?;
+                if secret.is_empty() {
+                    return Err("`k` must not be empty".to_string());
+                }
+                Key::Hmac(secret)
+            }
+            other => return Err(format!("unsupported key type `{other}`")),
+        };
+        let jwk = Jwk { kid: raw.kid, key };
+        if let Some(alg) = raw.alg {
+            if alg != jwk.algorithm().name() {
+                return Err(format!(
+                    "`{}` keys can only be used with {}, not `{alg}`",
+                    raw.kty,
+                    jwk.algorithm().name()
+                ));
+            }
+        }
+        Ok(jwk)
+    }
+}
+
+impl fmt::Debug for Jwk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Jwk")
+            .field("kid", &self.kid)
+            .field("alg", &self.algorithm())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Jwk {
+    fn algorithm(&self) -> Algorithm {
+        match self.key {
+            Key::Rsa(_) => Algorithm::Rs256,
+            Key::Ec(_) => Algorithm::Es256,
+            Key::Hmac(_) => Algorithm::Hs256,
+        }
+    }
+
+    fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        match &self.key {
+            Key::Rsa(key) => rsa::pkcs1v15::Signature::try_from(signature)
+                .is_ok_and(|signature| key.verify(message, &signature).is_ok()),
+            Key::Ec(key) => p256::ecdsa::Signature::from_slice(signature)
+                .is_ok_and(|signature| key.verify(message, &signature).is_ok()),
+            Key::Hmac(secret) => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(secret).unwrap();
+                mac.update(message);
+                mac.verify_slice(signature).is_ok()
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Header {
+    alg: String,
+    kid: Option<String>,
+}
+
+/// Verifies the signature of `token` with one of `keys` and returns its claims.
+fn verify<'a>(
+    token: &str,
+    keys: impl Iterator<Item = &'a Jwk>,
+) -> Result<Map<String, Value>, JwtError> {
+    let mut parts = token.split('.');
+    let (Some(header), Some(payload), Some(signature), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(JwtError::Malformed);
+    };
+    let decode = |part: &str| {
+        URL_SAFE_NO_PAD
+            .decode(part)
+            .map_err(|_| JwtError::Malformed)
+    };
+    let header: Header =
+        serde_json::from_slice(&decode(header)?).map_err(|_| JwtError::Malformed)?;
+    let alg = Algorithm::from_name(&header.alg).ok_or(JwtError::Algorithm)?;
+    let signature = decode(signature)?;
+
+    let mut candidates = keys
+        .filter(|key| key.algorithm() == alg)
+        .filter(|key| header.kid.is_none() || key.kid.is_none() || key.kid == header.kid)
+        .peekable();
+    if candidates.peek().is_none() {
+        return Err(JwtError::UnknownKey);
+    }
+    let signed = &token[..token.len() - token.rsplit('.').next().unwrap().len() - 1];
+    if !candidates.any(|key| key.verify(signed.as_bytes(), &signature)) {
+        return Err(JwtError::Signature);
+    }
+    serde_json::from_slice(&decode(payload)?).map_err(|_| JwtError::Malformed)
+}
+
+/// Why a request was not authenticated. The messages follow Envoy's `jwt_authn` filter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JwtError {
+    Missing,
+    Malformed,
+    Algorithm,
+    UnknownKey,
+    Signature,
+    Expired,
+    NotYetValid,
+    Issuer,
+    Audience,
+}
+
+impl JwtError {
+    /// The `reason` tag of the rejections counter.
+    fn reason(&self) -> &'static str {
+        match self {
+            JwtError::Missing => "missing_token",
+            JwtError::Malformed => "malformed_token",
+            JwtError::Algorithm => "unsupported_algorithm",
+            JwtError::UnknownKey => "unknown_key",
+            JwtError::Signature => "invalid_signature",
+            JwtError::Expired => "expired_token",
+            JwtError::NotYetValid => "token_not_yet_valid",
+            JwtError::Issuer => "invalid_issuer",
+            JwtError::Audience => "invalid_audience",
+        }
+    }
+}
+
+impl fmt::Display for JwtError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            JwtError::Missing => "Jwt is missing",
+            JwtError::Malformed => "Jwt is not in the form of Header.Payload.Signature",
+            JwtError::Algorithm => "Jwt algorithm is not supported",
+            JwtError::UnknownKey => "Jwks doesn't have key to match kid or alg from Jwt",
+            JwtError::Signature => "Jwt verification fails",
+            JwtError::Expired => "Jwt is expired",
+            JwtError::NotYetValid => "Jwt not yet valid",
+            JwtError::Issuer => "Jwt issuer is not configured",
+            JwtError::Audience => "Audiences in Jwt are not allowed",
+        })
+    }
+}
+
+/// Authenticates requests with a bearer token.
+pub struct JwtStage {
+    config: Rc<JwtConfig>,
+}
+
+impl JwtStage {
+    pub fn new(config: Rc<JwtConfig>) -> JwtStage {
+        JwtStage { config }
+    }
+
+    fn authenticate(&self) -> Result<Map<String, Value>, JwtError> {
+        let token = bearer_token().ok_or(JwtError::Missing)?;
+        let remote = self.config.remote_jwks.as_ref().and_then(RemoteJwks::keys);
+        let remote_keys = remote.iter().flat_map(|jwks| &jwks.keys);
+        let claims = verify(&token, self.config.jwks.keys.iter().chain(remote_keys))?;
+        let now = hostcalls::get_current_time()
+            .unwrap()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        self.config.check_claims(&claims, now)?;
+        Ok(claims)
+    }
+
+    /// The 401 reply, with a challenge as described in RFC 6750.
+    fn challenge(&self, err: JwtError) -> LocalReply {
+        let realm = &self.config.realm;
+        let challenge = if err == JwtError::Missing {
+            format!("Bearer realm=\"{realm}\"")
+        } else {
+            format!(
+                "Bearer realm=\"{realm}\", error=\"invalid_token\", error_description=\"{err}\""
+            )
+        };
+        LocalReply::new(401)
+            .header("www-authenticate", challenge)
+            .body(err.to_string())
+            .reason(err.reason())
+    }
+
+    /// Lets an authenticated request through, with its claims in the mapped headers.
+    fn admit(&self, claims: Map<String, Value>) -> Flow {
+        let map = MapType::HttpRequestHeaders;
+        for mapping in &self.config.claims_to_headers {
+            match claim(&claims, &mapping.claim).and_then(header_value) {
+                Some(value) => hostcalls::set_map_value(map, &mapping.header, Some(&value)),
+                None => hostcalls::remove_map_value(map, &mapping.header),
+            }
+            .unwrap();
+        }
+        if !self.config.forward {
+            hostcalls::remove_map_value(map, "authorization").unwrap();
+        }
+        Flow::Continue
+    }
+}
+
+impl Stage for JwtStage {
+    fn on_request_headers(&mut self, _num_headers: usize, _end_of_stream: bool) -> Flow {
+        match self.authenticate() {
+            Ok(claims) => self.admit(claims),
+            // The key may have been rotated in since the set was last fetched.
+            Err(JwtError::UnknownKey)
+                if self
+                    .config
+                    .remote_jwks
+                    .as_ref()
+                    .and_then(RemoteJwks::refresh)
+                    .is_some() =>
+            {
+                Flow::Pause
+            }
+            Err(err) => Flow::Respond(self.challenge(err)),
+        }
+    }
+
+    fn on_http_call_response(
+        &mut self,
+        _token_id: u32,
+        _num_headers: usize,
+        body_size: usize,
+    ) -> Flow {
+        if let Some(remote) = &self.config.remote_jwks {
+            remote.store(body_size);
+        }
+        match self.authenticate() {
+            Ok(claims) => self.admit(claims),
+            Err(err) => Flow::Respond(self.challenge(err)),
+        }
+    }
+}
+
+fn bearer_token() -> Option<String> {
+    let value = hostcalls::get_map_value(MapType::HttpRequestHeaders, "authorization").unwrap()?;
+    let (scheme, token) = value.trim().split_once(' ')?;
+    let token = token.trim();
+    (scheme.eq_ignore_ascii_case("bearer") && !token.is_empty()).then(|| token.to_string())
+}
+
+fn claim<'a>(claims: &'a Map<String, Value>, path: &str) -> Option<&'a Value> {
+    let mut parts = path.split('.');
+    let first = claims.get(parts.next()?)?;
+    parts.try_fold(first, |value, part| value.get(part))
+}
+
+/// Renders a claim as a header value: strings as-is, arrays comma-separated and anything
+/// else as JSON. Values that cannot be carried in a header are dropped.
+fn header_value(value: &Value) -> Option<String> {
+    let rendered = match value {
+        Value::String(s) => s.clone(),
+        Value::Array(items) => items
+            .iter()
+            .map(|item| match item {
+                Value::String(s) => s.clone(),
+                other => other.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join(","),
+        Value::Null => return None,
+        other => other.to_string(),
+    };
+    (!rendered.bytes().any(|b| b == b'\r' || b == b'\n' || b == 0)).then_some(rendered)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mock::{with_host, Plugin};
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine as _;
+    use hmac::{Hmac, Mac};
+    use p256::ecdsa::signature::Signer;
+    use proxy_wasm::types::Action;
+    use rsa::pkcs8::DecodePrivateKey;
+    use rsa::traits::PublicKeyParts;
+    use serde_json::{json, Value};
+    use sha2::Sha256;
+    use std::time::Duration;
+
+    const NOW: u64 = 1_700_000_000;
+    const SECRET: &[u8] = b"a-test-secret-of-reasonable-size";
+
+    /// A 2048-bit key used only by these tests.
+    const RSA_KEY: &str = include_str!("../testdata/rsa-2048.pem");
+
+    fn b64(bytes: &[u8]) -> String {
+        URL_SAFE_NO_PAD.encode(bytes)
+    }
+
+    fn token(header: Value, claims: Value, sign: impl Fn(&[u8]) -> Vec<u8>) -> String {
+        let signed = format!(
+            "{}.{}",
+            b64(header.to_string().as_bytes()),
+            b64(claims.to_string().as_bytes())
+        );
+        let signature = sign(signed.as_bytes());
+        format!("{signed}.{}", b64(&signature))
+    }
+
+    fn hs256(claims: Value) -> String {
+        token(json!({"alg": "HS256", "typ": "JWT"}), claims, |message| {
+            let mut mac = Hmac::<Sha256>::new_from_slice(SECRET).unwrap();
+            mac.update(message);
+            mac.finalize().into_bytes().to_vec()
+        })
+    }
+
+    fn plugin(keys: Value, extra: Value) -> Plugin {
+        with_host(|host| host.now = Duration::from_secs(NOW));
+        let mut config = json!({"type": "jwt", "jwks": {"keys": keys}});
+        config
+            .as_object_mut()
+            .unwrap()
+            .extend(extra.as_object().unwrap().clone());
+        Plugin::new("add_header_root", &json!({"stages": [config]}).to_string())
+    }
+
+    fn hmac_plugin(extra: Value) -> Plugin {
+        plugin(
+            json!([{"kty": "oct", "kid": "h1", "k": b64(SECRET)}]),
+            extra,
+        )
+    }
+
+    fn request(token: &str) -> Vec<(&'static str, String)> {
+        vec![
+            (":method", "GET".to_string()),
+            (":path", "/get".to_string()),
+            ("authorization", format!("Bearer {token}")),
+            ("x-user", "spoofed".to_string()),
+        ]
+    }
+
+    fn send(plugin: &mut Plugin, headers: &[(&str, String)]) -> crate::mock::Stream {
+        let stream = plugin.stream();
+        let headers: Vec<(&str, &str)> = headers.iter().map(|(k, v)| (*k, v.as_str())).collect();
+        stream.request_headers(&headers, true);
+        stream
+    }
+
+    #[test]
+    fn maps_claims_of_a_valid_token() {
+        let mut plugin = hmac_plugin(json!({
+            "issuer": "https://issuer.example",
+            "audiences": ["api"],
+            "forward": false,
+            "claims_to_headers": [
+                {"claim": "sub", "header": "x-sub"},
+                {"claim": "realm_access.roles", "header": "x-roles"},
+                {"claim": "email", "header": "x-user"}
+            ]
+        }));
+        let token = hs256(json!({
+            "sub": "alice",
+            "iss": "https://issuer.example",
+            "aud": ["other", "api"],
+            "exp": NOW + 60,
+            "realm_access": {"roles": ["admin", "dev"]}
+        }));
+        let stream = send(&mut plugin, &request(&token));
+        assert!(stream.local_response().is_none());
+        assert_eq!(stream.request_header("x-sub").as_deref(), Some("alice"));
+        assert_eq!(
+            stream.request_header("x-roles").as_deref(),
+            Some("admin,dev")
+        );
+        assert_eq!(stream.request_header("x-user"), None);
+        assert_eq!(stream.request_header("authorization"), None);
+    }
+
+    #[test]
+    fn rejects_invalid_tokens_with_a_challenge() {
+        let mut plugin =
+            hmac_plugin(json!({"issuer": "me", "audiences": ["api"], "clock_skew_seconds": 0}));
+        let valid = json!({"iss": "me", "aud": "api", "exp": NOW + 60});
+        let with = |patch: Value| {
+            let mut claims = valid.clone();
+            claims
+                .as_object_mut()
+                .unwrap()
+                .extend(patch.as_object().unwrap().clone());
+            hs256(claims)
+        };
+        let mut forged = hs256(valid.clone());
+        forged.push('A');
+        let cases = [
+            (with(json!({"exp": NOW})), "Jwt is expired"),
+            (with(json!({"nbf": NOW + 10})), "Jwt not yet valid"),
+            (with(json!({"iss": "you"})), "Jwt issuer is not configured"),
+            (
+                with(json!({"aud": ["web"]})),
+                "Audiences in Jwt are not allowed",
+            ),
+            (forged, "Jwt verification fails"),
+            (
+                "not-a-jwt".to_string(),
+                "Jwt is not in the form of Header.Payload.Signature",
+            ),
+            (
+                token(json!({"alg": "none"}), valid.clone(), |_| Vec::new()),
+                "Jwt algorithm is not supported",
+            ),
+            (
+                token(json!({"alg": "HS256", "kid": "h2"}), valid.clone(), |_| {
+                    Vec::new()
+                }),
+                "Jwks doesn't have key to match kid or alg from Jwt",
+            ),
+        ];
+        for (token, reason) in cases {
+            let stream = send(&mut plugin, &request(&token));
+            let reply = stream.local_response().expect(reason);
+            assert_eq!(reply.status, 401, "{reason}");
+            assert_eq!(String::from_utf8(reply.body).unwrap(), reason);
+            let challenge = &reply
+                .headers
+                .iter()
+                .find(|(k, _)| k == "www-authenticate")
+                .unwrap()
+                .1;
+            assert_eq!(
+                challenge,
+                &format!("Bearer realm=\"wasmup\", error=\"invalid_token\", error_description=\"{reason}\"")
+            );
+        }
+
+        let stream = plugin.stream();
+        assert_eq!(
+            stream.request_headers(&[(":path", "/")], true),
+            Action::Pause
+        );
+        let reply = stream.local_response().unwrap();
+        assert_eq!(
+            reply.headers,
+            vec![(
+                "www-authenticate".to_string(),
+                "Bearer realm=\"wasmup\"".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn verifies_rs256_and_es256_tokens() {
+        let rsa_key = rsa::RsaPrivateKey::from_pkcs8_pem(RSA_KEY).unwrap();
+        let ec_key = p256::ecdsa::SigningKey::from_slice(&[7; 32]).unwrap();
+        let point = ec_key.verifying_key().to_encoded_point(false);
+        let mut plugin = plugin(
+            json!([
+                {"kty": "RSA", "kid": "r1", "alg": "RS256", "use": "sig",
+                 "n": b64(&rsa_key.n().to_bytes_be()), "e": b64(&rsa_key.e().to_bytes_be())},
+                {"kty": "EC", "kid": "e1", "crv": "P-256",
+                 "x": b64(point.x().unwrap()), "y": b64(point.y().unwrap())}
+            ]),
+            json!({}),
+        );
+        let claims = json!({"sub": "bob", "exp": NOW + 60});
+
+        let signer = rsa::pkcs1v15::SigningKey::<Sha256>::new(rsa_key);
+        let rs256 = token(
+            json!({"alg": "RS256", "kid": "r1"}),
+            claims.clone(),
+            |message| Box::<[u8]>::from(signer.sign(message)).into_vec(),
+        );
+        let es256 = token(json!({"alg": "ES256"}), claims.clone(), |message| {
+            let signature: p256::ecdsa::Signature = ec_key.sign(message);
+            signature.to_vec()
+        });
+        for token in [rs256, es256] {
+            assert!(send(&mut plugin, &request(&token))
+                .local_response()
+                .is_none());
+        }
+
+        // An HS256 token "signed" with the RSA public key must not be accepted.
+        let confused = token(json!({"alg": "HS256", "kid": "r1"}), claims, |_| Vec::new());
+        let reply = send(&mut plugin, &request(&confused))
+            .local_response()
+            .unwrap();
+        assert_eq!(reply.status, 401);
+    }
+
+    #[test]
+    fn rejects_unusable_keys() {
+        let cases = [
+            (json!({"kty": "oct", "k": ""}), "`k` must not be empty"),
+            (
+                json!({"kty": "oct", "k": "c2VjcmV0", "alg": "RS256"}),
+                "`oct` keys can only be used with HS256",
+            ),
+            (
+                json!({"kty": "EC", "crv": "P-384", "x": "", "y": ""}),
+                "only EC keys on curve `P-256`",
+            ),
+            (
+                json!({"kty": "RSA", "n": "AQAB", "e": "AQAB"}),
+                "RSA keys must have at least 2048 bits",
+            ),
+            (json!({"kty": "OKP"}), "unsupported key type `OKP`"),
+        ];
+        for (key, message) in cases {
+            let config = json!({"jwks": {"keys": [key]}});
+            let (_, accepted) = Plugin::try_new("auth_root", &config.to_string());
+            assert!(!accepted);
+            let logged = format!("auth_root: invalid plugin config at `jwks.keys[0]`: {message}");
+            assert!(with_host(|host| host.logged(&logged)), "{message}");
+        }
+    }
+}
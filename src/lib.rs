@@ -1,28 +1,142 @@
+mod access_log;
+mod api_key;
+mod basic_auth;
+mod body_limit;
+mod concurrency;
+mod config;
+mod ext_authz;
+mod headers;
+mod json_transform;
+mod jwt;
+mod matcher;
+mod metrics;
+#[cfg(test)]
+mod mock;
+mod pipeline;
+mod protobuf;
+mod quota;
+mod ratelimit;
+mod registry;
+mod remote_jwks;
+mod request_id;
+mod rls;
+mod rules;
+mod signature;
+mod template;
+mod tracing;
+mod util;
+
+use log::error;
+use proxy_wasm::hostcalls;
 use proxy_wasm::traits::{Context, HttpContext, RootContext};
-use proxy_wasm::types::{Action, ContextType};
+use proxy_wasm::types::{ContextType, LogLevel};
+use registry::{FilterFactory, FilterKind};
+use std::rc::Rc;
+
+struct Root {
+    root_id: String,
+    kind: Option<&'static FilterKind>,
+    factory: Option<Rc<dyn FilterFactory>>,
+}
+
+impl Root {
+    fn new(root_id: String) -> Root {
+        Root {
+            kind: registry::lookup(&root_id),
+            root_id,
+            factory: None,
+        }
+    }
+}
+
+impl Context for Root {
+    fn on_http_call_response(
+        &mut self,
+        token_id: u32,
+        num_headers: usize,
+        body_size: usize,
+        _num_trailers: usize,
+    ) {
+        if let Some(factory) = &self.factory {
+            factory.on_http_call_response(token_id, num_headers, body_size);
+        }
+    }
+}
 
-struct Root;
-impl Context for Root {}
 impl RootContext for Root {
     fn get_type(&self) -> Option<ContextType> {
         Some(ContextType::HttpContext)
     }
 
-    fn create_http_context(&self, _id: u32) -> Option<Box<dyn HttpContext>> {
-        Some(Box::new(Filter))
+    fn on_configure(&mut self, _plugin_configuration_size: usize) -> bool {
+        let Some(kind) = self.kind else {
+            let known: Vec<_> = registry::FILTERS.iter().map(|k| k.root_id).collect();
+            error!(
+                "unknown root id `{}`, expected one of: {}",
+                self.root_id,
+                known.join(", ")
+            );
+            return false;
+        };
+        let raw = self.get_plugin_configuration().unwrap_or_default();
+        match (kind.configure)(&raw) {
+            Ok(factory) => {
+                if let Some(period) = factory.tick_period() {
+                    self.set_tick_period(period);
+                }
+                self.factory = Some(factory);
+                true
+            }
+            Err(err) => {
+                error!("{}: {err}", kind.root_id);
+                false
+            }
+        }
+    }
+
+    fn on_tick(&mut self) {
+        if let Some(factory) = &self.factory {
+            factory.on_tick();
+        }
     }
-}
 
-struct Filter;
-impl Context for Filter {}
-impl HttpContext for Filter {
-    fn on_http_response_headers(&mut self, _num: usize, _eos: bool) -> Action {
-        // Add a header to every response
-        self.set_http_response_header("x-wasm-custom", Some("FOO"));
-        Action::Continue
+    fn create_http_context(&self, context_id: u32) -> Option<Box<dyn HttpContext>> {
+        // Envoy only creates streams for roots whose configuration was accepted.
+        self.factory
+            .as_ref()
+            .map(|factory| factory.create_filter(context_id))
     }
 }
 
 proxy_wasm::main! {{
-    proxy_wasm::set_root_context(|_vm_id| Box::new(Root));
+    proxy_wasm::set_log_level(LogLevel::Info);
+    proxy_wasm::set_root_context(|_context_id| {
+        let root_id = hostcalls::get_property(vec!["plugin_root_id"])
+            .ok()
+            .flatten()
+            .and_then(|raw| String::from_utf8(raw).ok())
+            .unwrap_or_default();
+        Box::new(Root::new(root_id))
+    });
 }}
+
+#[cfg(test)]
+mod tests {
+    use crate::mock::{with_host, Plugin};
+
+    #[test]
+    fn rejects_unknown_root_id() {
+        let (_, accepted) = Plugin::try_new("nope_root", "");
+        assert!(!accepted);
+        assert!(with_host(|host| host.logged("unknown root id `nope_root`")));
+    }
+
+    #[test]
+    fn rejects_invalid_config() {
+        let (_, accepted) = Plugin::try_new("add_header_root", r#"{"routes": 1}"#);
+        assert!(!accepted);
+        assert!(with_host(
+            |host| host.logged("add_header_root: invalid plugin config at `routes`")
+        ));
+    }
+}
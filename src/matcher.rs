@@ -0,0 +1,314 @@
+use crate::config::{validate_header_name, ConfigError};
+use regex_lite::Regex;
+use serde::Deserialize;
+
+/// Conditions on the downstream request. Every condition that is set must hold.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Match {
+    /// Matched against the path without its query string.
+    pub path: Option<StringMatch>,
+    /// Any of the listed methods, e.g. `[GET, HEAD]`.
+    #[serde(default)]
+    pub methods: Vec<String>,
+    pub authority: Option<StringMatch>,
+    #[serde(default)]
+    pub headers: Vec<HeaderMatch>,
+    #[serde(default)]
+    pub query: Vec<QueryMatch>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum StringMatch {
+    Exact(String),
+    Prefix(String),
+    Suffix(String),
+    Regex(Pattern),
+}
+
+/// A header condition. Without `value` it only checks that the header is
+/// present, or absent when `present: false`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HeaderMatch {
+    pub name: String,
+    pub value: Option<StringMatch>,
+    #[serde(default = "default_present")]
+    pub present: bool,
+}
+
+/// A query parameter condition, with the same semantics as [`HeaderMatch`].
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QueryMatch {
+    pub name: String,
+    pub value: Option<StringMatch>,
+    #[serde(default = "default_present")]
+    pub present: bool,
+}
+
+fn default_present() -> bool {
+    true
+}
+
+/// A regular expression that must match the whole input.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Pattern(Regex);
+
+impl TryFrom<String> for Pattern {
+    type Error = String;
+
+    fn try_from(raw: String) -> Result<Self, Self::Error> {
+        Regex::new(&format!("^(?:{raw})$"))
+            .map(Pattern)
+            .map_err(|err| format!("invalid regex `{raw}`: {err}"))
+    }
+}
+
+/// The parts of the downstream request that matches are evaluated against.
+pub struct RequestInfo {
+    pub method: String,
+    pub authority: String,
+    pub path: String,
+    pub query: Vec<(String, String)>,
+    pub headers: Vec<(String, String)>,
+}
+
+impl RequestInfo {
+    pub fn new(headers: Vec<(String, String)>) -> RequestInfo {
+        let pseudo = |name: &str| {
+            headers
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.clone())
+                .unwrap_or_default()
+        };
+        let method = pseudo(":method");
+        let authority = pseudo(":authority");
+        let full_path = pseudo(":path");
+        let (path, query) = match full_path.split_once('?') {
+            Some((path, query)) => (path.to_string(), parse_query(query)),
+            None => (full_path, Vec::new()),
+        };
+        RequestInfo {
+            method,
+            authority,
+            path,
+            query,
+            headers,
+        }
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+impl Match {
+    pub fn matches(&self, request: &RequestInfo) -> bool {
+        self.path.as_ref().is_none_or(|m| m.matches(&request.path))
+            && (self.methods.is_empty() || self.methods.contains(&request.method))
+            && self
+                .authority
+                .as_ref()
+                .is_none_or(|m| m.matches(&request.authority))
+            && self
+                .headers
+                .iter()
+                .all(|h| matches_value(h.value.as_ref(), h.present, request.header(&h.name)))
+            && self
+                .query
+                .iter()
+                .all(|q| matches_value(q.value.as_ref(), q.present, request.query_param(&q.name)))
+    }
+
+    pub fn validate(&self, field: &str) -> Result<(), ConfigError> {
+        for (i, method) in self.methods.iter().enumerate() {
+            if method.is_empty() || !method.bytes().all(|b| b.is_ascii_uppercase()) {
+                return Err(ConfigError::new(
+                    format!("{field}.methods[{i}]"),
+                    format!("`{method}` is not an uppercase HTTP method"),
+                ));
+            }
+        }
+        for (i, header) in self.headers.iter().enumerate() {
+            validate_header_name(&format!("{field}.headers[{i}].name"), &header.name)?;
+            if header.value.is_some() && !header.present {
+                return Err(ConfigError::new(
+                    format!("{field}.headers[{i}]"),
+                    "`value` cannot be combined with `present: false`",
+                ));
+            }
+        }
+        for (i, param) in self.query.iter().enumerate() {
+            if param.name.is_empty() {
+                return Err(ConfigError::new(
+                    format!("{field}.query[{i}].name"),
+                    "query parameter name must not be empty",
+                ));
+            }
+            if param.value.is_some() && !param.present {
+                return Err(ConfigError::new(
+                    format!("{field}.query[{i}]"),
+                    "`value` cannot be combined with `present: false`",
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn matches_value(expected: Option<&StringMatch>, present: bool, actual: Option<&str>) -> bool {
+    match (expected, actual) {
+        (Some(expected), Some(actual)) => expected.matches(actual),
+        (Some(_), None) => false,
+        (None, actual) => actual.is_some() == present,
+    }
+}
+
+impl StringMatch {
+    pub fn matches(&self, input: &str) -> bool {
+        match self {
+            StringMatch::Exact(expected) => input == expected,
+            StringMatch::Prefix(prefix) => input.starts_with(prefix.as_str()),
+            StringMatch::Suffix(suffix) => input.ends_with(suffix.as_str()),
+            StringMatch::Regex(Pattern(regex)) => regex.is_match(input),
+        }
+    }
+}
+
+fn parse_query(query: &str) -> Vec<(String, String)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((key, value)) => (percent_decode(key), percent_decode(value)),
+            None => (percent_decode(pair), String::new()),
+        })
+        .collect()
+}
+
+fn percent_decode(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 2 < bytes.len() => match (hex(bytes[i + 1]), hex(bytes[i + 2])) {
+                (Some(high), Some(low)) => {
+                    out.push(high << 4 | low);
+                    i += 2;
+                }
+                _ => out.push(b'%'),
+            },
+            byte => out.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn hex(digit: u8) -> Option<u8> {
+    (digit as char).to_digit(16).map(|d| d as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{percent_decode, Match, RequestInfo, StringMatch};
+    use serde_json::json;
+
+    fn request(headers: &[(&str, &str)]) -> RequestInfo {
+        RequestInfo::new(
+            headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        )
+    }
+
+    fn matcher(value: serde_json::Value) -> Match {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn regexes_match_the_whole_input() {
+        let regex: StringMatch = serde_json::from_value(json!({"regex": "/api/v[0-9]+"})).unwrap();
+        assert!(regex.matches("/api/v2"));
+        assert!(!regex.matches("/api/v2/users"));
+        assert!(!regex.matches("/old/api/v2"));
+        // Alternations are anchored as a whole, not just their first and last branch.
+        let regex: StringMatch = serde_json::from_value(json!({"regex": "a|b"})).unwrap();
+        assert!(regex.matches("b"));
+        assert!(!regex.matches("ab"));
+        let invalid = serde_json::from_value::<StringMatch>(json!({"regex": "("}));
+        assert!(invalid
+            .unwrap_err()
+            .to_string()
+            .contains("invalid regex `(`"));
+    }
+
+    #[test]
+    fn decodes_query_parameters() {
+        assert_eq!(percent_decode("a%20b+c"), "a b c");
+        assert_eq!(percent_decode("%4"), "%4");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz%2"), "%zz%2");
+        assert_eq!(percent_decode("%C3%A9"), "é");
+        assert_eq!(percent_decode("%FF"), "\u{fffd}");
+
+        let request = request(&[(":path", "/search?q=a+b&&flag&x=%3D&q=second")]);
+        assert_eq!(request.path, "/search");
+        assert_eq!(request.query_param("q"), Some("a b"));
+        assert_eq!(request.query_param("flag"), Some(""));
+        assert_eq!(request.query_param("x"), Some("="));
+    }
+
+    #[test]
+    fn every_condition_must_hold() {
+        let matcher = matcher(json!({
+            "authority": {"suffix": ".example.com"},
+            "methods": ["GET"],
+            "headers": [
+                {"name": "x-debug", "present": false},
+                {"name": "X-Tenant", "value": {"prefix": "acme"}}
+            ],
+            "query": [{"name": "beta"}]
+        }));
+        let headers = [
+            (":method", "GET"),
+            (":authority", "api.example.com"),
+            (":path", "/?beta"),
+            ("x-tenant", "acme-eu"),
+        ];
+        assert!(matcher.matches(&request(&headers)));
+
+        let with = |name: &'static str, value: &'static str| {
+            let mut headers = headers.to_vec();
+            headers.retain(|(key, _)| *key != name);
+            headers.push((name, value));
+            request(&headers)
+        };
+        assert!(!matcher.matches(&with("x-debug", "1")));
+        assert!(!matcher.matches(&with(":authority", "example.com")));
+        assert!(!matcher.matches(&with(":method", "POST")));
+        assert!(!matcher.matches(&with("x-tenant", "globex")));
+        assert!(!matcher.matches(&with(":path", "/?alpha")));
+
+        let exact = self::matcher(json!({"authority": {"exact": "api.example.com"}}));
+        assert!(exact.matches(&request(&headers)));
+        assert!(!exact.matches(&with(":authority", "API.example.com:443")));
+    }
+}
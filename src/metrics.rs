@@ -0,0 +1,278 @@
+//! Metrics the pipeline reports through the host, which Envoy lists on its stats endpoint.
+//!
+//! Names are `{prefix}_{name}`, followed by `.{tag}.{value}` for each tag, the dotted
+//! form Envoy's `stats_tags` extract into labels.
+
+use crate::config::{field, ConfigError};
+use log::warn;
+use proxy_wasm::hostcalls;
+use proxy_wasm::types::MetricType;
+use serde::Deserialize;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+
+/// The `metrics` section of the plugin configuration.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MetricsConfig {
+    #[serde(default = "default_prefix")]
+    pub prefix: String,
+    #[serde(default)]
+    pub names: MetricNames,
+    /// Tags added to every metric, e.g. the gateway the policy belongs to.
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct MetricNames {
+    /// Counter of requests seen.
+    pub requests: String,
+    /// Gauge of requests in flight, from their headers until they are logged.
+    pub requests_active: String,
+    /// Counter of headers set or added by `headers` stages.
+    pub headers_injected: String,
+    /// Counter of requests answered by a stage, tagged with the stage type as `stage`
+    /// and the cause the stage gave as `reason`.
+    pub rejections: String,
+    /// Histogram of the time each request spends in a stage's hooks, tagged with the
+    /// stage type as `stage`.
+    pub stage_duration: String,
+}
+
+impl Default for MetricNames {
+    fn default() -> Self {
+        MetricNames {
+            requests: "requests_total".to_string(),
+            requests_active: "requests_active".to_string(),
+            headers_injected: "headers_injected_total".to_string(),
+            rejections: "rejections_total".to_string(),
+            stage_duration: "stage_duration_microseconds".to_string(),
+        }
+    }
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        MetricsConfig {
+            prefix: default_prefix(),
+            names: MetricNames::default(),
+            tags: BTreeMap::new(),
+        }
+    }
+}
+
+fn default_prefix() -> String {
+    "wasmup".to_string()
+}
+
+/// Whether `name` is safe in both Envoy stat names and Prometheus metric names.
+fn is_metric_name(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_')
+}
+
+impl MetricsConfig {
+    pub fn validate(&self, parent: &str) -> Result<(), ConfigError> {
+        let names = &self.names;
+        for (name, value) in [
+            ("prefix", &self.prefix),
+            ("names.requests", &names.requests),
+            ("names.requests_active", &names.requests_active),
+            ("names.headers_injected", &names.headers_injected),
+            ("names.rejections", &names.rejections),
+            ("names.stage_duration", &names.stage_duration),
+        ] {
+            if !is_metric_name(value) {
+                return Err(ConfigError::new(
+                    field(parent, name),
+                    format!("`{value}` must be letters, digits and underscores"),
+                ));
+            }
+        }
+        for (tag, value) in &self.tags {
+            let tag_field = field(parent, &format!("tags.{tag}"));
+            if !is_metric_name(tag) || !is_metric_name(value) {
+                return Err(ConfigError::new(
+                    tag_field,
+                    "tags and their values must be letters, digits and underscores",
+                ));
+            }
+            if tag == "reason" || tag == "stage" {
+                return Err(ConfigError::new(
+                    tag_field,
+                    format!("`{tag}` is set by the filter"),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn define(&self, kind: MetricType, name: &str, tags: &[(&str, &str)]) -> Option<u32> {
+        let mut full = format!("{}_{name}", self.prefix);
+        let fixed = self
+            .tags
+            .iter()
+            .map(|(tag, value)| (tag.as_str(), value.as_str()));
+        for (tag, value) in fixed.chain(tags.iter().copied()) {
+            full.push_str(&format!(".{tag}.{value}"));
+        }
+        match hostcalls::define_metric(kind, &full) {
+            Ok(id) => Some(id),
+            Err(status) => {
+                warn!("defining metric `{full}`: {status:?}");
+                None
+            }
+        }
+    }
+}
+
+/// The metrics of one configured root. Metrics the host refused to define are skipped.
+pub struct Metrics {
+    config: MetricsConfig,
+    requests: Option<u32>,
+    requests_active: Option<u32>,
+    headers_injected: Option<u32>,
+    /// Per stage of the pipeline, its type and duration histogram.
+    stages: Vec<(String, Option<u32>)>,
+    /// Rejections counters by stage type and reason, defined as the reasons come up.
+    rejections: RefCell<HashMap<(String, &'static str), Option<u32>>>,
+}
+
+impl Metrics {
+    /// Defines the metrics of a pipeline whose stages have the given types. Stages of
+    /// the same type share their metrics.
+    pub fn define(config: &MetricsConfig, stage_types: &[&str]) -> Metrics {
+        let names = &config.names;
+        Metrics {
+            config: config.clone(),
+            requests: config.define(MetricType::Counter, &names.requests, &[]),
+            requests_active: config.define(MetricType::Gauge, &names.requests_active, &[]),
+            headers_injected: config.define(MetricType::Counter, &names.headers_injected, &[]),
+            stages: stage_types
+                .iter()
+                .map(|&stage| {
+                    let duration = config.define(
+                        MetricType::Histogram,
+                        &names.stage_duration,
+                        &[("stage", stage)],
+                    );
+                    (stage.to_string(), duration)
+                })
+                .collect(),
+            rejections: RefCell::new(HashMap::new()),
+        }
+    }
+
+    pub fn request(&self) {
+        increment(self.requests, 1);
+        increment(self.requests_active, 1);
+    }
+
+    /// Counts a request seen by [`Metrics::request`] as done.
+    pub fn request_done(&self) {
+        increment(self.requests_active, -1);
+    }
+
+    pub fn headers_injected(&self, count: usize) {
+        if count > 0 {
+            increment(self.headers_injected, count as i64);
+        }
+    }
+
+    /// Counts a request answered by the stage at `stage` for `reason`.
+    pub fn rejection(&self, stage: usize, reason: &'static str) {
+        let stage = &self.stages[stage].0;
+        let id = *self
+            .rejections
+            .borrow_mut()
+            .entry((stage.clone(), reason))
+            .or_insert_with(|| {
+                self.config.define(
+                    MetricType::Counter,
+                    &self.config.names.rejections,
+                    &[("stage", stage), ("reason", reason)],
+                )
+            });
+        increment(id, 1);
+    }
+
+    pub fn stage_duration(&self, stage: usize, micros: u64) {
+        if let Some(id) = self.stages[stage].1 {
+            hostcalls::record_metric(id, micros).unwrap();
+        }
+    }
+}
+
+fn increment(id: Option<u32>, offset: i64) {
+    if let Some(id) = id {
+        hostcalls::increment_metric(id, offset).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mock::{with_host, Plugin};
+    use serde_json::json;
+
+    #[test]
+    fn counts_requests_headers_and_rejections() {
+        let config = json!({
+            "stages": [
+                {"type": "headers", "request_headers": [
+                    {"op": "set", "name": "x-a", "value": "1"},
+                    {"op": "add", "name": "x-b", "value": "2"},
+                    {"op": "remove", "name": "x-c"}
+                ]},
+                {"type": "api_key", "keys": [{"consumer": "alice", "sha256": "2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b"}]}
+            ]
+        });
+        let mut plugin = Plugin::new("add_header_root", &config.to_string());
+        let metric = |name: &str| with_host(|host| host.metric(name));
+        for key in ["secret", "wrong", "other"] {
+            let stream = plugin.stream();
+            stream.request_headers(&[(":path", "/"), ("x-api-key", key)], true);
+            assert_eq!(metric("wasmup_requests_active"), Some(1));
+            stream.log();
+        }
+        let stream = plugin.stream();
+        stream.request_headers(&[(":path", "/")], true);
+        stream.log();
+        assert_eq!(metric("wasmup_requests_total"), Some(4));
+        assert_eq!(metric("wasmup_requests_active"), Some(0));
+        assert_eq!(metric("wasmup_headers_injected_total"), Some(8));
+        // Each cause of a rejection counts apart, even with the same status.
+        assert_eq!(
+            metric("wasmup_rejections_total.stage.api_key.reason.invalid_key"),
+            Some(2)
+        );
+        assert_eq!(
+            metric("wasmup_rejections_total.stage.api_key.reason.missing_key"),
+            Some(1)
+        );
+        assert!(metric("wasmup_stage_duration_microseconds.stage.api_key").is_some());
+    }
+
+    #[test]
+    fn names_and_tags_are_configurable() {
+        let config = json!({
+            "metrics": {
+                "prefix": "edge",
+                "names": {"requests": "http_requests_total"},
+                "tags": {"gateway": "eg", "env": "prod"}
+            },
+            "response_headers": [{"op": "set", "name": "x-wasm-custom", "value": "FOO"}]
+        });
+        let mut plugin = Plugin::new("add_header_root", &config.to_string());
+        plugin.stream().request_headers(&[(":path", "/")], true);
+        assert_eq!(
+            with_host(|host| host.metric("edge_http_requests_total.env.prod.gateway.eg")),
+            Some(1)
+        );
+
+        let config = json!({"metrics": {"tags": {"stage": "x"}}, "stages": []});
+        let (_, accepted) = Plugin::try_new("add_header_root", &config.to_string());
+        assert!(!accepted);
+        assert!(with_host(|host| host.logged("`stage` is set by the filter")));
+    }
+}
@@ -0,0 +1,937 @@
+//! A mock proxy-wasm host for native unit tests.
+//!
+//! On `wasm32` the SDK's hostcalls are imports provided by Envoy. Natively the test binary
+//! links them against the `#[no_mangle]` functions below instead, which operate on a
+//! thread-local [`Host`]. Tests drive the module through the SDK's real `proxy_on_*`
+//! exports, via [`Plugin`] and [`Stream`], and then inspect the host state.
+
+// Not every test exercises every part of the host.
+#![allow(dead_code)]
+
+use proxy_wasm::types::{Action, LogLevel, MetricType, Status};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+extern "C" {
+    fn proxy_on_context_create(context_id: u32, root_context_id: u32);
+    fn proxy_on_vm_start(context_id: u32, vm_configuration_size: usize) -> bool;
+    fn proxy_on_configure(context_id: u32, plugin_configuration_size: usize) -> bool;
+    fn proxy_on_tick(context_id: u32);
+    fn proxy_on_request_headers(context_id: u32, num_headers: usize, end_of_stream: bool)
+        -> Action;
+    fn proxy_on_request_body(context_id: u32, body_size: usize, end_of_stream: bool) -> Action;
+    fn proxy_on_response_headers(
+        context_id: u32,
+        num_headers: usize,
+        end_of_stream: bool,
+    ) -> Action;
+    fn proxy_on_response_body(context_id: u32, body_size: usize, end_of_stream: bool) -> Action;
+    fn proxy_on_http_call_response(
+        context_id: u32,
+        token_id: u32,
+        num_headers: usize,
+        body_size: usize,
+        num_trailers: usize,
+    );
+    fn proxy_on_grpc_receive(context_id: u32, token_id: u32, response_size: usize);
+    fn proxy_on_grpc_close(context_id: u32, token_id: u32, status_code: u32);
+    fn proxy_on_log(context_id: u32);
+    fn proxy_on_done(context_id: u32) -> bool;
+    fn proxy_on_delete(context_id: u32);
+}
+
+// Buffer and map type discriminants, as passed over the ABI.
+const REQUEST_BODY: u32 = 0;
+const RESPONSE_BODY: u32 = 1;
+const HTTP_CALL_RESPONSE_BODY: u32 = 4;
+const GRPC_RECEIVE_BUFFER: u32 = 5;
+const PLUGIN_CONFIGURATION: u32 = 7;
+const REQUEST_HEADERS: u32 = 0;
+const RESPONSE_HEADERS: u32 = 2;
+const HTTP_CALL_RESPONSE_HEADERS: u32 = 6;
+
+pub type Headers = Vec<(String, String)>;
+
+/// The state of the mock host for the current test thread.
+#[derive(Default)]
+pub struct Host {
+    /// Context the module currently acts on; header maps and bodies belong to it.
+    pub current: u32,
+    pub streams: HashMap<u32, StreamData>,
+    pub plugin_configuration: Vec<u8>,
+    pub properties: HashMap<String, Vec<u8>>,
+    /// Properties whose lookup fails with an internal error, as Envoy's may.
+    pub failing_properties: HashSet<String>,
+    pub shared_data: HashMap<String, (Vec<u8>, u32)>,
+    pub logs: Vec<(LogLevel, String)>,
+    pub now: Duration,
+    pub tick_period: Option<Duration>,
+    pub http_calls: Vec<HttpCall>,
+    /// Response of the HTTP call currently being delivered.
+    pub http_call_response: (Headers, Vec<u8>),
+    pub grpc_calls: Vec<GrpcCall>,
+    /// Message and status of the gRPC call currently being delivered.
+    pub grpc_response: (Vec<u8>, u32, String),
+    pub metrics: Vec<Metric>,
+    /// Last context id handed out; ids stay unique across plugins started on one thread.
+    last_context_id: u32,
+}
+
+#[derive(Default)]
+pub struct StreamData {
+    pub request_headers: Headers,
+    pub request_body: Vec<u8>,
+    pub response_headers: Headers,
+    pub response_body: Vec<u8>,
+    pub local_response: Option<LocalResponse>,
+    pub request_resumed: bool,
+    pub response_resumed: bool,
+    /// Whether the module paused the last body chunk, so the host keeps buffering.
+    request_buffering: bool,
+    response_buffering: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct LocalResponse {
+    pub status: u32,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+pub struct GrpcCall {
+    pub context_id: u32,
+    pub token: u32,
+    pub upstream: String,
+    pub service: String,
+    pub method: String,
+    pub message: Vec<u8>,
+    pub timeout: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct Metric {
+    pub kind: MetricType,
+    pub name: String,
+    pub value: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct HttpCall {
+    pub context_id: u32,
+    pub token: u32,
+    pub upstream: String,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+    pub timeout: Duration,
+}
+
+thread_local! {
+    static HOST: RefCell<Host> = RefCell::new(Host::default());
+}
+
+/// Runs `f` with the mock host of the current thread.
+pub fn with_host<R>(f: impl FnOnce(&mut Host) -> R) -> R {
+    HOST.with(|host| f(&mut host.borrow_mut()))
+}
+
+impl Host {
+    fn new_context_id(&mut self) -> u32 {
+        self.last_context_id += 1;
+        self.last_context_id
+    }
+
+    fn stream(&mut self) -> &mut StreamData {
+        self.streams.entry(self.current).or_default()
+    }
+
+    fn map(&mut self, map_type: u32) -> Option<&mut Headers> {
+        match map_type {
+            REQUEST_HEADERS => Some(&mut self.stream().request_headers),
+            RESPONSE_HEADERS => Some(&mut self.stream().response_headers),
+            HTTP_CALL_RESPONSE_HEADERS => Some(&mut self.http_call_response.0),
+            _ => None,
+        }
+    }
+
+    fn buffer(&mut self, buffer_type: u32) -> Option<&mut Vec<u8>> {
+        match buffer_type {
+            REQUEST_BODY => Some(&mut self.stream().request_body),
+            RESPONSE_BODY => Some(&mut self.stream().response_body),
+            HTTP_CALL_RESPONSE_BODY => Some(&mut self.http_call_response.1),
+            GRPC_RECEIVE_BUFFER => Some(&mut self.grpc_response.0),
+            PLUGIN_CONFIGURATION => Some(&mut self.plugin_configuration),
+            _ => None,
+        }
+    }
+
+    pub fn set_property(&mut self, path: &[&str], value: &[u8]) {
+        self.properties.insert(path.join("."), value.to_vec());
+    }
+
+    pub fn fail_property(&mut self, path: &[&str]) {
+        self.failing_properties.insert(path.join("."));
+    }
+
+    pub fn logged(&self, needle: &str) -> bool {
+        self.logs
+            .iter()
+            .any(|(_, message)| message.contains(needle))
+    }
+
+    pub fn metric(&self, name: &str) -> Option<u64> {
+        self.metrics
+            .iter()
+            .find(|metric| metric.name == name)
+            .map(|metric| metric.value)
+    }
+}
+
+/// A root context of the module, configured through the mock host.
+pub struct Plugin {
+    root_context_id: u32,
+}
+
+impl Plugin {
+    /// Starts the module for `root_id` and configures it, panicking if it refuses the configuration.
+    pub fn new(root_id: &str, config: &str) -> Plugin {
+        let (plugin, accepted) = Plugin::try_new(root_id, config);
+        if !accepted {
+            let logs = with_host(|host| host.logs.clone());
+            panic!("configuration rejected: {logs:?}");
+        }
+        plugin
+    }
+
+    /// Starts the module and returns whether `on_configure` accepted the configuration.
+    pub fn try_new(root_id: &str, config: &str) -> (Plugin, bool) {
+        crate::_initialize();
+        let root_context_id = with_host(|host| {
+            let id = host.new_context_id();
+            host.current = id;
+            host.set_property(&["plugin_root_id"], root_id.as_bytes());
+            host.plugin_configuration = config.as_bytes().to_vec();
+            id
+        });
+        let accepted = unsafe {
+            proxy_on_context_create(root_context_id, 0);
+            proxy_on_vm_start(root_context_id, 0)
+                && proxy_on_configure(root_context_id, config.len())
+        };
+        (Plugin { root_context_id }, accepted)
+    }
+
+    /// Fires the root's timer, as Envoy does every tick period.
+    pub fn tick(&self) {
+        with_host(|host| host.current = self.root_context_id);
+        unsafe { proxy_on_tick(self.root_context_id) };
+    }
+
+    /// Delivers the response to an HTTP call the root dispatched.
+    pub fn http_call_response(&self, token: u32, headers: &[(&str, &str)], body: &[u8]) {
+        with_host(|host| {
+            host.current = self.root_context_id;
+            host.http_call_response = (to_headers(headers), body.to_vec());
+        });
+        unsafe { proxy_on_http_call_response(0, token, headers.len(), body.len(), 0) };
+    }
+
+    /// Creates a new HTTP stream on this root.
+    pub fn stream(&mut self) -> Stream {
+        let id = with_host(|host| {
+            host.current = host.new_context_id();
+            host.current
+        });
+        unsafe { proxy_on_context_create(id, self.root_context_id) };
+        Stream { id }
+    }
+}
+
+/// One HTTP stream, driven callback by callback.
+pub struct Stream {
+    pub id: u32,
+}
+
+impl Stream {
+    fn enter(&self) {
+        with_host(|host| host.current = self.id);
+    }
+
+    pub fn request_headers(&self, headers: &[(&str, &str)], end_of_stream: bool) -> Action {
+        self.enter();
+        with_host(|host| host.stream().request_headers = to_headers(headers));
+        unsafe { proxy_on_request_headers(self.id, headers.len(), end_of_stream) }
+    }
+
+    /// Delivers a request body chunk. Like Envoy, the host keeps earlier chunks
+    /// in the buffer only while the module pauses the body.
+    pub fn request_body(&self, chunk: &[u8], end_of_stream: bool) -> Action {
+        self.enter();
+        let size = with_host(|host| {
+            let stream = host.stream();
+            if !stream.request_buffering {
+                stream.request_body.clear();
+            }
+            stream.request_body.extend_from_slice(chunk);
+            stream.request_body.len()
+        });
+        let action = unsafe { proxy_on_request_body(self.id, size, end_of_stream) };
+        with_host(|host| host.stream().request_buffering = action == Action::Pause);
+        action
+    }
+
+    pub fn response_headers(&self, headers: &[(&str, &str)], end_of_stream: bool) -> Action {
+        self.enter();
+        with_host(|host| host.stream().response_headers = to_headers(headers));
+        unsafe { proxy_on_response_headers(self.id, headers.len(), end_of_stream) }
+    }
+
+    /// Delivers a response body chunk, buffering like [`Stream::request_body`].
+    pub fn response_body(&self, chunk: &[u8], end_of_stream: bool) -> Action {
+        self.enter();
+        let size = with_host(|host| {
+            let stream = host.stream();
+            if !stream.response_buffering {
+                stream.response_body.clear();
+            }
+            stream.response_body.extend_from_slice(chunk);
+            stream.response_body.len()
+        });
+        let action = unsafe { proxy_on_response_body(self.id, size, end_of_stream) };
+        with_host(|host| host.stream().response_buffering = action == Action::Pause);
+        action
+    }
+
+    /// Delivers the response to the HTTP call identified by `token`.
+    pub fn http_call_response(&self, token: u32, headers: &[(&str, &str)], body: &[u8]) {
+        with_host(|host| host.http_call_response = (to_headers(headers), body.to_vec()));
+        unsafe { proxy_on_http_call_response(0, token, headers.len(), body.len(), 0) };
+    }
+
+    /// Delivers the reply to the gRPC call identified by `token`.
+    pub fn grpc_call_response(&self, token: u32, status: u32, message: &[u8]) {
+        with_host(|host| host.grpc_response = (message.to_vec(), status, String::new()));
+        unsafe {
+            if status == 0 {
+                proxy_on_grpc_receive(0, token, message.len());
+            } else {
+                proxy_on_grpc_close(0, token, status);
+            }
+        }
+    }
+
+    pub fn log(&self) {
+        self.enter();
+        unsafe { proxy_on_log(self.id) };
+    }
+
+    pub fn done(self) {
+        self.enter();
+        unsafe {
+            proxy_on_done(self.id);
+            proxy_on_delete(self.id);
+        }
+    }
+
+    pub fn request_header(&self, name: &str) -> Option<String> {
+        self.data(|data| find(&data.request_headers, name))
+    }
+
+    pub fn response_header(&self, name: &str) -> Option<String> {
+        self.data(|data| find(&data.response_headers, name))
+    }
+
+    pub fn local_response(&self) -> Option<LocalResponse> {
+        self.data(|data| data.local_response.clone())
+    }
+
+    pub fn data<R>(&self, f: impl FnOnce(&StreamData) -> R) -> R {
+        with_host(|host| f(host.streams.entry(self.id).or_default()))
+    }
+}
+
+fn to_headers(headers: &[(&str, &str)]) -> Headers {
+    headers
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect()
+}
+
+fn find(headers: &Headers, name: &str) -> Option<String> {
+    headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.clone())
+}
+
+// ABI helpers.
+
+unsafe fn slice<'a>(data: *const u8, size: usize) -> &'a [u8] {
+    if data.is_null() || size == 0 {
+        &[]
+    } else {
+        std::slice::from_raw_parts(data, size)
+    }
+}
+
+unsafe fn string(data: *const u8, size: usize) -> String {
+    String::from_utf8_lossy(slice(data, size)).into_owned()
+}
+
+/// Hands `bytes` to the module, which takes ownership with `Vec::from_raw_parts`.
+unsafe fn give(bytes: &[u8], return_data: *mut *mut u8, return_size: *mut usize) {
+    let boxed: Box<[u8]> = bytes.into();
+    *return_size = boxed.len();
+    *return_data = Box::into_raw(boxed) as *mut u8;
+}
+
+fn serialize_map(map: &[(String, Vec<u8>)]) -> Vec<u8> {
+    let mut bytes = (map.len() as u32).to_le_bytes().to_vec();
+    for (name, value) in map {
+        bytes.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    }
+    for (name, value) in map {
+        bytes.extend_from_slice(name.as_bytes());
+        bytes.push(0);
+        bytes.extend_from_slice(value);
+        bytes.push(0);
+    }
+    bytes
+}
+
+fn deserialize_map(bytes: &[u8]) -> Vec<(String, Vec<u8>)> {
+    if bytes.len() < 4 {
+        return Vec::new();
+    }
+    let read = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap()) as usize;
+    let count = read(0);
+    let mut p = 4 + count * 8;
+    let mut map = Vec::with_capacity(count);
+    for n in 0..count {
+        let (key_size, value_size) = (read(4 + n * 8), read(8 + n * 8));
+        let key = String::from_utf8_lossy(&bytes[p..p + key_size]).into_owned();
+        p += key_size + 1;
+        let value = bytes[p..p + value_size].to_vec();
+        p += value_size + 1;
+        map.push((key, value));
+    }
+    map
+}
+
+fn deserialize_headers(bytes: &[u8]) -> Headers {
+    deserialize_map(bytes)
+        .into_iter()
+        .map(|(name, value)| (name, String::from_utf8_lossy(&value).into_owned()))
+        .collect()
+}
+
+// Hostcalls.
+
+#[no_mangle]
+unsafe extern "C" fn proxy_log(level: LogLevel, data: *const u8, size: usize) -> Status {
+    let message = string(data, size);
+    // The SDK's panic hook logs at critical level; keep failing assertions visible.
+    if level == LogLevel::Critical {
+        eprintln!("{message}");
+    }
+    with_host(|host| host.logs.push((level, message)));
+    Status::Ok
+}
+
+#[no_mangle]
+unsafe extern "C" fn proxy_get_log_level(return_level: *mut LogLevel) -> Status {
+    *return_level = LogLevel::Trace;
+    Status::Ok
+}
+
+#[no_mangle]
+unsafe extern "C" fn proxy_get_current_time_nanoseconds(return_time: *mut u64) -> Status {
+    *return_time = with_host(|host| host.now.as_nanos() as u64);
+    Status::Ok
+}
+
+#[no_mangle]
+extern "C" fn proxy_set_tick_period_milliseconds(period: u32) -> Status {
+    with_host(|host| host.tick_period = Some(Duration::from_millis(period.into())));
+    Status::Ok
+}
+
+#[no_mangle]
+extern "C" fn proxy_set_effective_context(context_id: u32) -> Status {
+    with_host(|host| host.current = context_id);
+    Status::Ok
+}
+
+#[no_mangle]
+unsafe extern "C" fn proxy_get_buffer_bytes(
+    buffer_type: u32,
+    start: usize,
+    max_size: usize,
+    return_data: *mut *mut u8,
+    return_size: *mut usize,
+) -> Status {
+    let bytes = with_host(|host| {
+        host.buffer(buffer_type).map(|buffer| {
+            let start = start.min(buffer.len());
+            let end = start.saturating_add(max_size).min(buffer.len());
+            buffer[start..end].to_vec()
+        })
+    });
+    match bytes {
+        Some(bytes) => {
+            give(&bytes, return_data, return_size);
+            Status::Ok
+        }
+        None => Status::NotFound,
+    }
+}
+
+#[no_mangle]
+unsafe extern "C" fn proxy_set_buffer_bytes(
+    buffer_type: u32,
+    start: usize,
+    size: usize,
+    data: *const u8,
+    data_size: usize,
+) -> Status {
+    let data = slice(data, data_size);
+    with_host(|host| match host.buffer(buffer_type) {
+        Some(buffer) => {
+            let start = start.min(buffer.len());
+            let end = start.saturating_add(size).min(buffer.len());
+            buffer.splice(start..end, data.iter().copied());
+            Status::Ok
+        }
+        None => Status::NotFound,
+    })
+}
+
+#[no_mangle]
+unsafe extern "C" fn proxy_get_header_map_pairs(
+    map_type: u32,
+    return_data: *mut *mut u8,
+    return_size: *mut usize,
+) -> Status {
+    let pairs = with_host(|host| {
+        host.map(map_type).map(|map| {
+            map.iter()
+                .map(|(name, value)| (name.clone(), value.as_bytes().to_vec()))
+                .collect::<Vec<_>>()
+        })
+    });
+    match pairs {
+        Some(pairs) => {
+            give(&serialize_map(&pairs), return_data, return_size);
+            Status::Ok
+        }
+        None => Status::NotFound,
+    }
+}
+
+#[no_mangle]
+unsafe extern "C" fn proxy_set_header_map_pairs(
+    map_type: u32,
+    data: *const u8,
+    size: usize,
+) -> Status {
+    let pairs = deserialize_headers(slice(data, size));
+    with_host(|host| match host.map(map_type) {
+        Some(map) => {
+            *map = pairs;
+            Status::Ok
+        }
+        None => Status::NotFound,
+    })
+}
+
+#[no_mangle]
+unsafe extern "C" fn proxy_get_header_map_value(
+    map_type: u32,
+    key_data: *const u8,
+    key_size: usize,
+    return_data: *mut *mut u8,
+    return_size: *mut usize,
+) -> Status {
+    let key = string(key_data, key_size);
+    match with_host(|host| host.map(map_type).and_then(|map| find(map, &key))) {
+        Some(value) => {
+            give(value.as_bytes(), return_data, return_size);
+            Status::Ok
+        }
+        None => Status::NotFound,
+    }
+}
+
+#[no_mangle]
+unsafe extern "C" fn proxy_remove_header_map_value(
+    map_type: u32,
+    key_data: *const u8,
+    key_size: usize,
+) -> Status {
+    let key = string(key_data, key_size);
+    with_host(|host| {
+        if let Some(map) = host.map(map_type) {
+            map.retain(|(name, _)| !name.eq_ignore_ascii_case(&key));
+        }
+    });
+    Status::Ok
+}
+
+#[no_mangle]
+unsafe extern "C" fn proxy_replace_header_map_value(
+    map_type: u32,
+    key_data: *const u8,
+    key_size: usize,
+    value_data: *const u8,
+    value_size: usize,
+) -> Status {
+    let (key, value) = (string(key_data, key_size), string(value_data, value_size));
+    with_host(|host| {
+        if let Some(map) = host.map(map_type) {
+            let first = map
+                .iter()
+                .position(|(name, _)| name.eq_ignore_ascii_case(&key));
+            map.retain(|(name, _)| !name.eq_ignore_ascii_case(&key));
+            match first {
+                Some(i) => map.insert(i, (key, value)),
+                None => map.push((key, value)),
+            }
+        }
+    });
+    Status::Ok
+}
+
+#[no_mangle]
+unsafe extern "C" fn proxy_add_header_map_value(
+    map_type: u32,
+    key_data: *const u8,
+    key_size: usize,
+    value_data: *const u8,
+    value_size: usize,
+) -> Status {
+    let (key, value) = (string(key_data, key_size), string(value_data, value_size));
+    with_host(|host| {
+        if let Some(map) = host.map(map_type) {
+            map.push((key, value));
+        }
+    });
+    Status::Ok
+}
+
+#[no_mangle]
+unsafe extern "C" fn proxy_get_property(
+    path_data: *const u8,
+    path_size: usize,
+    return_data: *mut *mut u8,
+    return_size: *mut usize,
+) -> Status {
+    let path = string(path_data, path_size).replace('\0', ".");
+    if with_host(|host| host.failing_properties.contains(&path)) {
+        return Status::InternalFailure;
+    }
+    match with_host(|host| host.properties.get(&path).cloned()) {
+        Some(value) => {
+            give(&value, return_data, return_size);
+            Status::Ok
+        }
+        None => Status::NotFound,
+    }
+}
+
+#[no_mangle]
+unsafe extern "C" fn proxy_set_property(
+    path_data: *const u8,
+    path_size: usize,
+    value_data: *const u8,
+    value_size: usize,
+) -> Status {
+    let path = string(path_data, path_size).replace('\0', ".");
+    let value = slice(value_data, value_size).to_vec();
+    with_host(|host| host.properties.insert(path, value));
+    Status::Ok
+}
+
+#[no_mangle]
+unsafe extern "C" fn proxy_get_shared_data(
+    key_data: *const u8,
+    key_size: usize,
+    return_data: *mut *mut u8,
+    return_size: *mut usize,
+    return_cas: *mut u32,
+) -> Status {
+    let key = string(key_data, key_size);
+    match with_host(|host| host.shared_data.get(&key).cloned()) {
+        Some((value, cas)) => {
+            give(&value, return_data, return_size);
+            *return_cas = cas;
+            Status::Ok
+        }
+        None => Status::NotFound,
+    }
+}
+
+#[no_mangle]
+unsafe extern "C" fn proxy_set_shared_data(
+    key_data: *const u8,
+    key_size: usize,
+    value_data: *const u8,
+    value_size: usize,
+    cas: u32,
+) -> Status {
+    let key = string(key_data, key_size);
+    let value = slice(value_data, value_size).to_vec();
+    with_host(|host| {
+        let current = host.shared_data.get(&key).map_or(0, |(_, cas)| *cas);
+        if cas != 0 && cas != current {
+            return Status::CasMismatch;
+        }
+        host.shared_data.insert(key, (value, current + 1));
+        Status::Ok
+    })
+}
+
+#[no_mangle]
+extern "C" fn proxy_continue_stream(stream_type: u32) -> Status {
+    with_host(|host| {
+        let stream = host.stream();
+        match stream_type {
+            0 => stream.request_resumed = true,
+            _ => stream.response_resumed = true,
+        }
+    });
+    Status::Ok
+}
+
+#[no_mangle]
+extern "C" fn proxy_close_stream(_stream_type: u32) -> Status {
+    Status::Ok
+}
+
+#[no_mangle]
+unsafe extern "C" fn proxy_send_local_response(
+    status_code: u32,
+    _details_data: *const u8,
+    _details_size: usize,
+    body_data: *const u8,
+    body_size: usize,
+    headers_data: *const u8,
+    headers_size: usize,
+    _grpc_status: i32,
+) -> Status {
+    let response = LocalResponse {
+        status: status_code,
+        headers: deserialize_headers(slice(headers_data, headers_size)),
+        body: slice(body_data, body_size).to_vec(),
+    };
+    with_host(|host| host.stream().local_response = Some(response));
+    Status::Ok
+}
+
+#[no_mangle]
+unsafe extern "C" fn proxy_http_call(
+    upstream_data: *const u8,
+    upstream_size: usize,
+    headers_data: *const u8,
+    headers_size: usize,
+    body_data: *const u8,
+    body_size: usize,
+    _trailers_data: *const u8,
+    _trailers_size: usize,
+    timeout: u32,
+    return_token: *mut u32,
+) -> Status {
+    let token = with_host(|host| {
+        let token = host.http_calls.len() as u32 + 1;
+        host.http_calls.push(HttpCall {
+            context_id: host.current,
+            token,
+            upstream: string(upstream_data, upstream_size),
+            headers: deserialize_headers(slice(headers_data, headers_size)),
+            body: slice(body_data, body_size).to_vec(),
+            timeout: Duration::from_millis(timeout.into()),
+        });
+        token
+    });
+    *return_token = token;
+    Status::Ok
+}
+
+#[no_mangle]
+extern "C" fn proxy_done() -> Status {
+    Status::Ok
+}
+
+#[no_mangle]
+unsafe extern "C" fn proxy_grpc_call(
+    upstream_data: *const u8,
+    upstream_size: usize,
+    service_data: *const u8,
+    service_size: usize,
+    method_data: *const u8,
+    method_size: usize,
+    _metadata_data: *const u8,
+    _metadata_size: usize,
+    message_data: *const u8,
+    message_size: usize,
+    timeout: u32,
+    return_token: *mut u32,
+) -> Status {
+    let token = with_host(|host| {
+        // Keep gRPC tokens apart from HTTP call tokens; the SDK tracks both in one table.
+        let token = 1000 + host.grpc_calls.len() as u32;
+        host.grpc_calls.push(GrpcCall {
+            context_id: host.current,
+            token,
+            upstream: string(upstream_data, upstream_size),
+            service: string(service_data, service_size),
+            method: string(method_data, method_size),
+            message: slice(message_data, message_size).to_vec(),
+            timeout: Duration::from_millis(timeout.into()),
+        });
+        token
+    });
+    *return_token = token;
+    Status::Ok
+}
+
+#[no_mangle]
+unsafe extern "C" fn proxy_get_status(
+    return_code: *mut u32,
+    return_message_data: *mut *mut u8,
+    return_message_size: *mut usize,
+) -> Status {
+    let (code, message) = with_host(|host| (host.grpc_response.1, host.grpc_response.2.clone()));
+    *return_code = code;
+    give(message.as_bytes(), return_message_data, return_message_size);
+    Status::Ok
+}
+
+#[no_mangle]
+unsafe extern "C" fn proxy_define_metric(
+    kind: MetricType,
+    name_data: *const u8,
+    name_size: usize,
+    return_id: *mut u32,
+) -> Status {
+    let name = string(name_data, name_size);
+    *return_id = with_host(|host| {
+        let id = match host.metrics.iter().position(|metric| metric.name == name) {
+            Some(id) => id,
+            None => {
+                host.metrics.push(Metric {
+                    kind,
+                    name,
+                    value: 0,
+                });
+                host.metrics.len() - 1
+            }
+        };
+        id as u32
+    });
+    Status::Ok
+}
+
+#[no_mangle]
+unsafe extern "C" fn proxy_get_metric(metric_id: u32, return_value: *mut u64) -> Status {
+    match with_host(|host| host.metrics.get(metric_id as usize).map(|m| m.value)) {
+        Some(value) => {
+            *return_value = value;
+            Status::Ok
+        }
+        None => Status::NotFound,
+    }
+}
+
+#[no_mangle]
+extern "C" fn proxy_record_metric(metric_id: u32, value: u64) -> Status {
+    with_host(|host| match host.metrics.get_mut(metric_id as usize) {
+        Some(metric) => {
+            metric.value = value;
+            Status::Ok
+        }
+        None => Status::NotFound,
+    })
+}
+
+#[no_mangle]
+extern "C" fn proxy_increment_metric(metric_id: u32, offset: i64) -> Status {
+    with_host(|host| match host.metrics.get_mut(metric_id as usize) {
+        Some(metric) => {
+            metric.value = metric.value.saturating_add_signed(offset);
+            Status::Ok
+        }
+        None => Status::NotFound,
+    })
+}
+
+// Hostcalls the module does not use; they only need to link.
+
+#[no_mangle]
+extern "C" fn proxy_register_shared_queue(_: *const u8, _: usize, _: *mut u32) -> Status {
+    Status::InternalFailure
+}
+
+#[no_mangle]
+extern "C" fn proxy_resolve_shared_queue(
+    _: *const u8,
+    _: usize,
+    _: *const u8,
+    _: usize,
+    _: *mut u32,
+) -> Status {
+    Status::NotFound
+}
+
+#[no_mangle]
+extern "C" fn proxy_dequeue_shared_queue(_: u32, _: *mut *mut u8, _: *mut usize) -> Status {
+    Status::NotFound
+}
+
+#[no_mangle]
+extern "C" fn proxy_enqueue_shared_queue(_: u32, _: *const u8, _: usize) -> Status {
+    Status::NotFound
+}
+
+#[no_mangle]
+extern "C" fn proxy_grpc_stream(
+    _: *const u8,
+    _: usize,
+    _: *const u8,
+    _: usize,
+    _: *const u8,
+    _: usize,
+    _: *const u8,
+    _: usize,
+    _: *mut u32,
+) -> Status {
+    Status::InternalFailure
+}
+
+#[no_mangle]
+extern "C" fn proxy_grpc_send(_: u32, _: *const u8, _: usize, _: bool) -> Status {
+    Status::NotFound
+}
+
+#[no_mangle]
+extern "C" fn proxy_grpc_cancel(_: u32) -> Status {
+    Status::Ok
+}
+
+#[no_mangle]
+extern "C" fn proxy_grpc_close(_: u32) -> Status {
+    Status::Ok
+}
+
+#[no_mangle]
+extern "C" fn proxy_call_foreign_function(
+    _: *const u8,
+    _: usize,
+    _: *const u8,
+    _: usize,
+    _: *mut *mut u8,
+    _: *mut usize,
+) -> Status {
+    Status::NotFound
+}
@@ -0,0 +1,727 @@
+//! The per-request filter: an ordered list of stages built from the plugin configuration.
+
+use crate::access_log::{AccessLog, RequestLog};
+use crate::api_key::ApiKeyStage;
+use crate::basic_auth::BasicAuthStage;
+use crate::body_limit::BodyLimitStage;
+use crate::concurrency::ConcurrencyLimitStage;
+use crate::config::{self, Config, ConfigError, StageConfig};
+use crate::ext_authz::ExtAuthzStage;
+use crate::headers::HeadersStage;
+use crate::json_transform::JsonTransformStage;
+use crate::jwt::{JwtConfig, JwtStage};
+use crate::metrics::Metrics;
+use crate::quota::QuotaStage;
+use crate::ratelimit::{LocalRateLimitConfig, LocalRateLimitStage};
+use crate::registry::FilterFactory;
+use crate::remote_jwks::RemoteJwks;
+use crate::request_id::RequestIdStage;
+use crate::rls::GlobalRateLimitStage;
+use crate::signature::SignatureStage;
+use crate::tracing::TracingStage;
+use crate::util::{now_micros, now_millis};
+use proxy_wasm::hostcalls;
+use proxy_wasm::traits::{Context, HttpContext};
+use proxy_wasm::types::{Action, MapType};
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::Duration;
+
+/// What a stage wants the pipeline to do after one of its hooks ran.
+pub enum Flow {
+    /// Hand the stream to the next stage.
+    Continue,
+    /// Hand the stream to the next stage, but hold the headers back until the end of the
+    /// body, so they can still change with it. A stage returns this from a header hook
+    /// and then pauses its body hooks until the end of the stream.
+    HoldHeaders,
+    /// Stop the pipeline and answer the client directly.
+    Respond(LocalReply),
+    /// Stop the pipeline until the stage is woken up by a callout response.
+    /// In body hooks this buffers the body and waits for the next chunk instead.
+    Pause,
+}
+
+pub struct LocalReply {
+    pub status: u32,
+    pub headers: Vec<(String, String)>,
+    pub body: Option<String>,
+    /// Why the stage answered, e.g. `invalid_key`; the `reason` tag of the rejections
+    /// counter.
+    pub reason: &'static str,
+}
+
+impl LocalReply {
+    pub fn new(status: u32) -> LocalReply {
+        LocalReply {
+            status,
+            headers: Vec::new(),
+            body: None,
+            reason: "rejected",
+        }
+    }
+
+    pub fn reason(mut self, reason: &'static str) -> LocalReply {
+        self.reason = reason;
+        self
+    }
+
+    pub fn header(mut self, name: &str, value: impl Into<String>) -> LocalReply {
+        self.headers.push((name.to_string(), value.into()));
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<String>) -> LocalReply {
+        self.body = Some(body.into());
+        self
+    }
+
+    fn send(self) {
+        let headers = self
+            .headers
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect();
+        let body = self.body.as_deref().map(str::as_bytes);
+        hostcalls::send_http_response(self.status, headers, body).unwrap();
+    }
+}
+
+/// One step of the pipeline. Every hook defaults to passing the stream on.
+pub trait Stage {
+    fn on_request_headers(&mut self, _num_headers: usize, _end_of_stream: bool) -> Flow {
+        Flow::Continue
+    }
+
+    fn on_request_body(&mut self, _body_size: usize, _end_of_stream: bool) -> Flow {
+        Flow::Continue
+    }
+
+    fn on_response_headers(&mut self, _num_headers: usize, _end_of_stream: bool) -> Flow {
+        Flow::Continue
+    }
+
+    fn on_response_body(&mut self, _body_size: usize, _end_of_stream: bool) -> Flow {
+        Flow::Continue
+    }
+
+    /// Delivered to the stage that paused the stream when its `dispatch_http_call` returns.
+    fn on_http_call_response(
+        &mut self,
+        _token_id: u32,
+        _num_headers: usize,
+        _body_size: usize,
+    ) -> Flow {
+        Flow::Continue
+    }
+
+    /// Delivered to the stage that paused the stream when its `dispatch_grpc_call` returns.
+    fn on_grpc_call_response(
+        &mut self,
+        _token_id: u32,
+        _status_code: u32,
+        _response_size: usize,
+    ) -> Flow {
+        Flow::Continue
+    }
+
+    /// Called on every stage before a local reply is sent, which skips the response hooks.
+    fn on_local_reply(&mut self, _reply: &mut LocalReply) {}
+
+    /// Called on every stage before the access log line is written.
+    fn on_access_log(&self, _request: &mut RequestLog) {}
+
+    /// Called on every stage once the stream is complete, including stages it never
+    /// reached.
+    fn on_log(&mut self) {}
+}
+
+/// Body bytes received so far on a stream. Stages can't count them from `body_size`
+/// alone: the host only keeps earlier chunks in the buffer while the pipeline pauses.
+#[derive(Default)]
+pub struct BodyBytes {
+    pub request: Cell<u64>,
+    pub response: Cell<u64>,
+}
+
+impl StageConfig {
+    fn build(&self, metrics: &Rc<Metrics>, body_bytes: &Rc<BodyBytes>) -> Box<dyn Stage> {
+        match self {
+            StageConfig::Headers(config) => {
+                Box::new(HeadersStage::new(Rc::clone(config), Rc::clone(metrics)))
+            }
+            StageConfig::Jwt(config) => Box::new(JwtStage::new(Rc::clone(config))),
+            StageConfig::ApiKey(config) => Box::new(ApiKeyStage::new(Rc::clone(config))),
+            StageConfig::BasicAuth(config) => Box::new(BasicAuthStage::new(Rc::clone(config))),
+            StageConfig::HmacSignature(config) => Box::new(SignatureStage::new(Rc::clone(config))),
+            StageConfig::ExtAuthz(config) => Box::new(ExtAuthzStage::new(Rc::clone(config))),
+            StageConfig::LocalRateLimit(config) => {
+                Box::new(LocalRateLimitStage::new(Rc::clone(config)))
+            }
+            StageConfig::Quota(config) => Box::new(QuotaStage::new(Rc::clone(config))),
+            StageConfig::GlobalRateLimit(config) => {
+                Box::new(GlobalRateLimitStage::new(Rc::clone(config)))
+            }
+            StageConfig::ConcurrencyLimit(config) => {
+                Box::new(ConcurrencyLimitStage::new(Rc::clone(config)))
+            }
+            StageConfig::Tracing(config) => Box::new(TracingStage::new(Rc::clone(config))),
+            StageConfig::RequestId(config) => Box::new(RequestIdStage::new(Rc::clone(config))),
+            StageConfig::BodyLimit(config) => Box::new(BodyLimitStage::new(
+                Rc::clone(config),
+                Rc::clone(body_bytes),
+            )),
+            StageConfig::JsonTransform(config) => {
+                Box::new(JsonTransformStage::new(Rc::clone(config)))
+            }
+        }
+    }
+}
+
+/// Factory for roots that run a stage pipeline.
+pub fn configure(raw: &[u8]) -> Result<Rc<dyn FilterFactory>, ConfigError> {
+    Ok(Rc::new(PipelineFactory::new(Config::parse(raw)?)))
+}
+
+/// Factory for `auth_root`, whose flat configuration is a single `jwt` stage.
+pub fn configure_auth(raw: &[u8]) -> Result<Rc<dyn FilterFactory>, ConfigError> {
+    let config = Config::parse_flat(raw, |value| {
+        let jwt: JwtConfig = config::deserialize(value)?;
+        jwt.validate("")?;
+        Ok(StageConfig::Jwt(Rc::new(jwt)))
+    })?;
+    Ok(Rc::new(PipelineFactory::new(config)))
+}
+
+/// Factory for `ratelimit_root`, whose flat configuration is a single
+/// `local_rate_limit` stage.
+pub fn configure_ratelimit(raw: &[u8]) -> Result<Rc<dyn FilterFactory>, ConfigError> {
+    let config = Config::parse_flat(raw, |value| {
+        let limit: LocalRateLimitConfig = config::deserialize(value)?;
+        limit.validate("")?;
+        Ok(StageConfig::LocalRateLimit(Rc::new(limit)))
+    })?;
+    Ok(Rc::new(PipelineFactory::new(config)))
+}
+
+struct PipelineFactory {
+    config: Config,
+    metrics: Rc<Metrics>,
+    access_log: Option<Rc<AccessLog>>,
+}
+
+impl PipelineFactory {
+    /// Defines the metrics of `config`, whose names the plugin configuration only gives
+    /// once the root is configured.
+    fn new(config: Config) -> PipelineFactory {
+        let stage_types: Vec<_> = config.stages.iter().map(StageConfig::kind).collect();
+        let metrics = Rc::new(Metrics::define(&config.metrics, &stage_types));
+        let access_log = config
+            .access_log
+            .clone()
+            .map(|access_log| Rc::new(AccessLog::new(access_log, stage_types)));
+        PipelineFactory {
+            config,
+            metrics,
+            access_log,
+        }
+    }
+
+    fn remote_jwks(&self) -> impl Iterator<Item = &RemoteJwks> {
+        self.config.stages.iter().filter_map(|stage| match stage {
+            StageConfig::Jwt(config) => config.remote_jwks.as_ref(),
+            _ => None,
+        })
+    }
+}
+
+impl FilterFactory for PipelineFactory {
+    fn create_filter(&self, _context_id: u32) -> Box<dyn HttpContext> {
+        let body_bytes = Rc::new(BodyBytes::default());
+        let stages = self
+            .config
+            .stages
+            .iter()
+            .map(|stage| stage.build(&self.metrics, &body_bytes))
+            .collect();
+        Box::new(Pipeline::new(
+            stages,
+            body_bytes,
+            Rc::clone(&self.metrics),
+            self.access_log.clone(),
+        ))
+    }
+
+    fn tick_period(&self) -> Option<Duration> {
+        // Each key set keeps its own schedule; the tick only has to be fine enough for it.
+        self.remote_jwks().next().map(|_| Duration::from_secs(1))
+    }
+
+    fn on_tick(&self) {
+        self.remote_jwks().for_each(RemoteJwks::on_tick);
+    }
+
+    fn on_http_call_response(&self, token_id: u32, _num_headers: usize, body_size: usize) {
+        self.remote_jwks()
+            .any(|remote| remote.on_root_response(token_id, body_size));
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Hook {
+    RequestHeaders(usize, bool),
+    RequestBody(usize, bool),
+    ResponseHeaders(usize, bool),
+    ResponseBody(usize, bool),
+}
+
+impl Hook {
+    fn call(self, stage: &mut dyn Stage) -> Flow {
+        match self {
+            Hook::RequestHeaders(num, eos) => stage.on_request_headers(num, eos),
+            Hook::RequestBody(size, eos) => stage.on_request_body(size, eos),
+            Hook::ResponseHeaders(num, eos) => stage.on_response_headers(num, eos),
+            Hook::ResponseBody(size, eos) => stage.on_response_body(size, eos),
+        }
+    }
+
+    /// 0 for the request, 1 for the response.
+    fn side(self) -> usize {
+        match self {
+            Hook::RequestHeaders(..) | Hook::RequestBody(..) => 0,
+            Hook::ResponseHeaders(..) | Hook::ResponseBody(..) => 1,
+        }
+    }
+
+    fn is_body(self) -> bool {
+        matches!(self, Hook::RequestBody(..) | Hook::ResponseBody(..))
+    }
+
+    fn resume(self) {
+        match self {
+            Hook::RequestHeaders(..) | Hook::RequestBody(..) => {
+                hostcalls::resume_http_request().unwrap()
+            }
+            Hook::ResponseHeaders(..) | Hook::ResponseBody(..) => {
+                hostcalls::resume_http_response().unwrap()
+            }
+        }
+    }
+}
+
+/// State of one direction of the stream.
+#[derive(Default)]
+struct Side {
+    /// Bytes of the body the host still holds from earlier calls.
+    held: usize,
+    /// A stage returned [`Flow::HoldHeaders`], so the headers go on with the end of the
+    /// body rather than when the header hooks are done.
+    headers_held: bool,
+    /// `end_of_stream` of body data that arrived while a header hook was paused. The
+    /// stages see it once the headers go on.
+    early_body: Option<bool>,
+}
+
+pub struct Pipeline {
+    stages: Vec<Box<dyn Stage>>,
+    /// The hook and stage index that paused the stream, if any.
+    paused: Option<(Hook, usize)>,
+    body_bytes: Rc<BodyBytes>,
+    /// The request and response sides.
+    sides: [Side; 2],
+    metrics: Rc<Metrics>,
+    /// Whether the request counts towards the requests in flight.
+    active: bool,
+    access_log: Option<Rc<AccessLog>>,
+    request: RequestLog,
+}
+
+impl Pipeline {
+    fn new(
+        stages: Vec<Box<dyn Stage>>,
+        body_bytes: Rc<BodyBytes>,
+        metrics: Rc<Metrics>,
+        access_log: Option<Rc<AccessLog>>,
+    ) -> Pipeline {
+        Pipeline {
+            request: RequestLog {
+                durations: vec![None; stages.len()],
+                ..RequestLog::default()
+            },
+            stages,
+            paused: None,
+            body_bytes,
+            sides: Default::default(),
+            metrics,
+            active: false,
+            access_log,
+        }
+    }
+
+    /// Calls the stage at index `i`, timing the call and counting its local replies.
+    fn call(&mut self, i: usize, hook: impl FnOnce(&mut dyn Stage) -> Flow) -> Flow {
+        let start = now_micros();
+        let flow = hook(self.stages[i].as_mut());
+        *self.request.durations[i].get_or_insert(0) += now_micros().saturating_sub(start);
+        if let Flow::Respond(reply) = &flow {
+            self.metrics.rejection(i, reply.reason);
+            self.request.reply = Some((i, reply.status));
+            self.request.status = Some(reply.status);
+        }
+        flow
+    }
+
+    /// Runs `hook` on the stages from index `from` on.
+    fn run(&mut self, hook: Hook, from: usize) -> Action {
+        for i in from..self.stages.len() {
+            match self.call(i, |stage| hook.call(stage)) {
+                Flow::Continue => {}
+                Flow::HoldHeaders => self.sides[hook.side()].headers_held = true,
+                Flow::Respond(reply) => {
+                    self.respond(reply);
+                    return Action::Pause;
+                }
+                Flow::Pause => {
+                    self.paused = Some((hook, i));
+                    return Action::Pause;
+                }
+            }
+        }
+        // Envoy sends held headers on with the body once a body hook continues.
+        if !hook.is_body() && self.sides[hook.side()].headers_held {
+            Action::Pause
+        } else {
+            Action::Continue
+        }
+    }
+
+    /// Hands a callout response to the stage that paused the stream, and carries on with
+    /// the stages after it if that stage lets the stream through.
+    fn wake(&mut self, deliver: impl FnOnce(&mut dyn Stage) -> Flow) {
+        let Some((hook, i)) = self.paused.take() else {
+            return;
+        };
+        match self.call(i, deliver) {
+            Flow::Continue => {}
+            Flow::HoldHeaders => self.sides[hook.side()].headers_held = true,
+            Flow::Respond(reply) => return self.respond(reply),
+            Flow::Pause => {
+                self.paused = Some((hook, i));
+                return;
+            }
+        }
+        self.run(hook, i + 1);
+        if !self.stopped() {
+            self.replay_body(hook);
+        }
+        // Headers an earlier stage held stay paused until the end of the body.
+        let resume = !self.stopped() && (hook.is_body() || !self.sides[hook.side()].headers_held);
+        if resume {
+            let side = &mut self.sides[hook.side()];
+            hook.resume();
+            side.held = 0;
+            side.headers_held = false;
+        }
+    }
+
+    /// Whether a stage paused the stream or answered the client.
+    fn stopped(&self) -> bool {
+        self.paused.is_some() || self.request.reply.is_some()
+    }
+
+    /// Runs a body hook, keeping count of the bytes received and of those the host holds.
+    fn body(&mut self, hook: Hook) -> Action {
+        let (Hook::RequestBody(body_size, end_of_stream)
+        | Hook::ResponseBody(body_size, end_of_stream)) = hook
+        else {
+            unreachable!("not a body hook");
+        };
+        let received = [&self.body_bytes.request, &self.body_bytes.response][hook.side()];
+        let side = &mut self.sides[hook.side()];
+        received.set(received.get() + body_size.saturating_sub(side.held) as u64);
+        // Envoy delivers the body even while the headers wait on a callout. Letting it
+        // through would send the headers on with it, before the stage made up its mind.
+        if matches!(self.paused, Some((paused, _)) if !paused.is_body() && paused.side() == hook.side())
+        {
+            side.held = body_size;
+            side.early_body = Some(end_of_stream);
+            return Action::Pause;
+        }
+        // A body hook only pauses to buffer the body, which this chunk carries on.
+        if matches!(self.paused, Some((paused, _)) if paused.side() == hook.side()) {
+            self.paused = None;
+        }
+        let action = self.run(hook, 0);
+        let side = &mut self.sides[hook.side()];
+        if action == Action::Pause {
+            side.held = body_size;
+        } else {
+            side.held = 0;
+            side.headers_held = false;
+        }
+        action
+    }
+
+    /// Runs the body data that arrived while the header hook `hook` was paused.
+    fn replay_body(&mut self, hook: Hook) {
+        let side = &mut self.sides[hook.side()];
+        let body = match (hook, side.early_body.take()) {
+            (Hook::RequestHeaders(..), Some(end_of_stream)) => {
+                Hook::RequestBody(side.held, end_of_stream)
+            }
+            (Hook::ResponseHeaders(..), Some(end_of_stream)) => {
+                Hook::ResponseBody(side.held, end_of_stream)
+            }
+            _ => return,
+        };
+        self.body(body);
+    }
+
+    fn respond(&mut self, mut reply: LocalReply) {
+        for stage in &mut self.stages {
+            stage.on_local_reply(&mut reply);
+        }
+        reply.send();
+    }
+}
+
+impl Context for Pipeline {
+    fn on_http_call_response(
+        &mut self,
+        token_id: u32,
+        num_headers: usize,
+        body_size: usize,
+        _num_trailers: usize,
+    ) {
+        self.wake(|stage| stage.on_http_call_response(token_id, num_headers, body_size));
+    }
+
+    fn on_grpc_call_response(&mut self, token_id: u32, status_code: u32, response_size: usize) {
+        self.wake(|stage| stage.on_grpc_call_response(token_id, status_code, response_size));
+    }
+}
+
+impl HttpContext for Pipeline {
+    fn on_http_request_headers(&mut self, num_headers: usize, end_of_stream: bool) -> Action {
+        self.metrics.request();
+        self.active = true;
+        self.request.start_ms = now_millis();
+        self.request.method =
+            hostcalls::get_map_value(MapType::HttpRequestHeaders, ":method").unwrap();
+        self.request.path = hostcalls::get_map_value(MapType::HttpRequestHeaders, ":path").unwrap();
+        self.run(Hook::RequestHeaders(num_headers, end_of_stream), 0)
+    }
+
+    fn on_http_request_body(&mut self, body_size: usize, end_of_stream: bool) -> Action {
+        self.body(Hook::RequestBody(body_size, end_of_stream))
+    }
+
+    fn on_http_response_headers(&mut self, num_headers: usize, end_of_stream: bool) -> Action {
+        self.request.status = hostcalls::get_map_value(MapType::HttpResponseHeaders, ":status")
+            .unwrap()
+            .and_then(|status| status.parse().ok());
+        self.run(Hook::ResponseHeaders(num_headers, end_of_stream), 0)
+    }
+
+    fn on_http_response_body(&mut self, body_size: usize, end_of_stream: bool) -> Action {
+        self.body(Hook::ResponseBody(body_size, end_of_stream))
+    }
+
+    fn on_log(&mut self) {
+        if self.active {
+            self.metrics.request_done();
+        }
+        for (i, duration) in self.request.durations.iter().enumerate() {
+            if let Some(duration) = duration {
+                self.metrics.stage_duration(i, *duration);
+            }
+        }
+        if let Some(access_log) = &self.access_log {
+            self.request.paused = self.paused.map(|(_, i)| i);
+            for stage in &self.stages {
+                stage.on_access_log(&mut self.request);
+            }
+            access_log.write(&self.request, now_millis());
+        }
+        self.stages.iter_mut().for_each(|stage| stage.on_log());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BodyBytes, Flow, LocalReply, Pipeline, Stage};
+    use crate::metrics::Metrics;
+    use crate::mock::{with_host, StreamData};
+    use proxy_wasm::traits::{Context, HttpContext};
+    use proxy_wasm::types::Action;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    type Calls = Rc<RefCell<Vec<String>>>;
+    type Script = fn(&str, bool) -> Flow;
+
+    /// A stage that answers each hook, given its name and `end_of_stream`, with `script`,
+    /// and records the hooks it saw.
+    struct Scripted {
+        name: &'static str,
+        script: Script,
+        calls: Calls,
+    }
+
+    impl Scripted {
+        fn hook(&mut self, hook: &str, end_of_stream: bool) -> Flow {
+            self.calls
+                .borrow_mut()
+                .push(format!("{}:{hook}", self.name));
+            (self.script)(hook, end_of_stream)
+        }
+    }
+
+    impl Stage for Scripted {
+        fn on_request_headers(&mut self, _num_headers: usize, end_of_stream: bool) -> Flow {
+            self.hook("request_headers", end_of_stream)
+        }
+
+        fn on_request_body(&mut self, _body_size: usize, end_of_stream: bool) -> Flow {
+            self.hook("request_body", end_of_stream)
+        }
+
+        fn on_http_call_response(
+            &mut self,
+            _token_id: u32,
+            _num_headers: usize,
+            _body_size: usize,
+        ) -> Flow {
+            self.hook("http_call_response", true)
+        }
+
+        fn on_local_reply(&mut self, reply: &mut LocalReply) {
+            reply
+                .headers
+                .push((format!("x-{}", self.name), "1".to_string()));
+        }
+    }
+
+    fn pipeline(stages: &[(&'static str, Script)]) -> (Pipeline, Calls) {
+        let calls = Calls::default();
+        let stages = stages
+            .iter()
+            .map(|&(name, script)| {
+                Box::new(Scripted {
+                    name,
+                    script,
+                    calls: Rc::clone(&calls),
+                }) as Box<dyn Stage>
+            })
+            .collect::<Vec<_>>();
+        let kinds = vec!["scripted"; stages.len()];
+        let metrics = Metrics::define(&serde_json::from_str("{}").unwrap(), &kinds);
+        let body_bytes = Rc::new(BodyBytes::default());
+        (
+            Pipeline::new(stages, body_bytes, Rc::new(metrics), None),
+            calls,
+        )
+    }
+
+    fn stream<R>(f: impl FnOnce(&StreamData) -> R) -> R {
+        with_host(|host| {
+            let current = host.current;
+            f(host.streams.entry(current).or_default())
+        })
+    }
+
+    fn taken(calls: &Calls) -> Vec<String> {
+        calls.borrow_mut().drain(..).collect()
+    }
+
+    #[test]
+    fn pauses_until_woken() {
+        let (mut pipeline, calls) = pipeline(&[
+            ("callout", |hook, _| match hook {
+                "request_headers" => Flow::Pause,
+                _ => Flow::Continue,
+            }),
+            ("next", |_, _| Flow::Continue),
+        ]);
+        assert_eq!(pipeline.on_http_request_headers(1, false), Action::Pause);
+        assert_eq!(pipeline.on_http_request_body(4, true), Action::Pause);
+        assert_eq!(taken(&calls), ["callout:request_headers"]);
+        assert!(!stream(|data| data.request_resumed));
+
+        pipeline.on_http_call_response(1, 0, 0, 0);
+        assert_eq!(
+            taken(&calls),
+            [
+                "callout:http_call_response",
+                "next:request_headers",
+                "callout:request_body",
+                "next:request_body"
+            ]
+        );
+        assert!(stream(|data| data.request_resumed));
+    }
+
+    #[test]
+    fn local_replies_pass_every_stage() {
+        let (mut pipeline, calls) = pipeline(&[
+            ("first", |_, _| Flow::Continue),
+            ("deny", |_, _| {
+                Flow::Respond(LocalReply::new(403).body("no"))
+            }),
+            ("last", |_, _| Flow::Continue),
+        ]);
+        assert_eq!(pipeline.on_http_request_headers(1, true), Action::Pause);
+        assert_eq!(
+            taken(&calls),
+            ["first:request_headers", "deny:request_headers"]
+        );
+        let reply = stream(|data| data.local_response.clone()).unwrap();
+        assert_eq!(reply.status, 403);
+        let names: Vec<_> = reply
+            .headers
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect();
+        assert_eq!(names, ["x-first", "x-deny", "x-last"]);
+        assert_eq!(pipeline.request.reply, Some((1, 403)));
+    }
+
+    #[test]
+    fn held_headers_go_on_with_the_body() {
+        let (mut pipeline, calls) = pipeline(&[
+            ("hold", |hook, end_of_stream| match hook {
+                "request_headers" => Flow::HoldHeaders,
+                _ if end_of_stream => Flow::Continue,
+                _ => Flow::Pause,
+            }),
+            ("next", |_, _| Flow::Continue),
+        ]);
+        assert_eq!(pipeline.on_http_request_headers(1, false), Action::Pause);
+        assert_eq!(
+            taken(&calls),
+            ["hold:request_headers", "next:request_headers"]
+        );
+        assert_eq!(pipeline.on_http_request_body(3, false), Action::Pause);
+        assert_eq!(taken(&calls), ["hold:request_body"]);
+        assert_eq!(pipeline.on_http_request_body(5, true), Action::Continue);
+        assert_eq!(taken(&calls), ["hold:request_body", "next:request_body"]);
+        assert!(pipeline.paused.is_none());
+        assert!(!pipeline.sides[0].headers_held);
+    }
+
+    #[test]
+    fn counts_body_bytes_the_host_holds_once() {
+        let (mut pipeline, _) = pipeline(&[("buffer", |hook, end_of_stream| match hook {
+            "request_body" if !end_of_stream => Flow::Pause,
+            _ => Flow::Continue,
+        })]);
+        pipeline.on_http_request_headers(1, false);
+        // Each call hands over the chunks received so far while the stage buffers.
+        assert_eq!(pipeline.on_http_request_body(3, false), Action::Pause);
+        assert_eq!(pipeline.on_http_request_body(7, false), Action::Pause);
+        assert_eq!(pipeline.sides[0].held, 7);
+        assert_eq!(pipeline.on_http_request_body(9, true), Action::Continue);
+        assert_eq!(pipeline.sides[0].held, 0);
+        assert_eq!(pipeline.body_bytes.request.get(), 9);
+    }
+}
@@ -0,0 +1,210 @@
+//! Just enough of the protobuf wire format for the gRPC services the module calls.
+//!
+//! Messages are written and read field by field, so each service keeps the handful of
+//! field numbers it needs next to the code that uses them.
+
+use std::fmt;
+
+/// Writes a message. Like proto3, fields holding their default value are left out.
+#[derive(Default)]
+pub struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    pub fn new() -> Encoder {
+        Encoder::default()
+    }
+
+    pub fn uint64(&mut self, field: u32, value: u64) -> &mut Encoder {
+        if value != 0 {
+            self.key(field, WIRE_VARINT);
+            varint(&mut self.buf, value);
+        }
+        self
+    }
+
+    pub fn bytes(&mut self, field: u32, value: &[u8]) -> &mut Encoder {
+        if !value.is_empty() {
+            self.key(field, WIRE_LEN);
+            varint(&mut self.buf, value.len() as u64);
+            self.buf.extend_from_slice(value);
+        }
+        self
+    }
+
+    pub fn string(&mut self, field: u32, value: &str) -> &mut Encoder {
+        self.bytes(field, value.as_bytes())
+    }
+
+    /// Writes the nested message built by `build`, even if it is empty.
+    pub fn message(&mut self, field: u32, build: impl FnOnce(&mut Encoder)) -> &mut Encoder {
+        let mut nested = Encoder::new();
+        build(&mut nested);
+        self.key(field, WIRE_LEN);
+        varint(&mut self.buf, nested.buf.len() as u64);
+        self.buf.extend_from_slice(&nested.buf);
+        self
+    }
+
+    pub fn finish(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.buf)
+    }
+
+    fn key(&mut self, field: u32, wire_type: u64) {
+        varint(&mut self.buf, (u64::from(field) << 3) | wire_type);
+    }
+}
+
+const WIRE_VARINT: u64 = 0;
+const WIRE_FIXED64: u64 = 1;
+const WIRE_LEN: u64 = 2;
+const WIRE_FIXED32: u64 = 5;
+
+fn varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+/// A field value as found on the wire; its meaning depends on the field's declared type.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value<'a> {
+    Varint(u64),
+    Fixed64(u64),
+    Fixed32(u32),
+    Bytes(&'a [u8]),
+}
+
+impl<'a> Value<'a> {
+    pub fn as_u64(self) -> Result<u64, DecodeError> {
+        match self {
+            Value::Varint(value) | Value::Fixed64(value) => Ok(value),
+            Value::Fixed32(value) => Ok(value.into()),
+            Value::Bytes(_) => Err(DecodeError),
+        }
+    }
+
+    pub fn as_bytes(self) -> Result<&'a [u8], DecodeError> {
+        match self {
+            Value::Bytes(bytes) => Ok(bytes),
+            _ => Err(DecodeError),
+        }
+    }
+
+    pub fn as_str(self) -> Result<&'a str, DecodeError> {
+        std::str::from_utf8(self.as_bytes()?).map_err(|_| DecodeError)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct DecodeError;
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("malformed protobuf message")
+    }
+}
+
+/// Iterates over the `(field number, value)` pairs of a message, in wire order.
+pub fn fields(buf: &[u8]) -> Fields<'_> {
+    Fields { buf }
+}
+
+pub struct Fields<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Fields<'a> {
+    fn varint(&mut self) -> Result<u64, DecodeError> {
+        let mut value = 0;
+        for (i, byte) in self.buf.iter().enumerate().take(10) {
+            value |= u64::from(byte & 0x7f) << (7 * i);
+            if byte & 0x80 == 0 {
+                self.buf = &self.buf[i + 1..];
+                return Ok(value);
+            }
+        }
+        Err(DecodeError)
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        if self.buf.len() < len {
+            return Err(DecodeError);
+        }
+        let (taken, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(taken)
+    }
+
+    fn field(&mut self) -> Result<(u32, Value<'a>), DecodeError> {
+        let key = self.varint()?;
+        let field = u32::try_from(key >> 3).map_err(|_| DecodeError)?;
+        let value = match key & 7 {
+            WIRE_VARINT => Value::Varint(self.varint()?),
+            WIRE_FIXED64 => Value::Fixed64(u64::from_le_bytes(self.take(8)?.try_into().unwrap())),
+            WIRE_LEN => {
+                let len = usize::try_from(self.varint()?).map_err(|_| DecodeError)?;
+                Value::Bytes(self.take(len)?)
+            }
+            WIRE_FIXED32 => Value::Fixed32(u32::from_le_bytes(self.take(4)?.try_into().unwrap())),
+            _ => return Err(DecodeError),
+        };
+        Ok((field, value))
+    }
+}
+
+impl<'a> Iterator for Fields<'a> {
+    type Item = Result<(u32, Value<'a>), DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buf.is_empty() {
+            return None;
+        }
+        let field = self.field();
+        if field.is_err() {
+            self.buf = &[];
+        }
+        Some(field)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_nested_messages() {
+        let message = Encoder::new()
+            .uint64(1, 300)
+            .string(2, "")
+            .message(3, |nested| {
+                nested.string(1, "key").uint64(2, 1);
+            })
+            .finish();
+        // 300 is the example varint of the protobuf encoding guide.
+        assert_eq!(&message[..3], [0x08, 0xac, 0x02]);
+
+        let decoded: Vec<_> = fields(&message).collect::<Result<_, _>>().unwrap();
+        assert_eq!(decoded.len(), 2);
+        assert_eq!(decoded[0], (1, Value::Varint(300)));
+        let (3, nested) = decoded[1] else {
+            panic!("expected field 3, got {:?}", decoded[1]);
+        };
+        let nested: Vec<_> = fields(nested.as_bytes().unwrap())
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(nested, [(1, Value::Bytes(b"key")), (2, Value::Varint(1))]);
+    }
+
+    #[test]
+    fn rejects_truncated_messages() {
+        let message = Encoder::new().string(1, "hello").finish();
+        let mut truncated = fields(&message[..4]);
+        assert_eq!(truncated.next(), Some(Err(DecodeError)));
+        assert_eq!(truncated.next(), None);
+        assert!(fields(&[0x80]).next().unwrap().is_err());
+    }
+}
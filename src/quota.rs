@@ -0,0 +1,388 @@
+//! Quotas counted over sliding windows, for limits such as a billing plan's requests per
+//! hour that a token bucket's constant refill does not express.
+//!
+//! Each window keeps a counter for the current and the previous fixed interval, in shared
+//! data. The count over the sliding window is the current counter plus the previous one
+//! weighted by how much of the previous interval the window still covers.
+
+use crate::config::{field, validate_header_name, ConfigError, StageConfig};
+use crate::matcher::{Match, RequestInfo};
+use crate::pipeline::{Flow, Stage};
+use crate::ratelimit::{update_shared, Decision};
+use crate::util::now_millis;
+use proxy_wasm::hostcalls;
+use proxy_wasm::types::MapType;
+use serde::Deserialize;
+use std::rc::Rc;
+
+/// Configuration of the `quota` stage.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QuotaConfig {
+    /// Request header naming the consumer, which an earlier authentication stage must set.
+    #[serde(default = "default_consumer_header")]
+    pub consumer_header: String,
+    /// The quota table. The first row that applies to a request counts it.
+    pub quotas: Vec<Quota>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Quota {
+    /// Names the counters in shared data, so keep it when editing the row.
+    pub name: String,
+    #[serde(rename = "match", default)]
+    pub matches: Match,
+    /// Consumers the row applies to; every consumer when empty.
+    #[serde(default)]
+    pub consumers: Vec<String>,
+    #[serde(default)]
+    pub per: QuotaKey,
+    /// Requests each consumer, or the whole row, may make per window.
+    pub limit: u64,
+    pub window_seconds: u64,
+}
+
+/// What a row counts requests by.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QuotaKey {
+    /// Each consumer has a counter of its own.
+    #[default]
+    Consumer,
+    /// All requests the row applies to share one counter, e.g. for a route's quota.
+    Row,
+}
+
+fn default_consumer_header() -> String {
+    "x-consumer".to_string()
+}
+
+impl QuotaConfig {
+    pub fn validate(&self, parent: &str) -> Result<(), ConfigError> {
+        validate_header_name(&field(parent, "consumer_header"), &self.consumer_header)?;
+        if self.quotas.is_empty() {
+            return Err(ConfigError::new(
+                field(parent, "quotas"),
+                "at least one quota is required",
+            ));
+        }
+        for (i, quota) in self.quotas.iter().enumerate() {
+            let quota_field = field(parent, &format!("quotas[{i}]"));
+            if quota.name.is_empty() {
+                return Err(ConfigError::new(
+                    field(&quota_field, "name"),
+                    "name must not be empty",
+                ));
+            }
+            if self.quotas[..i]
+                .iter()
+                .any(|other| other.name == quota.name)
+            {
+                return Err(ConfigError::new(
+                    field(&quota_field, "name"),
+                    format!("duplicate quota `{}`", quota.name),
+                ));
+            }
+            quota.matches.validate(&field(&quota_field, "match"))?;
+            for (name, value) in [
+                ("limit", quota.limit),
+                ("window_seconds", quota.window_seconds),
+            ] {
+                if value == 0 {
+                    return Err(ConfigError::new(
+                        field(&quota_field, name),
+                        "must be positive",
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that one of the `earlier` stages authenticates the consumers, if any row
+    /// needs them. A client could otherwise name any consumer, and every new name would
+    /// add counters to shared data.
+    pub fn validate_consumers(
+        &self,
+        parent: &str,
+        earlier: &[StageConfig],
+    ) -> Result<(), ConfigError> {
+        let needed = self
+            .quotas
+            .iter()
+            .any(|quota| quota.per == QuotaKey::Consumer || !quota.consumers.is_empty());
+        if needed
+            && !earlier
+                .iter()
+                .any(|stage| stage.sets_request_header(&self.consumer_header))
+        {
+            return Err(ConfigError::new(
+                field(parent, "consumer_header"),
+                format!(
+                    "no earlier authentication stage sets `{}`",
+                    self.consumer_header
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Quota {
+    /// Whether the row counts the request, whose consumer is `None` if no stage
+    /// authenticated one.
+    fn applies(&self, request: &RequestInfo, consumer: Option<&str>) -> bool {
+        let consumer_applies = match consumer {
+            Some(consumer) => {
+                self.consumers.is_empty() || self.consumers.iter().any(|c| c == consumer)
+            }
+            None => self.per == QuotaKey::Row && self.consumers.is_empty(),
+        };
+        consumer_applies && self.matches.matches(request)
+    }
+
+    /// Counts a request of `consumer`, unless that would exceed the quota.
+    fn count(&self, consumer: Option<&str>) -> Option<Decision> {
+        let key = match (self.per, consumer) {
+            (QuotaKey::Consumer, Some(consumer)) => format!(
+                "wasmup.quota.{}.{}",
+                self.name,
+                serde_json::to_string(consumer).unwrap()
+            ),
+            _ => format!("wasmup.quota.{}", self.name),
+        };
+        let window = self.window_seconds * 1000;
+        let now = now_millis();
+        let interval = now / window;
+        let elapsed = now % window;
+        update_shared(&key, |value| {
+            // Stored as the index of the current interval and the counters of the
+            // current and previous intervals.
+            let stored = value.filter(|value| value.len() == 24).map(|value| {
+                let word = |i: usize| u64::from_le_bytes(value[i * 8..][..8].try_into().unwrap());
+                (word(0), word(1), word(2))
+            });
+            let (current, previous) = match stored {
+                Some((at, current, previous)) if at == interval => (current, previous),
+                Some((at, current, _)) if at + 1 == interval => (0, current),
+                _ => (0, 0),
+            };
+            // Rounded up, so the quota is never exceeded.
+            let weighted =
+                (u128::from(previous) * u128::from(window - elapsed)).div_ceil(u128::from(window));
+            let used = weighted as u64 + current;
+            let allowed = used < self.limit;
+            let current = current + u64::from(allowed);
+            let decision = Decision {
+                allowed,
+                limit: self.limit,
+                remaining: self.limit.saturating_sub(used + u64::from(allowed)),
+                reset_ms: self.retry_ms(current, previous, elapsed),
+            };
+            let value = [interval, current, previous]
+                .iter()
+                .flat_map(|word| word.to_le_bytes())
+                .collect();
+            (decision, value)
+        })
+    }
+
+    /// Time until the sliding count drops below the limit, given the counters of the
+    /// current and previous intervals, `elapsed` milliseconds into the current one.
+    fn retry_ms(&self, current: u64, previous: u64, elapsed: u64) -> u64 {
+        let window = self.window_seconds * 1000;
+        // How far into an interval the weight of the previous interval's `counter` is
+        // down to `room` requests. The weight falls by `counter / window` per millisecond.
+        let fallen_to = |room: u64, counter: u64| {
+            if counter <= room {
+                return 0;
+            }
+            let covered = u128::from(room) * u128::from(window) / u128::from(counter);
+            window - covered as u64
+        };
+        if current < self.limit {
+            // Wait in this interval until the previous one leaves room for a request.
+            fallen_to(self.limit - current - 1, previous).saturating_sub(elapsed)
+        } else {
+            // Wait for the next interval, where this one becomes the previous.
+            (window - elapsed) + fallen_to(self.limit - 1, current)
+        }
+    }
+}
+
+/// Counts requests against the first quota that applies to them.
+pub struct QuotaStage {
+    config: Rc<QuotaConfig>,
+    decision: Option<Decision>,
+}
+
+impl QuotaStage {
+    pub fn new(config: Rc<QuotaConfig>) -> QuotaStage {
+        QuotaStage {
+            config,
+            decision: None,
+        }
+    }
+}
+
+impl Stage for QuotaStage {
+    fn on_request_headers(&mut self, _num_headers: usize, _end_of_stream: bool) -> Flow {
+        let request = RequestInfo::new(hostcalls::get_map(MapType::HttpRequestHeaders).unwrap());
+        let consumer = request
+            .header(&self.config.consumer_header)
+            .filter(|consumer| !consumer.is_empty());
+        self.decision = self
+            .config
+            .quotas
+            .iter()
+            .find(|quota| quota.applies(&request, consumer))
+            .and_then(|quota| quota.count(consumer));
+        match self.decision {
+            Some(decision) if !decision.allowed => Flow::Respond(decision.reply()),
+            _ => Flow::Continue,
+        }
+    }
+
+    fn on_response_headers(&mut self, _num_headers: usize, _end_of_stream: bool) -> Flow {
+        if let Some(decision) = self.decision {
+            for (name, value) in decision.headers() {
+                hostcalls::set_map_value(MapType::HttpResponseHeaders, name, Some(&value)).unwrap();
+            }
+        }
+        Flow::Continue
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mock::{with_host, Plugin};
+    use serde_json::{json, Value};
+    use sha2::{Digest, Sha256};
+    use std::time::Duration;
+
+    /// A quota stage with `quotas`, behind an `api_key` stage whose keys are the names of
+    /// their consumers.
+    fn plugin(quotas: Value) -> Plugin {
+        let keys: Vec<_> = ["alice", "bob", "gold", "free"]
+            .iter()
+            .map(|consumer| json!({"consumer": consumer, "sha256": hex::encode(Sha256::digest(consumer))}))
+            .collect();
+        let config = json!({"stages": [
+            {"type": "api_key", "keys": keys},
+            {"type": "quota", "quotas": quotas}
+        ]});
+        Plugin::new("add_header_root", &config.to_string())
+    }
+
+    /// Sends a request and returns its status with the remaining quota it reports.
+    fn send(plugin: &mut Plugin, path: &str, consumer: &str) -> (u32, Option<String>) {
+        let stream = plugin.stream();
+        stream.request_headers(&[(":path", path), ("x-api-key", consumer)], true);
+        if let Some(reply) = stream.local_response() {
+            let retry = reply
+                .headers
+                .iter()
+                .find(|(name, _)| name == "retry-after")
+                .map(|(_, value)| value.clone());
+            return (reply.status, retry);
+        }
+        stream.response_headers(&[(":status", "200")], true);
+        (200, stream.response_header("ratelimit-remaining"))
+    }
+
+    fn advance(millis: u64) {
+        with_host(|host| host.now += Duration::from_millis(millis));
+    }
+
+    #[test]
+    fn window_slides_over_the_previous_interval() {
+        with_host(|host| host.now = Duration::from_secs(1_700_000_000 / 60 * 60));
+        let mut plugin = plugin(json!([{"name": "hourly", "limit": 4, "window_seconds": 60}]));
+        for remaining in ["3", "2", "1", "0"] {
+            assert_eq!(
+                send(&mut plugin, "/", "alice"),
+                (200, Some(remaining.to_string()))
+            );
+        }
+        // Full until a quarter into the next interval, when one of these four drops out.
+        assert_eq!(
+            send(&mut plugin, "/", "alice"),
+            (429, Some("75".to_string()))
+        );
+        advance(60_000);
+        assert_eq!(
+            send(&mut plugin, "/", "alice"),
+            (429, Some("15".to_string()))
+        );
+        advance(15_000);
+        assert_eq!(
+            send(&mut plugin, "/", "alice"),
+            (200, Some("0".to_string()))
+        );
+        // Other consumers have their own counters.
+        assert_eq!(send(&mut plugin, "/", "bob"), (200, Some("3".to_string())));
+    }
+
+    #[test]
+    fn first_applicable_row_counts() {
+        with_host(|host| host.now = Duration::from_secs(1_700_000_000));
+        let mut plugin = plugin(json!([
+            {"name": "gold", "consumers": ["gold"], "limit": 100, "window_seconds": 3600},
+            {"name": "reports", "match": {"path": {"prefix": "/reports"}}, "limit": 1, "window_seconds": 3600},
+            {"name": "search", "match": {"path": {"prefix": "/search"}}, "per": "row", "limit": 2, "window_seconds": 3600},
+            {"name": "default", "limit": 10, "window_seconds": 3600}
+        ]));
+        assert_eq!(
+            send(&mut plugin, "/reports/1", "gold").1.as_deref(),
+            Some("99")
+        );
+        assert_eq!(send(&mut plugin, "/reports/1", "free").0, 200);
+        assert_eq!(send(&mut plugin, "/reports/2", "free").0, 429);
+        assert_eq!(send(&mut plugin, "/orders", "free").1.as_deref(), Some("9"));
+        assert!(with_host(|host| host
+            .shared_data
+            .contains_key(r#"wasmup.quota.reports."free""#)));
+        // The route's quota is shared by all consumers.
+        assert_eq!(send(&mut plugin, "/search", "alice").0, 200);
+        assert_eq!(send(&mut plugin, "/search", "bob").0, 200);
+        assert_eq!(send(&mut plugin, "/search", "free").0, 429);
+        assert!(with_host(|host| host
+            .shared_data
+            .contains_key("wasmup.quota.search")));
+    }
+
+    #[test]
+    fn rejects_duplicate_names_and_unauthenticated_consumers() {
+        let quota = json!({"name": "a", "limit": 1, "window_seconds": 1});
+        let config = json!({"stages": [{"type": "quota", "quotas": [quota, quota]}]});
+        let (_, accepted) = Plugin::try_new("add_header_root", &config.to_string());
+        assert!(!accepted);
+        assert!(with_host(|host| host.logged("duplicate quota `a`")));
+
+        let config = json!({"stages": [{"type": "quota", "quotas": [quota]}]});
+        let (_, accepted) = Plugin::try_new("add_header_root", &config.to_string());
+        assert!(!accepted);
+        assert!(with_host(
+            |host| host.logged("no earlier authentication stage sets `x-consumer`")
+        ));
+        let quota = json!({"name": "a", "per": "row", "limit": 1, "window_seconds": 1});
+        let config = json!({"stages": [{"type": "quota", "quotas": [quota]}]});
+        assert!(Plugin::try_new("add_header_root", &config.to_string()).1);
+    }
+
+    #[test]
+    fn ext_authz_can_name_the_consumer() {
+        let quota = json!({"name": "a", "limit": 1, "window_seconds": 1});
+        let ext_authz = |headers: serde_json::Value| json!({"type": "ext_authz", "cluster": "authz", "allowed_upstream_headers": headers});
+        // Headers the service may set pass through when it doesn't, so they don't count.
+        let mut authz = ext_authz(json!(["x-consumer"]));
+        let config = json!({"stages": [authz, {"type": "quota", "quotas": [quota]}]});
+        assert!(!Plugin::try_new("add_header_root", &config.to_string()).1);
+
+        authz = ext_authz(json!([]));
+        authz["trusted_upstream_headers"] = json!(["x-consumer"]);
+        let config = json!({"stages": [authz, {"type": "quota", "quotas": [quota]}]});
+        assert!(Plugin::try_new("add_header_root", &config.to_string()).1);
+    }
+}
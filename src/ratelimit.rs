@@ -0,0 +1,387 @@
+//! Local rate limiting with token buckets.
+//!
+//! Buckets live in shared data and are updated with compare-and-swap, so every worker
+//! VM of the gateway draws from the same budget. The values of the configured `key` are
+//! hashed into a fixed number of buckets, since shared data can't be deleted.
+
+use crate::config::{field, validate_header_name, ConfigError};
+use crate::matcher::RequestInfo;
+use crate::pipeline::{Flow, LocalReply, Stage};
+use crate::util::now_millis;
+use log::warn;
+use proxy_wasm::hostcalls;
+use proxy_wasm::types::{MapType, Status};
+use serde::Deserialize;
+use std::rc::Rc;
+
+/// Configuration of the `local_rate_limit` stage.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LocalRateLimitConfig {
+    /// Names the buckets in shared data; stages with the same name share them.
+    #[serde(default)]
+    pub name: String,
+    /// What requests are counted together. Requests missing a part are not limited.
+    #[serde(default)]
+    pub key: Vec<KeyPart>,
+    /// Size of each bucket, and so the largest burst.
+    pub max_tokens: u64,
+    #[serde(default = "default_tokens_per_fill")]
+    pub tokens_per_fill: u64,
+    #[serde(default = "default_fill_interval")]
+    pub fill_interval_ms: u64,
+    /// Whether allowed responses report the bucket in `RateLimit-*` headers too.
+    #[serde(default = "default_rate_limit_headers")]
+    pub rate_limit_headers: bool,
+    /// Number of buckets the keys are hashed into. Keys that collide share a bucket.
+    #[serde(default = "default_slots")]
+    pub slots: u64,
+}
+
+/// One part of a rate limit key, taken from the request.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum KeyPart {
+    /// The downstream address without its port.
+    ClientIp,
+    Method,
+    /// The path without its query string.
+    Path,
+    Header(String),
+}
+
+fn default_tokens_per_fill() -> u64 {
+    1
+}
+
+fn default_fill_interval() -> u64 {
+    1000
+}
+
+fn default_rate_limit_headers() -> bool {
+    true
+}
+
+fn default_slots() -> u64 {
+    65536
+}
+
+/// Compare-and-swap attempts before a request is let through unaccounted.
+const CAS_ATTEMPTS: usize = 8;
+
+impl KeyPart {
+    pub fn validate(&self, field: &str) -> Result<(), ConfigError> {
+        match self {
+            KeyPart::Header(name) => validate_header_name(field, name),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn value(&self, request: &RequestInfo) -> Option<String> {
+        match self {
+            KeyPart::ClientIp => client_ip(),
+            KeyPart::Method => Some(request.method.clone()),
+            KeyPart::Path => Some(request.path.clone()),
+            KeyPart::Header(name) => request.header(name).map(str::to_string),
+        }
+    }
+}
+
+/// The values of `parts` for `request`, or `None` if one of them is missing.
+pub fn key_values(parts: &[KeyPart], request: &RequestInfo) -> Option<Vec<String>> {
+    parts.iter().map(|part| part.value(request)).collect()
+}
+
+fn client_ip() -> Option<String> {
+    let address = hostcalls::get_property(vec!["source", "address"]).unwrap()?;
+    let address = String::from_utf8(address).ok()?;
+    let ip = match address.rsplit_once(':') {
+        // `[::1]:8080`
+        Some((ip, _)) if ip.starts_with('[') => ip.trim_start_matches('[').trim_end_matches(']'),
+        // `10.0.0.1:8080`, but not a bare IPv6 address
+        Some((ip, _)) if !ip.contains(':') => ip,
+        _ => &address,
+    };
+    Some(ip.to_string())
+}
+
+/// 64-bit FNV-1a, which every worker computes the same for the same bytes.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// The outcome of counting one request against a limit.
+#[derive(Debug, Clone, Copy)]
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: u64,
+    pub remaining: u64,
+    /// Time until the budget grows again.
+    pub reset_ms: u64,
+}
+
+impl Decision {
+    /// The `RateLimit-*` headers describing the decision, with `Retry-After` once the
+    /// limit is reached.
+    pub fn headers(&self) -> Vec<(&'static str, String)> {
+        let reset = self.reset_ms.div_ceil(1000).max(1).to_string();
+        let mut headers = vec![
+            ("ratelimit-limit", self.limit.to_string()),
+            ("ratelimit-remaining", self.remaining.to_string()),
+            ("ratelimit-reset", reset.clone()),
+        ];
+        if !self.allowed {
+            headers.push(("retry-after", reset));
+        }
+        headers
+    }
+
+    /// The 429 reply for a request over the limit.
+    pub fn reply(&self) -> LocalReply {
+        self.headers()
+            .into_iter()
+            .fold(LocalReply::new(429), |reply, (name, value)| {
+                reply.header(name, value)
+            })
+            .body("rate limit exceeded")
+            .reason("rate_limited")
+    }
+}
+
+/// Applies `update` to the shared data entry `key` until no other VM changed it in
+/// between. Returns `None` if that never happened.
+pub fn update_shared<T>(
+    key: &str,
+    mut update: impl FnMut(Option<&[u8]>) -> (T, Vec<u8>),
+) -> Option<T> {
+    for _ in 0..CAS_ATTEMPTS {
+        let (value, cas) = hostcalls::get_shared_data(key).unwrap();
+        let (result, value) = update(value.as_deref());
+        match hostcalls::set_shared_data(key, Some(&value), cas) {
+            Ok(()) => return Some(result),
+            Err(Status::CasMismatch) => continue,
+            Err(status) => {
+                warn!("updating shared data `{key}`: {status:?}");
+                return None;
+            }
+        }
+    }
+    warn!("updating shared data `{key}`: too much contention");
+    None
+}
+
+impl LocalRateLimitConfig {
+    pub fn validate(&self, parent: &str) -> Result<(), ConfigError> {
+        for (i, part) in self.key.iter().enumerate() {
+            part.validate(&field(parent, &format!("key[{i}].header")))?;
+        }
+        for (name, value) in [
+            ("max_tokens", self.max_tokens),
+            ("tokens_per_fill", self.tokens_per_fill),
+            ("fill_interval_ms", self.fill_interval_ms),
+            ("slots", self.slots),
+        ] {
+            if value == 0 {
+                return Err(ConfigError::new(field(parent, name), "must be positive"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Takes a token from the bucket of `request`, if its key is complete.
+    pub fn take(&self, request: &RequestInfo) -> Option<Decision> {
+        let values = key_values(&self.key, request)?;
+        let slot = fnv1a(serde_json::to_string(&values).unwrap().as_bytes()) % self.slots;
+        let key = format!("wasmup.ratelimit.{}.{slot}", self.name);
+        let now = now_millis();
+        update_shared(&key, |value| {
+            // A bucket is stored as its token count and the time it was last filled.
+            let (mut tokens, mut filled) = value
+                .filter(|value| value.len() == 16)
+                .map(|value| {
+                    let (tokens, filled) = value.split_at(8);
+                    (
+                        u64::from_le_bytes(tokens.try_into().unwrap()),
+                        u64::from_le_bytes(filled.try_into().unwrap()),
+                    )
+                })
+                .unwrap_or((self.max_tokens, now));
+            let fills = now.saturating_sub(filled) / self.fill_interval_ms;
+            tokens = tokens
+                .saturating_add(fills.saturating_mul(self.tokens_per_fill))
+                .min(self.max_tokens);
+            filled += fills * self.fill_interval_ms;
+            if tokens == self.max_tokens {
+                filled = now;
+            }
+            let allowed = tokens > 0;
+            if allowed {
+                tokens -= 1;
+            }
+            let decision = Decision {
+                allowed,
+                limit: self.max_tokens,
+                remaining: tokens,
+                reset_ms: (filled + self.fill_interval_ms).saturating_sub(now),
+            };
+            let mut value = tokens.to_le_bytes().to_vec();
+            value.extend_from_slice(&filled.to_le_bytes());
+            (decision, value)
+        })
+    }
+}
+
+/// Rejects requests once their bucket is empty.
+pub struct LocalRateLimitStage {
+    config: Rc<LocalRateLimitConfig>,
+    decision: Option<Decision>,
+}
+
+impl LocalRateLimitStage {
+    pub fn new(config: Rc<LocalRateLimitConfig>) -> LocalRateLimitStage {
+        LocalRateLimitStage {
+            config,
+            decision: None,
+        }
+    }
+}
+
+impl Stage for LocalRateLimitStage {
+    fn on_request_headers(&mut self, _num_headers: usize, _end_of_stream: bool) -> Flow {
+        let request = RequestInfo::new(hostcalls::get_map(MapType::HttpRequestHeaders).unwrap());
+        self.decision = self.config.take(&request);
+        match self.decision {
+            Some(decision) if !decision.allowed => Flow::Respond(decision.reply()),
+            _ => Flow::Continue,
+        }
+    }
+
+    fn on_response_headers(&mut self, _num_headers: usize, _end_of_stream: bool) -> Flow {
+        if let Some(decision) = self.decision.filter(|_| self.config.rate_limit_headers) {
+            for (name, value) in decision.headers() {
+                hostcalls::set_map_value(MapType::HttpResponseHeaders, name, Some(&value)).unwrap();
+            }
+        }
+        Flow::Continue
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mock::{with_host, Plugin};
+    use serde_json::json;
+    use std::time::Duration;
+
+    fn plugin(stage: serde_json::Value) -> Plugin {
+        with_host(|host| host.now = Duration::from_secs(1_700_000_000));
+        Plugin::new("add_header_root", &json!({"stages": [stage]}).to_string())
+    }
+
+    fn send(
+        plugin: &mut Plugin,
+        headers: &[(&str, &str)],
+    ) -> Result<Option<String>, Vec<(String, String)>> {
+        let stream = plugin.stream();
+        stream.request_headers(headers, true);
+        if let Some(reply) = stream.local_response() {
+            assert_eq!(reply.status, 429);
+            return Err(reply.headers);
+        }
+        stream.response_headers(&[(":status", "200")], true);
+        Ok(stream.response_header("ratelimit-remaining"))
+    }
+
+    #[test]
+    fn buckets_refill_over_time() {
+        let mut plugin = plugin(json!({
+            "type": "local_rate_limit",
+            "max_tokens": 2,
+            "tokens_per_fill": 1,
+            "fill_interval_ms": 10_000
+        }));
+        let request = [(":path", "/"), (":method", "GET")];
+        assert_eq!(send(&mut plugin, &request).unwrap().as_deref(), Some("1"));
+        assert_eq!(send(&mut plugin, &request).unwrap().as_deref(), Some("0"));
+
+        with_host(|host| host.now += Duration::from_millis(2_500));
+        let headers = send(&mut plugin, &request).unwrap_err();
+        let header = |name: &str| {
+            headers
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.as_str())
+        };
+        assert_eq!(header("retry-after"), Some("8"));
+        assert_eq!(header("ratelimit-limit"), Some("2"));
+        assert_eq!(header("ratelimit-remaining"), Some("0"));
+        assert_eq!(header("ratelimit-reset"), Some("8"));
+
+        with_host(|host| host.now += Duration::from_millis(7_500));
+        assert_eq!(send(&mut plugin, &request).unwrap().as_deref(), Some("0"));
+        assert!(send(&mut plugin, &request).is_err());
+    }
+
+    #[test]
+    fn keys_have_separate_buckets() {
+        let mut plugin = plugin(json!({
+            "type": "local_rate_limit",
+            "key": ["client_ip", {"header": "x-tenant"}],
+            "max_tokens": 1
+        }));
+        let mut from = |address: &str, tenant: Option<&str>| {
+            with_host(|host| host.set_property(&["source", "address"], address.as_bytes()));
+            let mut headers = vec![(":path", "/")];
+            headers.extend(tenant.map(|tenant| ("x-tenant", tenant)));
+            send(&mut plugin, &headers).is_ok()
+        };
+        assert!(from("10.0.0.1:5000", Some("a")));
+        assert!(!from("10.0.0.1:5001", Some("a")));
+        assert!(from("10.0.0.1:5001", Some("b")));
+        assert!(from("[2001:db8::1]:443", Some("a")));
+        assert!(!from("[2001:db8::1]:444", Some("a")));
+        // Without a tenant the request has no bucket and is not limited.
+        assert!(from("10.0.0.1:5000", None));
+        assert!(from("10.0.0.1:5000", None));
+
+        let keys = with_host(|host| host.shared_data.len());
+        assert_eq!(keys, 3);
+    }
+
+    #[test]
+    fn ratelimit_root_takes_a_flat_stage() {
+        with_host(|host| host.now = Duration::from_secs(1_700_000_000));
+        let config = json!({"max_tokens": 1, "rate_limit_headers": false});
+        let mut plugin = Plugin::new("ratelimit_root", &config.to_string());
+        let request = [(":path", "/")];
+        assert_eq!(send(&mut plugin, &request), Ok(None));
+        assert!(send(&mut plugin, &request).is_err());
+    }
+
+    #[test]
+    fn keys_hash_into_a_bounded_number_of_buckets() {
+        let mut plugin = plugin(json!({
+            "type": "local_rate_limit",
+            "key": [{"header": "x-tenant"}],
+            "max_tokens": 100,
+            "slots": 4
+        }));
+        for tenant in 0..50 {
+            let tenant = tenant.to_string();
+            assert!(send(&mut plugin, &[(":path", "/"), ("x-tenant", &tenant)]).is_ok());
+        }
+        let keys = with_host(|host| host.shared_data.len());
+        assert!(keys <= 4, "{keys}");
+    }
+
+    #[test]
+    fn rejects_empty_buckets() {
+        let config = json!({"stages": [{"type": "local_rate_limit", "max_tokens": 0}]});
+        let (_, accepted) = Plugin::try_new("add_header_root", &config.to_string());
+        assert!(!accepted);
+        assert!(with_host(
+            |host| host.logged("`stages[0].max_tokens`: must be positive")
+        ));
+    }
+}
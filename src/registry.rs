@@ -0,0 +1,59 @@
+//! Maps the `rootID` of an EnvoyExtensionPolicy entry to the filter it runs, so one
+//! module can back several policies.
+
+use crate::config::ConfigError;
+use crate::pipeline;
+use proxy_wasm::traits::HttpContext;
+use std::rc::Rc;
+use std::time::Duration;
+
+/// Creates the per-request filters of a configured root context, and does the
+/// background work of the root, such as fetching key sets.
+pub trait FilterFactory {
+    fn create_filter(&self, context_id: u32) -> Box<dyn HttpContext>;
+
+    /// How often the root should call [`FilterFactory::on_tick`], if at all.
+    fn tick_period(&self) -> Option<Duration> {
+        None
+    }
+
+    fn on_tick(&self) {}
+
+    /// Delivers the response to an HTTP call dispatched from the root context.
+    fn on_http_call_response(&self, _token_id: u32, _num_headers: usize, _body_size: usize) {}
+}
+
+/// Parses a plugin configuration into the factory for one filter kind.
+pub type Configure = fn(&[u8]) -> Result<Rc<dyn FilterFactory>, ConfigError>;
+
+pub struct FilterKind {
+    pub root_id: &'static str,
+    pub configure: Configure,
+}
+
+pub static FILTERS: &[FilterKind] = &[
+    FilterKind {
+        root_id: "add_header_root",
+        configure: pipeline::configure,
+    },
+    FilterKind {
+        root_id: "auth_root",
+        configure: pipeline::configure_auth,
+    },
+    FilterKind {
+        root_id: "ratelimit_root",
+        configure: pipeline::configure_ratelimit,
+    },
+];
+
+/// Root id used when the policy leaves `rootID` unset.
+const DEFAULT_ROOT_ID: &str = "add_header_root";
+
+pub fn lookup(root_id: &str) -> Option<&'static FilterKind> {
+    let root_id = if root_id.is_empty() {
+        DEFAULT_ROOT_ID
+    } else {
+        root_id
+    };
+    FILTERS.iter().find(|kind| kind.root_id == root_id)
+}
@@ -0,0 +1,357 @@
+//! JSON Web Key Sets fetched from an Envoy cluster.
+//!
+//! The root context fetches the set with `dispatch_http_call` on its timer and stores it
+//! in shared data, where every worker VM reads it. A stream that meets an unknown `kid`
+//! refetches the set itself, at most once per `min_refresh_interval_seconds`.
+
+use crate::config::{field, ConfigError};
+use crate::jwt::Jwks;
+use log::warn;
+use proxy_wasm::hostcalls;
+use proxy_wasm::types::{BufferType, MapType};
+use serde::Deserialize;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RemoteJwks {
+    /// Envoy cluster serving the key set.
+    pub cluster: String,
+    pub path: String,
+    /// `:authority` of the request, the cluster name by default.
+    pub authority: Option<String>,
+    #[serde(default = "default_refresh_interval")]
+    pub refresh_interval_seconds: u64,
+    /// Lower bound between fetches caused by unknown `kid`s or failed fetches.
+    #[serde(default = "default_min_refresh_interval")]
+    pub min_refresh_interval_seconds: u64,
+    #[serde(default = "default_timeout")]
+    pub timeout_ms: u64,
+    #[serde(skip)]
+    state: RefCell<FetchState>,
+}
+
+/// What this VM knows about the fetches of one key set.
+#[derive(Debug, Default)]
+struct FetchState {
+    /// Token of the fetch the root context is waiting for.
+    pending: Option<u32>,
+    last_attempt: Option<SystemTime>,
+    /// The key set last parsed from shared data, with the CAS it was read at.
+    cached: Option<(u32, Rc<Jwks>)>,
+}
+
+fn default_refresh_interval() -> u64 {
+    300
+}
+
+fn default_min_refresh_interval() -> u64 {
+    30
+}
+
+fn default_timeout() -> u64 {
+    5000
+}
+
+impl RemoteJwks {
+    pub fn validate(&self, parent: &str) -> Result<(), ConfigError> {
+        if self.cluster.is_empty() {
+            return Err(ConfigError::new(
+                field(parent, "cluster"),
+                "cluster must not be empty",
+            ));
+        }
+        if !self.path.starts_with('/') {
+            return Err(ConfigError::new(
+                field(parent, "path"),
+                format!("`{}` must start with `/`", self.path),
+            ));
+        }
+        for (name, value) in [
+            ("refresh_interval_seconds", self.refresh_interval_seconds),
+            (
+                "min_refresh_interval_seconds",
+                self.min_refresh_interval_seconds,
+            ),
+            ("timeout_ms", self.timeout_ms),
+        ] {
+            if value == 0 {
+                return Err(ConfigError::new(field(parent, name), "must be positive"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Shared data key under which the fetched set is stored.
+    fn key(&self) -> String {
+        format!("wasmup.jwks.{}{}", self.cluster, self.path)
+    }
+
+    /// The most recently fetched key set, if any.
+    pub fn keys(&self) -> Option<Rc<Jwks>> {
+        let (fetched, cas) = hostcalls::get_shared_data(&self.key()).unwrap();
+        let (fetched, cas) = (fetched?, cas?);
+        let mut state = self.state.borrow_mut();
+        if let Some((cached_cas, jwks)) = &state.cached {
+            if *cached_cas == cas {
+                return Some(Rc::clone(jwks));
+            }
+        }
+        let jwks = Rc::new(Jwks::parse_fetched(fetched.get(8..)?).ok()?);
+        state.cached = Some((cas, Rc::clone(&jwks)));
+        Some(jwks)
+    }
+
+    /// Fetches the set from the root context when the refresh interval has passed, here or
+    /// in another VM.
+    pub fn on_tick(&self) {
+        let refresh = Duration::from_secs(self.refresh_interval_seconds);
+        if self.state.borrow().pending.is_none() && self.due(refresh) {
+            self.state.borrow_mut().pending = self.fetch();
+        }
+    }
+
+    /// Handles the response to a fetch of the root context. Returns false if `token_id`
+    /// belongs to another call.
+    pub fn on_root_response(&self, token_id: u32, body_size: usize) -> bool {
+        if self.state.borrow().pending != Some(token_id) {
+            return false;
+        }
+        self.state.borrow_mut().pending = None;
+        self.store(body_size);
+        true
+    }
+
+    /// Starts a fetch from a stream that met an unknown key, unless the set was fetched or
+    /// attempted too recently. Returns the token of the call.
+    pub fn refresh(&self) -> Option<u32> {
+        if !self.due(Duration::from_secs(self.min_refresh_interval_seconds)) {
+            return None;
+        }
+        self.fetch()
+    }
+
+    /// Stores the response to a fetch in shared data, keeping the previous set if the
+    /// response carries no usable keys.
+    pub fn store(&self, body_size: usize) {
+        let status = hostcalls::get_map_value(MapType::HttpCallResponseHeaders, ":status")
+            .unwrap()
+            .unwrap_or_default();
+        let body = hostcalls::get_buffer(BufferType::HttpCallResponseBody, 0, body_size)
+            .unwrap()
+            .unwrap_or_default();
+        if status != "200" {
+            let status = if status.is_empty() {
+                "no response"
+            } else {
+                &status
+            };
+            warn!("fetching JWKS from {}: {status}", self.cluster);
+            return;
+        }
+        if let Err(err) = Jwks::parse_fetched(&body) {
+            warn!("fetching JWKS from {}: {err}", self.cluster);
+            return;
+        }
+        let mut value = millis(now()).to_le_bytes().to_vec();
+        value.extend_from_slice(&body);
+        hostcalls::set_shared_data(&self.key(), Some(&value), None).unwrap();
+    }
+
+    fn fetch(&self) -> Option<u32> {
+        self.state.borrow_mut().last_attempt = Some(now());
+        let authority = self.authority.as_deref().unwrap_or(&self.cluster);
+        let headers = vec![
+            (":method", "GET"),
+            (":path", self.path.as_str()),
+            (":authority", authority),
+        ];
+        let timeout = Duration::from_millis(self.timeout_ms);
+        match hostcalls::dispatch_http_call(&self.cluster, headers, None, vec![], timeout) {
+            Ok(token) => Some(token),
+            Err(status) => {
+                warn!("fetching JWKS from {}: {status:?}", self.cluster);
+                None
+            }
+        }
+    }
+
+    /// Whether `interval` has passed since the last successful fetch in any VM, and the
+    /// minimum interval since this VM's last attempt.
+    fn due(&self, interval: Duration) -> bool {
+        let now = now();
+        let min = Duration::from_secs(self.min_refresh_interval_seconds);
+        let fetched = hostcalls::get_shared_data(&self.key())
+            .unwrap()
+            .0
+            .and_then(|value| Some(u64::from_le_bytes(value.get(..8)?.try_into().ok()?)));
+        let elapsed = |since: SystemTime| now.duration_since(since).unwrap_or_default();
+        fetched.is_none_or(|at| millis(now).saturating_sub(at) >= interval.as_millis() as u64)
+            && self
+                .state
+                .borrow()
+                .last_attempt
+                .is_none_or(|at| elapsed(at) >= min)
+    }
+}
+
+fn now() -> SystemTime {
+    hostcalls::get_current_time().unwrap()
+}
+
+fn millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mock::{with_host, Plugin};
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine as _;
+    use hmac::{Hmac, Mac};
+    use proxy_wasm::types::Action;
+    use serde_json::json;
+    use sha2::Sha256;
+    use std::time::Duration;
+
+    const CONFIG: &str = r#"{"remote_jwks": {"cluster": "idp", "path": "/jwks"}}"#;
+
+    fn secret(kid: &str) -> Vec<u8> {
+        format!("secret-for-{kid}").into_bytes()
+    }
+
+    fn jwks(kids: &[&str]) -> Vec<u8> {
+        let keys: Vec<_> = kids
+            .iter()
+            .map(|kid| json!({"kty": "oct", "kid": kid, "k": URL_SAFE_NO_PAD.encode(secret(kid))}))
+            .chain([json!({"kty": "RSA", "use": "enc", "n": "", "e": ""})])
+            .collect();
+        json!({ "keys": keys }).to_string().into_bytes()
+    }
+
+    fn token(kid: &str) -> String {
+        let encode = |value: serde_json::Value| URL_SAFE_NO_PAD.encode(value.to_string());
+        let signed = format!(
+            "{}.{}",
+            encode(json!({"alg": "HS256", "kid": kid})),
+            encode(json!({"sub": "alice"}))
+        );
+        let mut mac = Hmac::<Sha256>::new_from_slice(&secret(kid)).unwrap();
+        mac.update(signed.as_bytes());
+        format!(
+            "{signed}.{}",
+            URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes())
+        )
+    }
+
+    fn request(kid: &str) -> [(&'static str, String); 2] {
+        [
+            (":path", "/".to_string()),
+            ("authorization", format!("Bearer {}", token(kid))),
+        ]
+    }
+
+    fn send(plugin: &mut Plugin, kid: &str) -> (crate::mock::Stream, Action) {
+        let stream = plugin.stream();
+        let headers = request(kid);
+        let headers: Vec<_> = headers.iter().map(|(k, v)| (*k, v.as_str())).collect();
+        let action = stream.request_headers(&headers, true);
+        (stream, action)
+    }
+
+    fn advance(seconds: u64) {
+        with_host(|host| host.now += Duration::from_secs(seconds));
+    }
+
+    /// Starts the plugin and completes the root's first fetch with `kids`.
+    fn fetched(kids: &[&str]) -> Plugin {
+        with_host(|host| host.now = Duration::from_secs(1_700_000_000));
+        let plugin = Plugin::new("auth_root", CONFIG);
+        plugin.tick();
+        let token = with_host(|host| host.http_calls.last().unwrap().token);
+        plugin.http_call_response(token, &[(":status", "200")], &jwks(kids));
+        plugin
+    }
+
+    #[test]
+    fn root_fetches_on_its_timer() {
+        let mut plugin = fetched(&["k1"]);
+        let call = with_host(|host| {
+            assert_eq!(host.tick_period, Some(Duration::from_secs(1)));
+            assert!(host.shared_data.contains_key("wasmup.jwks.idp/jwks"));
+            host.http_calls[0].clone()
+        });
+        assert_eq!(call.upstream, "idp");
+        assert!(call
+            .headers
+            .contains(&(":path".to_string(), "/jwks".to_string())));
+        assert!(call
+            .headers
+            .contains(&(":authority".to_string(), "idp".to_string())));
+
+        let (stream, action) = send(&mut plugin, "k1");
+        assert_eq!(action, Action::Continue);
+        assert!(stream.local_response().is_none());
+
+        plugin.tick();
+        advance(299);
+        plugin.tick();
+        assert_eq!(with_host(|host| host.http_calls.len()), 1);
+        advance(1);
+        plugin.tick();
+        assert_eq!(with_host(|host| host.http_calls.len()), 2);
+    }
+
+    #[test]
+    fn unknown_kid_refetches_the_set() {
+        let mut plugin = fetched(&["k1"]);
+        advance(60);
+
+        let (stream, action) = send(&mut plugin, "k2");
+        assert_eq!(action, Action::Pause);
+        let call = with_host(|host| host.http_calls.last().unwrap().clone());
+        assert_eq!(call.context_id, stream.id);
+        stream.http_call_response(call.token, &[(":status", "200")], &jwks(&["k1", "k2"]));
+        assert!(stream.data(|data| data.request_resumed));
+        assert!(stream.local_response().is_none());
+
+        // Right after a fetch, an unknown key is rejected without fetching again.
+        let (stream, action) = send(&mut plugin, "k3");
+        assert_eq!(action, Action::Pause);
+        assert_eq!(stream.local_response().unwrap().status, 401);
+        assert_eq!(with_host(|host| host.http_calls.len()), 2);
+    }
+
+    #[test]
+    fn failed_fetch_keeps_previous_keys() {
+        let mut plugin = fetched(&["k1"]);
+        advance(300);
+        plugin.tick();
+        let token = with_host(|host| host.http_calls.last().unwrap().token);
+        plugin.http_call_response(token, &[(":status", "503")], b"unavailable");
+        assert!(with_host(|host| host.logged("fetching JWKS from idp: 503")));
+
+        let (_, action) = send(&mut plugin, "k1");
+        assert_eq!(action, Action::Continue);
+    }
+
+    #[test]
+    fn rejects_intervals_of_zero() {
+        for name in [
+            "refresh_interval_seconds",
+            "min_refresh_interval_seconds",
+            "timeout_ms",
+        ] {
+            let config = json!({"remote_jwks": {"cluster": "idp", "path": "/jwks", name: 0}});
+            let (_, accepted) = Plugin::try_new("auth_root", &config.to_string());
+            assert!(!accepted, "{name}");
+            assert!(with_host(
+                |host| host.logged(&format!("{name}`: must be positive"))
+            ));
+        }
+    }
+}
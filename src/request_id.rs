@@ -0,0 +1,199 @@
+//! Request ids, to correlate the logs of every hop a request went through.
+//!
+//! An id the client or an earlier proxy sent is kept; otherwise the stage creates one
+//! from the host clock and WASI random bytes.
+
+use crate::access_log::RequestLog;
+use crate::config::{field, validate_header_name, ConfigError};
+use crate::pipeline::{Flow, LocalReply, Stage};
+use crate::util::{now_millis, random_id};
+use proxy_wasm::hostcalls;
+use proxy_wasm::types::MapType;
+use serde::Deserialize;
+use std::rc::Rc;
+
+/// Crockford's base32 alphabet, which ULIDs are written in.
+const CROCKFORD: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+/// Configuration of the `request_id` stage.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RequestIdConfig {
+    #[serde(default)]
+    pub format: IdFormat,
+    /// Request header that carries the id upstream.
+    #[serde(default = "default_header")]
+    pub header: String,
+    /// Response header that receives the id, or `null` for none.
+    #[serde(default = "default_response_header")]
+    pub response_header: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IdFormat {
+    #[default]
+    UuidV4,
+    /// Time-ordered UUID, whose first 48 bits are the Unix time in milliseconds.
+    UuidV7,
+    /// Time-ordered 26-character id in Crockford's base32.
+    Ulid,
+}
+
+fn default_header() -> String {
+    "x-request-id".to_string()
+}
+
+fn default_response_header() -> Option<String> {
+    Some(default_header())
+}
+
+impl RequestIdConfig {
+    pub fn validate(&self, parent: &str) -> Result<(), ConfigError> {
+        validate_header_name(&field(parent, "header"), &self.header)?;
+        if let Some(header) = &self.response_header {
+            validate_header_name(&field(parent, "response_header"), header)?;
+        }
+        Ok(())
+    }
+}
+
+impl IdFormat {
+    fn generate(self, now_ms: u64) -> String {
+        let mut bytes: [u8; 16] = random_id();
+        match self {
+            IdFormat::UuidV4 => uuid(bytes, 4),
+            IdFormat::UuidV7 => {
+                bytes[..6].copy_from_slice(&now_ms.to_be_bytes()[2..]);
+                uuid(bytes, 7)
+            }
+            IdFormat::Ulid => {
+                bytes[..6].copy_from_slice(&now_ms.to_be_bytes()[2..]);
+                let value = u128::from_be_bytes(bytes);
+                // 26 characters of 5 bits hold the 128 bits with 2 to spare at the top.
+                (0..26)
+                    .map(|i| CROCKFORD[(value >> (125 - 5 * i)) as usize & 31] as char)
+                    .collect()
+            }
+        }
+    }
+}
+
+/// Formats `bytes` as a UUID of the given version, with the RFC 9562 variant.
+fn uuid(mut bytes: [u8; 16], version: u8) -> String {
+    bytes[6] = bytes[6] & 0x0f | version << 4;
+    bytes[8] = bytes[8] & 0x3f | 0x80;
+    let hex = hex::encode(bytes);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// Makes sure the request has an id and hands it upstream, downstream and to the log.
+pub struct RequestIdStage {
+    config: Rc<RequestIdConfig>,
+    id: Option<String>,
+}
+
+impl RequestIdStage {
+    pub fn new(config: Rc<RequestIdConfig>) -> RequestIdStage {
+        RequestIdStage { config, id: None }
+    }
+}
+
+impl Stage for RequestIdStage {
+    fn on_request_headers(&mut self, _num_headers: usize, _end_of_stream: bool) -> Flow {
+        let header = &self.config.header;
+        let id = match hostcalls::get_map_value(MapType::HttpRequestHeaders, header).unwrap() {
+            Some(id) if !id.is_empty() => id,
+            _ => {
+                let id = self.config.format.generate(now_millis());
+                hostcalls::set_map_value(MapType::HttpRequestHeaders, header, Some(&id)).unwrap();
+                id
+            }
+        };
+        self.id = Some(id);
+        Flow::Continue
+    }
+
+    fn on_response_headers(&mut self, _num_headers: usize, _end_of_stream: bool) -> Flow {
+        if let (Some(header), Some(id)) = (&self.config.response_header, &self.id) {
+            hostcalls::set_map_value(MapType::HttpResponseHeaders, header, Some(id)).unwrap();
+        }
+        Flow::Continue
+    }
+
+    fn on_local_reply(&mut self, reply: &mut LocalReply) {
+        if let (Some(header), Some(id)) = (&self.config.response_header, &self.id) {
+            reply.headers.push((header.clone(), id.clone()));
+        }
+    }
+
+    fn on_access_log(&self, request: &mut RequestLog) {
+        request.request_id.clone_from(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mock::{with_host, Plugin};
+    use serde_json::{json, Value};
+    use std::time::Duration;
+
+    #[test]
+    fn creates_an_id_and_correlates_the_reply_and_log() {
+        with_host(|host| host.now = Duration::from_millis(0x0189_5d4c_3a00));
+        let config = json!({
+            "access_log": {"fields": ["status"]},
+            "stages": [
+                {"type": "request_id", "format": "uuid_v7"},
+                {"type": "basic_auth", "htpasswd": "alice:{SHA}5en6G6MezRroT3XKqkdPOmY/BfQ="}
+            ]
+        });
+        let mut plugin = Plugin::new("add_header_root", &config.to_string());
+        let stream = plugin.stream();
+        stream.request_headers(&[(":path", "/")], true);
+        stream.log();
+
+        let id = stream.request_header("x-request-id").unwrap();
+        assert_eq!(id.len(), 36);
+        assert!(id.starts_with("01895d4c-3a00-7"), "{id}");
+        assert!(matches!(&id[19..20], "8" | "9" | "a" | "b"), "{id}");
+        let reply = stream.local_response().unwrap();
+        assert!(reply
+            .headers
+            .contains(&("x-request-id".to_string(), id.clone())));
+        let line: Value =
+            with_host(|host| serde_json::from_str(&host.logs.last().unwrap().1)).unwrap();
+        assert_eq!(line, json!({"status": 401, "request_id": id}));
+    }
+
+    #[test]
+    fn keeps_incoming_ids_and_writes_ulids() {
+        with_host(|host| host.now = Duration::from_millis(1_469_918_176_385));
+        let config = json!({"stages": [{
+            "type": "request_id",
+            "format": "ulid",
+            "header": "x-correlation-id",
+            "response_header": null
+        }]});
+        let mut plugin = Plugin::new("add_header_root", &config.to_string());
+        let stream = plugin.stream();
+        stream.request_headers(&[(":path", "/"), ("x-correlation-id", "abc")], true);
+        assert_eq!(stream.request_header("x-correlation-id").unwrap(), "abc");
+
+        let stream = plugin.stream();
+        stream.request_headers(&[(":path", "/")], true);
+        stream.response_headers(&[(":status", "200")], true);
+        let id = stream.request_header("x-correlation-id").unwrap();
+        // The example timestamp of the ULID specification.
+        assert!(id.starts_with("01ARYZ6S41"), "{id}");
+        assert_eq!(id.len(), 26);
+        assert_eq!(stream.response_header("x-correlation-id"), None);
+    }
+}
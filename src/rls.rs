@@ -0,0 +1,519 @@
+//! Global rate limiting with an Envoy Rate Limit Service.
+//!
+//! Each request is described by `descriptors` and checked with the service's
+//! `ShouldRateLimit` method over gRPC, so every gateway replica shares the same limits.
+//! When the service cannot be reached, the local `fallback` limiter decides instead.
+
+use crate::config::{field, ConfigError};
+use crate::matcher::RequestInfo;
+use crate::pipeline::{Flow, LocalReply, Stage};
+use crate::protobuf::{self, DecodeError, Encoder};
+use crate::ratelimit::{Decision, KeyPart, LocalRateLimitConfig};
+use log::warn;
+use proxy_wasm::hostcalls;
+use proxy_wasm::types::{BufferType, MapType};
+use serde::Deserialize;
+use std::rc::Rc;
+use std::time::Duration;
+
+/// Configuration of the `global_rate_limit` stage.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GlobalRateLimitConfig {
+    /// Envoy cluster of the rate limit service.
+    pub cluster: String,
+    pub domain: String,
+    pub descriptors: Vec<Descriptor>,
+    #[serde(default = "default_timeout")]
+    pub timeout_ms: u64,
+    /// Local limiter used while the service is unreachable.
+    pub fallback: Option<LocalRateLimitConfig>,
+    /// Whether requests go upstream when the service is unreachable and there is no
+    /// fallback.
+    #[serde(default = "default_fail_open")]
+    pub fail_open: bool,
+    /// Whether allowed responses report the tightest limit in `RateLimit-*` headers.
+    #[serde(default = "default_rate_limit_headers")]
+    pub rate_limit_headers: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Descriptor {
+    pub entries: Vec<DescriptorEntry>,
+}
+
+/// A descriptor entry, with either a fixed `value` or one taken `from` the request.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DescriptorEntry {
+    pub key: String,
+    pub value: Option<String>,
+    pub from: Option<KeyPart>,
+}
+
+fn default_timeout() -> u64 {
+    20
+}
+
+fn default_fail_open() -> bool {
+    true
+}
+
+fn default_rate_limit_headers() -> bool {
+    true
+}
+
+impl GlobalRateLimitConfig {
+    pub fn validate(&self, parent: &str) -> Result<(), ConfigError> {
+        for (name, value) in [("cluster", &self.cluster), ("domain", &self.domain)] {
+            if value.is_empty() {
+                return Err(ConfigError::new(
+                    field(parent, name),
+                    format!("{name} must not be empty"),
+                ));
+            }
+        }
+        if self.descriptors.is_empty() {
+            return Err(ConfigError::new(
+                field(parent, "descriptors"),
+                "at least one descriptor is required",
+            ));
+        }
+        for (i, descriptor) in self.descriptors.iter().enumerate() {
+            let descriptor_field = field(parent, &format!("descriptors[{i}]"));
+            if descriptor.entries.is_empty() {
+                return Err(ConfigError::new(
+                    field(&descriptor_field, "entries"),
+                    "at least one entry is required",
+                ));
+            }
+            for (j, entry) in descriptor.entries.iter().enumerate() {
+                let entry_field = field(&descriptor_field, &format!("entries[{j}]"));
+                if entry.key.is_empty() {
+                    return Err(ConfigError::new(
+                        field(&entry_field, "key"),
+                        "key must not be empty",
+                    ));
+                }
+                match (&entry.value, &entry.from) {
+                    (Some(_), None) => {}
+                    (None, Some(part)) => part.validate(&field(&entry_field, "from.header"))?,
+                    _ => {
+                        return Err(ConfigError::new(
+                            entry_field,
+                            "exactly one of `value` and `from` is required",
+                        ))
+                    }
+                }
+            }
+        }
+        if self.timeout_ms == 0 {
+            return Err(ConfigError::new(
+                field(parent, "timeout_ms"),
+                "must be positive",
+            ));
+        }
+        if let Some(fallback) = &self.fallback {
+            fallback.validate(&field(parent, "fallback"))?;
+        }
+        Ok(())
+    }
+
+    /// Encodes the `RateLimitRequest` for `request`. Like Envoy, descriptors with an entry
+    /// the request lacks are left out; `None` if none is left.
+    fn request(&self, request: &RequestInfo) -> Option<Vec<u8>> {
+        let descriptors: Vec<Vec<(&str, String)>> = self
+            .descriptors
+            .iter()
+            .filter_map(|descriptor| {
+                descriptor
+                    .entries
+                    .iter()
+                    .map(|entry| {
+                        let value = match (&entry.value, &entry.from) {
+                            (Some(value), _) => Some(value.clone()),
+                            (None, Some(part)) => part.value(request),
+                            (None, None) => None,
+                        };
+                        Some((entry.key.as_str(), value?))
+                    })
+                    .collect()
+            })
+            .collect();
+        if descriptors.is_empty() {
+            return None;
+        }
+        let mut message = Encoder::new();
+        message.string(1, &self.domain);
+        for entries in &descriptors {
+            message.message(2, |descriptor| {
+                for (key, value) in entries {
+                    descriptor.message(1, |entry| {
+                        entry.string(1, key).string(2, value);
+                    });
+                }
+            });
+        }
+        Some(message.uint64(3, 1).finish())
+    }
+}
+
+/// The parts of a `RateLimitResponse` the filter acts on.
+#[derive(Debug, Default)]
+struct RateLimitResponse {
+    over_limit: bool,
+    /// The descriptor status with the least remaining.
+    tightest: Option<Decision>,
+    request_headers: Vec<(String, String)>,
+    response_headers: Vec<(String, String)>,
+}
+
+const OVER_LIMIT: u64 = 2;
+
+impl RateLimitResponse {
+    fn decode(message: &[u8]) -> Result<RateLimitResponse, DecodeError> {
+        let mut response = RateLimitResponse::default();
+        for field in protobuf::fields(message) {
+            match field? {
+                (1, code) => response.over_limit = code.as_u64()? == OVER_LIMIT,
+                (2, status) => {
+                    let status = descriptor_status(status)?;
+                    let tighter = |current: &Decision| {
+                        status.is_some_and(|s| s.remaining < current.remaining)
+                    };
+                    if response.tightest.as_ref().is_none_or(tighter) {
+                        response.tightest = status.or(response.tightest);
+                    }
+                }
+                (3, header) => response.response_headers.push(header_value(header)?),
+                (4, header) => response.request_headers.push(header_value(header)?),
+                _ => {}
+            }
+        }
+        if let Some(tightest) = &mut response.tightest {
+            tightest.allowed = !response.over_limit;
+        }
+        Ok(response)
+    }
+}
+
+/// Decodes a `DescriptorStatus`, or `None` if it carries no limit.
+fn descriptor_status(status: protobuf::Value) -> Result<Option<Decision>, DecodeError> {
+    let (mut limit, mut remaining, mut reset_ms) = (None, 0, 0);
+    for field in protobuf::fields(status.as_bytes()?) {
+        match field? {
+            // current_limit: RateLimit
+            (2, current) => {
+                for field in protobuf::fields(current.as_bytes()?) {
+                    if let (1, requests_per_unit) = field? {
+                        limit = Some(requests_per_unit.as_u64()?);
+                    }
+                }
+            }
+            (3, value) => remaining = value.as_u64()?,
+            // duration_until_reset: google.protobuf.Duration
+            (4, duration) => {
+                for field in protobuf::fields(duration.as_bytes()?) {
+                    match field? {
+                        (1, seconds) => reset_ms += seconds.as_u64()? * 1000,
+                        (2, nanos) => reset_ms += nanos.as_u64()? / 1_000_000,
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(limit.map(|limit| Decision {
+        allowed: true,
+        limit,
+        remaining,
+        reset_ms,
+    }))
+}
+
+/// Decodes a `config.core.v3.HeaderValue`.
+fn header_value(header: protobuf::Value) -> Result<(String, String), DecodeError> {
+    let (mut name, mut value) = (String::new(), String::new());
+    for field in protobuf::fields(header.as_bytes()?) {
+        match field? {
+            (1, key) => name = key.as_str()?.to_ascii_lowercase(),
+            (2, text) => value = text.as_str()?.to_string(),
+            (3, raw) => value = String::from_utf8_lossy(raw.as_bytes()?).into_owned(),
+            _ => {}
+        }
+    }
+    Ok((name, value))
+}
+
+/// Asks the rate limit service about each request.
+pub struct GlobalRateLimitStage {
+    config: Rc<GlobalRateLimitConfig>,
+    request: Option<RequestInfo>,
+    decision: Option<Decision>,
+    response_headers: Vec<(String, String)>,
+}
+
+impl GlobalRateLimitStage {
+    pub fn new(config: Rc<GlobalRateLimitConfig>) -> GlobalRateLimitStage {
+        GlobalRateLimitStage {
+            config,
+            request: None,
+            decision: None,
+            response_headers: Vec::new(),
+        }
+    }
+
+    /// Decides without the service, with the fallback limiter if there is one.
+    fn fail(&mut self, reason: &str) -> Flow {
+        warn!("rate limit service {}: {reason}", self.config.cluster);
+        match (&self.config.fallback, &self.request) {
+            (Some(fallback), Some(request)) => {
+                self.decision = fallback.take(request);
+                match self.decision {
+                    Some(decision) if !decision.allowed => Flow::Respond(decision.reply()),
+                    _ => Flow::Continue,
+                }
+            }
+            _ if self.config.fail_open => Flow::Continue,
+            _ => Flow::Respond(
+                LocalReply::new(503)
+                    .body("rate limit service unavailable")
+                    .reason("unavailable"),
+            ),
+        }
+    }
+}
+
+impl Stage for GlobalRateLimitStage {
+    fn on_request_headers(&mut self, _num_headers: usize, _end_of_stream: bool) -> Flow {
+        let request = RequestInfo::new(hostcalls::get_map(MapType::HttpRequestHeaders).unwrap());
+        let Some(message) = self.config.request(&request) else {
+            return Flow::Continue;
+        };
+        self.request = Some(request);
+        match hostcalls::dispatch_grpc_call(
+            &self.config.cluster,
+            "envoy.service.ratelimit.v3.RateLimitService",
+            "ShouldRateLimit",
+            vec![],
+            Some(&message),
+            Duration::from_millis(self.config.timeout_ms),
+        ) {
+            Ok(_) => Flow::Pause,
+            Err(status) => self.fail(&format!("{status:?}")),
+        }
+    }
+
+    fn on_grpc_call_response(
+        &mut self,
+        _token_id: u32,
+        status_code: u32,
+        response_size: usize,
+    ) -> Flow {
+        if status_code != 0 {
+            return self.fail(&format!("gRPC status {status_code}"));
+        }
+        let message = hostcalls::get_buffer(BufferType::GrpcReceiveBuffer, 0, response_size)
+            .unwrap()
+            .unwrap_or_default();
+        let response = match RateLimitResponse::decode(&message) {
+            Ok(response) => response,
+            Err(err) => return self.fail(&format!("RateLimitResponse: {err}")),
+        };
+        if response.over_limit {
+            let reply = match response.tightest {
+                Some(decision) => decision.reply(),
+                None => LocalReply::new(429)
+                    .body("rate limit exceeded")
+                    .reason("rate_limited"),
+            };
+            return Flow::Respond(
+                response
+                    .response_headers
+                    .into_iter()
+                    .fold(reply, |reply, (name, value)| reply.header(&name, value)),
+            );
+        }
+        for (name, value) in &response.request_headers {
+            hostcalls::set_map_value(MapType::HttpRequestHeaders, name, Some(value)).unwrap();
+        }
+        self.decision = response.tightest;
+        self.response_headers = response.response_headers;
+        Flow::Continue
+    }
+
+    fn on_response_headers(&mut self, _num_headers: usize, _end_of_stream: bool) -> Flow {
+        let limits = self
+            .decision
+            .filter(|_| self.config.rate_limit_headers)
+            .map(|decision| decision.headers())
+            .unwrap_or_default();
+        let limits = limits
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value));
+        for (name, value) in self.response_headers.iter().cloned().chain(limits) {
+            hostcalls::set_map_value(MapType::HttpResponseHeaders, &name, Some(&value)).unwrap();
+        }
+        Flow::Continue
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mock::{with_host, GrpcCall, Plugin, Stream};
+    use crate::protobuf::{self, Encoder, Value};
+    use proxy_wasm::types::Action;
+    use serde_json::json;
+    use std::time::Duration;
+
+    fn plugin() -> Plugin {
+        with_host(|host| host.now = Duration::from_secs(1_700_000_000));
+        let config = json!({"stages": [{
+            "type": "global_rate_limit",
+            "cluster": "ratelimit",
+            "domain": "gateway",
+            "descriptors": [
+                {"entries": [{"key": "tier", "value": "free"}, {"key": "user", "from": {"header": "x-user"}}]},
+                {"entries": [{"key": "path", "from": "path"}]}
+            ],
+            "fallback": {"max_tokens": 1, "fill_interval_ms": 60_000}
+        }]});
+        Plugin::new("add_header_root", &config.to_string())
+    }
+
+    fn send(plugin: &mut Plugin, headers: &[(&str, &str)]) -> (Stream, GrpcCall) {
+        let stream = plugin.stream();
+        assert_eq!(stream.request_headers(headers, true), Action::Pause);
+        let call = with_host(|host| host.grpc_calls.last().unwrap().clone());
+        (stream, call)
+    }
+
+    /// A `DescriptorStatus` with its limit, remaining hits and reset time.
+    fn status(encoder: &mut Encoder, code: u64, limit: u64, remaining: u64, reset: u64) {
+        encoder.message(2, |status| {
+            status
+                .uint64(1, code)
+                .message(2, |limit_message| {
+                    limit_message.uint64(1, limit).uint64(2, 2);
+                })
+                .uint64(3, remaining)
+                .message(4, |duration| {
+                    duration.uint64(1, reset);
+                });
+        });
+    }
+
+    fn header(encoder: &mut Encoder, field: u32, name: &str, value: &str) {
+        encoder.message(field, |header| {
+            header.string(1, name).string(2, value);
+        });
+    }
+
+    #[test]
+    fn sends_descriptors_and_applies_ok() {
+        let mut plugin = plugin();
+        let (stream, call) = send(
+            &mut plugin,
+            &[(":path", "/orders?id=1"), ("x-user", "alice")],
+        );
+        assert_eq!(call.upstream, "ratelimit");
+        assert_eq!(call.service, "envoy.service.ratelimit.v3.RateLimitService");
+        assert_eq!(call.method, "ShouldRateLimit");
+        assert_eq!(call.timeout, Duration::from_millis(20));
+        let entry = |key: &str, value: &str| {
+            Encoder::new()
+                .message(1, |entry| {
+                    entry.string(1, key).string(2, value);
+                })
+                .finish()
+        };
+        let fields: Vec<_> = protobuf::fields(&call.message)
+            .map(Result::unwrap)
+            .collect();
+        let first = [entry("tier", "free"), entry("user", "alice")].concat();
+        assert_eq!(
+            fields,
+            [
+                (1, Value::Bytes(b"gateway")),
+                (2, Value::Bytes(&first)),
+                (2, Value::Bytes(&entry("path", "/orders"))),
+                (3, Value::Varint(1)),
+            ]
+        );
+
+        let mut response = Encoder::new();
+        response.uint64(1, 1);
+        status(&mut response, 1, 100, 42, 30);
+        status(&mut response, 1, 10, 7, 5);
+        header(&mut response, 4, "x-ratelimit-tier", "free");
+        header(&mut response, 3, "x-served-by", "rls");
+        stream.grpc_call_response(call.token, 0, &response.finish());
+        assert!(stream.data(|data| data.request_resumed));
+        assert_eq!(
+            stream.request_header("x-ratelimit-tier").as_deref(),
+            Some("free")
+        );
+
+        stream.response_headers(&[(":status", "200")], true);
+        assert_eq!(
+            stream.response_header("x-served-by").as_deref(),
+            Some("rls")
+        );
+        assert_eq!(
+            stream.response_header("ratelimit-limit").as_deref(),
+            Some("10")
+        );
+        assert_eq!(
+            stream.response_header("ratelimit-remaining").as_deref(),
+            Some("7")
+        );
+        assert_eq!(
+            stream.response_header("ratelimit-reset").as_deref(),
+            Some("5")
+        );
+    }
+
+    #[test]
+    fn over_limit_gets_429() {
+        let mut plugin = plugin();
+        let (stream, call) = send(&mut plugin, &[(":path", "/orders")]);
+        // Without `x-user`, only the path descriptor is sent.
+        assert_eq!(protobuf::fields(&call.message).count(), 3);
+
+        let mut response = Encoder::new();
+        response.uint64(1, 2);
+        status(&mut response, 2, 10, 0, 12);
+        header(&mut response, 3, "x-served-by", "rls");
+        stream.grpc_call_response(call.token, 0, &response.finish());
+        let reply = stream.local_response().unwrap();
+        assert_eq!(reply.status, 429);
+        let reply_header = |name: &str| {
+            reply
+                .headers
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.as_str())
+        };
+        assert_eq!(reply_header("retry-after"), Some("12"));
+        assert_eq!(reply_header("ratelimit-remaining"), Some("0"));
+        assert_eq!(reply_header("x-served-by"), Some("rls"));
+    }
+
+    #[test]
+    fn falls_back_to_the_local_limiter() {
+        let mut plugin = plugin();
+        // UNAVAILABLE
+        let (stream, call) = send(&mut plugin, &[(":path", "/")]);
+        stream.grpc_call_response(call.token, 14, b"");
+        assert!(stream.data(|data| data.request_resumed));
+        assert!(with_host(
+            |host| host.logged("rate limit service ratelimit: gRPC status 14")
+        ));
+
+        let (stream, call) = send(&mut plugin, &[(":path", "/")]);
+        stream.grpc_call_response(call.token, 14, b"");
+        assert_eq!(stream.local_response().unwrap().status, 429);
+    }
+}
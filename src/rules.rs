@@ -0,0 +1,141 @@
+use crate::config::{validate_header_name, validate_header_value, ConfigError};
+use crate::template::Template;
+use proxy_wasm::hostcalls;
+use proxy_wasm::types::MapType;
+use serde::Deserialize;
+
+/// A single header operation, applied in order with the other rules of its phase.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase", deny_unknown_fields)]
+pub enum HeaderRule {
+    /// Adds another value, keeping any existing ones.
+    Add {
+        name: String,
+        value: Template,
+    },
+    /// Replaces all existing values.
+    Set {
+        name: String,
+        value: Template,
+    },
+    /// Appends to the existing value, or sets it when the header is absent.
+    Append {
+        name: String,
+        value: Template,
+        #[serde(default = "default_separator")]
+        separator: String,
+    },
+    Remove {
+        name: String,
+    },
+    /// Moves every value of `name` to `to`, replacing whatever `to` held.
+    Rename {
+        name: String,
+        to: String,
+    },
+}
+
+fn default_separator() -> String {
+    ", ".to_string()
+}
+
+impl HeaderRule {
+    pub fn validate(&self, field: &str) -> Result<(), ConfigError> {
+        match self {
+            HeaderRule::Add { name, value } | HeaderRule::Set { name, value } => {
+                validate_header_name(&format!("{field}.name"), name)?;
+                value.validate(&format!("{field}.value"))
+            }
+            HeaderRule::Append {
+                name,
+                value,
+                separator,
+            } => {
+                validate_header_name(&format!("{field}.name"), name)?;
+                value.validate(&format!("{field}.value"))?;
+                validate_header_value(&format!("{field}.separator"), separator)
+            }
+            HeaderRule::Remove { name } => validate_header_name(&format!("{field}.name"), name),
+            HeaderRule::Rename { name, to } => {
+                validate_header_name(&format!("{field}.name"), name)?;
+                validate_header_name(&format!("{field}.to"), to)
+            }
+        }
+    }
+}
+
+pub fn validate_rules(field: &str, rules: &[HeaderRule]) -> Result<(), ConfigError> {
+    rules
+        .iter()
+        .enumerate()
+        .try_for_each(|(i, rule)| rule.validate(&format!("{field}[{i}]")))
+}
+
+/// Which header map a set of rules operates on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Request,
+    Response,
+}
+
+impl Phase {
+    fn map_type(self) -> MapType {
+        match self {
+            Phase::Request => MapType::HttpRequestHeaders,
+            Phase::Response => MapType::HttpResponseHeaders,
+        }
+    }
+}
+
+/// Applies `rules` in order and returns how many header values they set or added.
+pub fn apply(rules: &[HeaderRule], phase: Phase) -> usize {
+    let map = phase.map_type();
+    let mut written = 0;
+    for rule in rules {
+        match rule {
+            HeaderRule::Add { name, value } => {
+                hostcalls::add_map_value(map, name, &value.render()).unwrap();
+                written += 1;
+            }
+            HeaderRule::Set { name, value } => {
+                hostcalls::set_map_value(map, name, Some(&value.render())).unwrap();
+                written += 1;
+            }
+            HeaderRule::Append {
+                name,
+                value,
+                separator,
+            } => {
+                let value = value.render();
+                let joined = match hostcalls::get_map_value(map, name).unwrap() {
+                    Some(existing) if !existing.is_empty() => {
+                        format!("{existing}{separator}{value}")
+                    }
+                    _ => value,
+                };
+                hostcalls::set_map_value(map, name, Some(&joined)).unwrap();
+                written += 1;
+            }
+            HeaderRule::Remove { name } => {
+                hostcalls::remove_map_value(map, name).unwrap();
+            }
+            HeaderRule::Rename { name, to } => {
+                let values: Vec<String> = hostcalls::get_map(map)
+                    .unwrap()
+                    .into_iter()
+                    .filter(|(key, _)| key.eq_ignore_ascii_case(name))
+                    .map(|(_, value)| value)
+                    .collect();
+                if values.is_empty() {
+                    continue;
+                }
+                hostcalls::remove_map_value(map, name).unwrap();
+                hostcalls::remove_map_value(map, to).unwrap();
+                for value in &values {
+                    hostcalls::add_map_value(map, to, value).unwrap();
+                }
+            }
+        }
+    }
+    written
+}
@@ -0,0 +1,343 @@
+//! HMAC-SHA256 request signatures, as sent by webhook senders such as GitHub and Stripe.
+//!
+//! The sender signs `{timestamp}.{body}` with the secret of its tenant and sends the hex
+//! digest, optionally prefixed with `sha256=`, next to the timestamp in unix seconds.
+
+use crate::config::{field, validate_header_name, ConfigError};
+use crate::pipeline::{Flow, LocalReply, Stage};
+use hmac::{Hmac, Mac};
+use proxy_wasm::hostcalls;
+use proxy_wasm::types::{BufferType, MapType};
+use serde::Deserialize;
+use sha2::Sha256;
+use std::rc::Rc;
+use std::time::UNIX_EPOCH;
+
+/// Configuration of the `hmac_signature` stage.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SignatureConfig {
+    pub tenants: Vec<Tenant>,
+    /// Request header naming the tenant whose secrets signed the request.
+    #[serde(default = "default_tenant_header")]
+    pub tenant_header: String,
+    #[serde(default = "default_signature_header")]
+    pub signature_header: String,
+    #[serde(default = "default_timestamp_header")]
+    pub timestamp_header: String,
+    /// How far the timestamp may be from the current time, in either direction.
+    #[serde(default = "default_replay_window")]
+    pub replay_window_seconds: u64,
+    /// Largest body that is buffered for verification; larger requests get a 413.
+    #[serde(default = "default_max_body_bytes")]
+    pub max_body_bytes: usize,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Tenant {
+    pub id: String,
+    /// Secrets accepted for the tenant; list the old and new secret while rotating.
+    pub secrets: Vec<String>,
+}
+
+fn default_tenant_header() -> String {
+    "x-tenant-id".to_string()
+}
+
+fn default_signature_header() -> String {
+    "x-signature".to_string()
+}
+
+fn default_timestamp_header() -> String {
+    "x-timestamp".to_string()
+}
+
+fn default_replay_window() -> u64 {
+    300
+}
+
+fn default_max_body_bytes() -> usize {
+    1 << 20
+}
+
+impl SignatureConfig {
+    pub fn validate(&self, parent: &str) -> Result<(), ConfigError> {
+        if self.tenants.is_empty() {
+            return Err(ConfigError::new(
+                field(parent, "tenants"),
+                "at least one tenant is required",
+            ));
+        }
+        for (i, tenant) in self.tenants.iter().enumerate() {
+            let tenant_field = field(parent, &format!("tenants[{i}]"));
+            if self.tenants[..i].iter().any(|other| other.id == tenant.id) {
+                return Err(ConfigError::new(
+                    field(&tenant_field, "id"),
+                    format!("duplicate tenant `{}`", tenant.id),
+                ));
+            }
+            if tenant.secrets.is_empty() || tenant.secrets.iter().any(String::is_empty) {
+                return Err(ConfigError::new(
+                    field(&tenant_field, "secrets"),
+                    "at least one secret is required and secrets must not be empty",
+                ));
+            }
+        }
+        for (name, header) in [
+            ("tenant_header", &self.tenant_header),
+            ("signature_header", &self.signature_header),
+            ("timestamp_header", &self.timestamp_header),
+        ] {
+            validate_header_name(&field(parent, name), header)?;
+        }
+        Ok(())
+    }
+}
+
+/// Verifies the signature of each request over its full body.
+pub struct SignatureStage {
+    config: Rc<SignatureConfig>,
+    /// What the request headers claimed, kept until the body is complete.
+    pending: Option<Signed>,
+}
+
+struct Signed {
+    tenant: usize,
+    timestamp: String,
+    signature: Vec<u8>,
+}
+
+impl SignatureStage {
+    pub fn new(config: Rc<SignatureConfig>) -> SignatureStage {
+        SignatureStage {
+            config,
+            pending: None,
+        }
+    }
+
+    /// Reads the tenant, timestamp and signature, rejecting the request if any is missing
+    /// or the timestamp is outside the replay window.
+    fn signed(&self) -> Result<Signed, Flow> {
+        let header = |name: &str| {
+            hostcalls::get_map_value(MapType::HttpRequestHeaders, name)
+                .unwrap()
+                .filter(|value| !value.is_empty())
+        };
+        let tenant = header(&self.config.tenant_header)
+            .and_then(|id| self.config.tenants.iter().position(|t| t.id == id))
+            .ok_or_else(|| reject("unknown tenant", "unknown_tenant"))?;
+        let signature = header(&self.config.signature_header)
+            .and_then(|value| {
+                let value = value.trim();
+                hex::decode(value.strip_prefix("sha256=").unwrap_or(value)).ok()
+            })
+            .ok_or_else(|| reject("missing signature", "missing_signature"))?;
+        let timestamp = header(&self.config.timestamp_header)
+            .ok_or_else(|| reject("missing timestamp", "missing_timestamp"))?;
+        let at: u64 = timestamp
+            .parse()
+            .map_err(|_| reject("invalid timestamp", "invalid_timestamp"))?;
+        let now = hostcalls::get_current_time()
+            .unwrap()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        if now.abs_diff(at) > self.config.replay_window_seconds {
+            return Err(reject(
+                "timestamp outside the replay window",
+                "stale_timestamp",
+            ));
+        }
+        Ok(Signed {
+            tenant,
+            timestamp,
+            signature,
+        })
+    }
+
+    fn verify(&self, signed: &Signed, body: &[u8]) -> Flow {
+        let valid = self.config.tenants[signed.tenant]
+            .secrets
+            .iter()
+            .any(|secret| {
+                let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+                mac.update(signed.timestamp.as_bytes());
+                mac.update(b".");
+                mac.update(body);
+                mac.verify_slice(&signed.signature).is_ok()
+            });
+        if valid {
+            Flow::Continue
+        } else {
+            reject("invalid signature", "invalid_signature")
+        }
+    }
+}
+
+fn reject(message: &str, reason: &'static str) -> Flow {
+    Flow::Respond(LocalReply::new(401).body(message).reason(reason))
+}
+
+impl Stage for SignatureStage {
+    fn on_request_headers(&mut self, _num_headers: usize, end_of_stream: bool) -> Flow {
+        let signed = match self.signed() {
+            Ok(signed) => signed,
+            Err(rejected) => return rejected,
+        };
+        if end_of_stream {
+            return self.verify(&signed, b"");
+        }
+        // The upstream must not see the headers of a request that turns out unsigned.
+        self.pending = Some(signed);
+        Flow::HoldHeaders
+    }
+
+    fn on_request_body(&mut self, body_size: usize, end_of_stream: bool) -> Flow {
+        if self.pending.is_none() {
+            return Flow::Continue;
+        }
+        if body_size > self.config.max_body_bytes {
+            self.pending = None;
+            return Flow::Respond(
+                LocalReply::new(413)
+                    .body("request body too large")
+                    .reason("request_too_large"),
+            );
+        }
+        if !end_of_stream {
+            return Flow::Pause;
+        }
+        let signed = self.pending.take().unwrap();
+        let body = hostcalls::get_buffer(BufferType::HttpRequestBody, 0, body_size)
+            .unwrap()
+            .unwrap_or_default();
+        self.verify(&signed, &body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mock::{with_host, Plugin};
+    use hmac::{Hmac, Mac};
+    use proxy_wasm::types::Action;
+    use serde_json::json;
+    use sha2::Sha256;
+    use std::time::Duration;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn plugin() -> Plugin {
+        with_host(|host| host.now = Duration::from_secs(NOW));
+        let config = json!({"stages": [{
+            "type": "hmac_signature",
+            "tenants": [
+                {"id": "acme", "secrets": ["old-secret", "new-secret"]},
+                {"id": "globex", "secrets": ["globex-secret"]}
+            ],
+            "max_body_bytes": 64
+        }]});
+        Plugin::new("add_header_root", &config.to_string())
+    }
+
+    fn sign(secret: &str, timestamp: u64, body: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(format!("{timestamp}.{body}").as_bytes());
+        format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+    }
+
+    /// Sends `chunks` as the body of a request signed with `signature`, and returns the
+    /// status of the local reply, if any.
+    fn send(
+        plugin: &mut Plugin,
+        tenant: &str,
+        timestamp: u64,
+        signature: &str,
+        chunks: &[&str],
+    ) -> Option<u32> {
+        let stream = plugin.stream();
+        let timestamp = timestamp.to_string();
+        let headers = [
+            (":path", "/webhooks"),
+            ("x-tenant-id", tenant),
+            ("x-timestamp", timestamp.as_str()),
+            ("x-signature", signature),
+        ];
+        let action = stream.request_headers(&headers, chunks.is_empty());
+        if !chunks.is_empty() && stream.local_response().is_none() {
+            assert_eq!(action, Action::Pause);
+        }
+        for (i, chunk) in chunks.iter().enumerate() {
+            if stream.local_response().is_some() {
+                break;
+            }
+            let last = i == chunks.len() - 1;
+            let action = stream.request_body(chunk.as_bytes(), last);
+            if !last {
+                assert_eq!(action, Action::Pause);
+            }
+        }
+        stream.local_response().map(|reply| reply.status)
+    }
+
+    #[test]
+    fn verifies_the_buffered_body() {
+        let mut plugin = plugin();
+        let body = r#"{"event":"paid"}"#;
+        let signature = sign("new-secret", NOW, body);
+        let chunks = [&body[..5], &body[5..]];
+        assert_eq!(send(&mut plugin, "acme", NOW, &signature, &chunks), None);
+
+        let signature = sign("old-secret", NOW - 200, body);
+        assert_eq!(
+            send(&mut plugin, "acme", NOW - 200, &signature, &[body]),
+            None
+        );
+
+        let signature = sign("globex-secret", NOW, "");
+        assert_eq!(send(&mut plugin, "globex", NOW, &signature, &[]), None);
+    }
+
+    #[test]
+    fn rejects_mismatches_and_replays() {
+        let mut plugin = plugin();
+        let body = r#"{"event":"paid"}"#;
+        let signature = sign("new-secret", NOW, body);
+        let tampered = [r#"{"event":"refunded"}"#];
+        assert_eq!(
+            send(&mut plugin, "acme", NOW, &signature, &tampered),
+            Some(401)
+        );
+        assert_eq!(
+            send(&mut plugin, "globex", NOW, &signature, &[body]),
+            Some(401)
+        );
+        assert_eq!(
+            send(&mut plugin, "nobody", NOW, &signature, &[body]),
+            Some(401)
+        );
+
+        let stale = sign("new-secret", NOW - 301, body);
+        assert_eq!(
+            send(&mut plugin, "acme", NOW - 301, &stale, &[body]),
+            Some(401)
+        );
+
+        let large = "x".repeat(65);
+        let signature = sign("new-secret", NOW, &large);
+        assert_eq!(
+            send(&mut plugin, "acme", NOW, &signature, &[&large]),
+            Some(413)
+        );
+    }
+
+    #[test]
+    fn rejects_tenants_without_secrets() {
+        let config = json!({"stages": [{"type": "hmac_signature", "tenants": [{"id": "a", "secrets": []}]}]});
+        let (_, accepted) = Plugin::try_new("add_header_root", &config.to_string());
+        assert!(!accepted);
+        assert!(with_host(
+            |host| host.logged("at least one secret is required")
+        ));
+    }
+}
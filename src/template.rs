@@ -0,0 +1,332 @@
+//! Header value templates.
+//!
+//! A template is literal text with `${...}` placeholders:
+//!
+//! - `${header:x-user}` / `${response_header:server}` - a request or response header
+//! - `${now}`, `${now:unix}`, `${now:unix_ms}` - the current time (RFC 3339 UTC by default)
+//! - `${request.id}`, `${route_name}`, ... - any Envoy attribute, looked up with `get_property`
+//!
+//! Any placeholder can carry a fallback, `${header:x-user:-anonymous}`, used when the value
+//! is missing. Without one a missing value renders as an empty string. `$$` is a literal `$`.
+
+use crate::config::{validate_header_value, ConfigError};
+use proxy_wasm::hostcalls;
+use proxy_wasm::types::MapType;
+use serde::Deserialize;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Template {
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(String),
+    Value {
+        source: Source,
+        fallback: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Source {
+    RequestHeader(String),
+    ResponseHeader(String),
+    Now(TimeFormat),
+    Property(Vec<String>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TimeFormat {
+    Rfc3339,
+    Unix,
+    UnixMillis,
+}
+
+/// Envoy attributes that are encoded as little-endian 64-bit integers rather than strings.
+const INTEGER_PROPERTIES: &[&str] = &[
+    "connection.id",
+    "destination.port",
+    "request.size",
+    "request.total_size",
+    "response.code",
+    "response.size",
+    "response.total_size",
+    "source.port",
+    "upstream.port",
+];
+
+impl TryFrom<String> for Template {
+    type Error = String;
+
+    fn try_from(raw: String) -> Result<Self, Self::Error> {
+        Template::parse(&raw)
+    }
+}
+
+impl Template {
+    pub fn literal(text: &str) -> Template {
+        Template {
+            segments: vec![Segment::Literal(text.to_string())],
+        }
+    }
+
+    pub fn parse(raw: &str) -> Result<Template, String> {
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut rest = raw;
+        while let Some(pos) = rest.find('$') {
+            literal.push_str(&rest[..pos]);
+            rest = &rest[pos..];
+            if let Some(after) = rest.strip_prefix("$$") {
+                literal.push('$');
+                rest = after;
+            } else if let Some(after) = rest.strip_prefix("${") {
+                let end = after
+                    .find('}')
+                    .ok_or_else(|| format!("unterminated placeholder in `{raw}`"))?;
+                if !literal.is_empty() {
+                    segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                }
+                segments.push(parse_placeholder(&after[..end])?);
+                rest = &after[end + 1..];
+            } else {
+                literal.push('$');
+                rest = &rest[1..];
+            }
+        }
+        literal.push_str(rest);
+        if !literal.is_empty() || segments.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+        Ok(Template { segments })
+    }
+
+    /// Checks the literal parts of the template, which end up verbatim in the header.
+    pub fn validate(&self, field: &str) -> Result<(), ConfigError> {
+        self.segments.iter().try_for_each(|segment| match segment {
+            Segment::Literal(text) => validate_header_value(field, text),
+            Segment::Value { fallback, .. } => {
+                validate_header_value(field, fallback.as_deref().unwrap_or_default())
+            }
+        })
+    }
+
+    /// Resolves every placeholder against the current HTTP stream.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(text) => out.push_str(text),
+                Segment::Value { source, fallback } => match source.resolve() {
+                    Some(value) => out.push_str(&value),
+                    None => out.push_str(fallback.as_deref().unwrap_or_default()),
+                },
+            }
+        }
+        out
+    }
+}
+
+fn parse_placeholder(expr: &str) -> Result<Segment, String> {
+    let (expr, fallback) = match expr.split_once(":-") {
+        Some((expr, fallback)) => (expr, Some(fallback.to_string())),
+        None => (expr, None),
+    };
+    let expr = expr.trim();
+    let source = if let Some(name) = expr.strip_prefix("header:") {
+        Source::RequestHeader(header_name(name)?)
+    } else if let Some(name) = expr.strip_prefix("response_header:") {
+        Source::ResponseHeader(header_name(name)?)
+    } else if expr == "now" {
+        Source::Now(TimeFormat::Rfc3339)
+    } else if let Some(format) = expr.strip_prefix("now:") {
+        Source::Now(match format {
+            "rfc3339" => TimeFormat::Rfc3339,
+            "unix" => TimeFormat::Unix,
+            "unix_ms" => TimeFormat::UnixMillis,
+            other => return Err(format!("unknown time format `{other}`")),
+        })
+    } else if !expr.is_empty()
+        && expr.split('.').all(|part| {
+            !part.is_empty() && part.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_')
+        })
+    {
+        Source::Property(expr.split('.').map(str::to_string).collect())
+    } else {
+        return Err(format!("invalid placeholder `${{{expr}}}`"));
+    };
+    Ok(Segment::Value { source, fallback })
+}
+
+fn header_name(name: &str) -> Result<String, String> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err("placeholder is missing a header name".to_string());
+    }
+    Ok(name.to_ascii_lowercase())
+}
+
+impl Source {
+    fn resolve(&self) -> Option<String> {
+        let value = match self {
+            Source::RequestHeader(name) => {
+                hostcalls::get_map_value(MapType::HttpRequestHeaders, name).unwrap()
+            }
+            Source::ResponseHeader(name) => {
+                hostcalls::get_map_value(MapType::HttpResponseHeaders, name).unwrap()
+            }
+            Source::Now(format) => {
+                let now = hostcalls::get_current_time().unwrap();
+                Some(format_time(now, *format))
+            }
+            Source::Property(path) => {
+                let bytes = hostcalls::get_property(path.iter().map(String::as_str).collect())
+                    .ok()
+                    .flatten()?;
+                if INTEGER_PROPERTIES.contains(&path.join(".").as_str()) {
+                    let raw: [u8; 8] = bytes.as_slice().try_into().ok()?;
+                    return Some(i64::from_le_bytes(raw).to_string());
+                }
+                String::from_utf8(bytes).ok()
+            }
+        };
+        value.filter(|value| !value.is_empty())
+    }
+}
+
+fn format_time(time: SystemTime, format: TimeFormat) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    match format {
+        TimeFormat::Unix => since_epoch.as_secs().to_string(),
+        TimeFormat::UnixMillis => since_epoch.as_millis().to_string(),
+        TimeFormat::Rfc3339 => {
+            let secs = since_epoch.as_secs();
+            let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+            let rem = secs % 86_400;
+            format!(
+                "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+                rem / 3600,
+                rem % 3600 / 60,
+                rem % 60,
+                since_epoch.subsec_millis()
+            )
+        }
+    }
+}
+
+/// Converts days since the Unix epoch to a proleptic Gregorian date
+/// (Howard Hinnant's `civil_from_days`).
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_placeholder, Segment, Source, Template, TimeFormat};
+    use crate::mock::with_host;
+    use std::time::Duration;
+
+    fn literal(text: &str) -> Segment {
+        Segment::Literal(text.to_string())
+    }
+
+    fn header(name: &str, fallback: Option<&str>) -> Segment {
+        Segment::Value {
+            source: Source::RequestHeader(name.to_string()),
+            fallback: fallback.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn parses_literals_and_placeholders() {
+        let parse = |raw: &str| Template::parse(raw).unwrap().segments;
+        assert_eq!(parse("cost: $$5"), [literal("cost: $5")]);
+        assert_eq!(parse("a $ b $"), [literal("a $ b $")]);
+        assert_eq!(parse(""), [literal("")]);
+        // The name is trimmed, the fallback kept as written.
+        assert_eq!(
+            parse("user=${ header: X-User :-anonymous }!"),
+            [
+                literal("user="),
+                header("x-user", Some("anonymous ")),
+                literal("!")
+            ]
+        );
+        assert_eq!(
+            parse("${header:x-a}${header:x-b:-}"),
+            [header("x-a", None), header("x-b", Some(""))]
+        );
+        assert_eq!(parse("$${header:x-a}"), [literal("${header:x-a}")]);
+
+        let error = |raw: &str| Template::parse(raw).unwrap_err();
+        assert_eq!(
+            error("a ${header:x"),
+            "unterminated placeholder in `a ${header:x`"
+        );
+        assert_eq!(error("${header: }"), "placeholder is missing a header name");
+        assert_eq!(error("${now:iso}"), "unknown time format `iso`");
+        assert_eq!(
+            error("${request..id}"),
+            "invalid placeholder `${request..id}`"
+        );
+        assert_eq!(error("${}"), "invalid placeholder `${}`");
+    }
+
+    #[test]
+    fn parses_each_source() {
+        let source = |expr: &str| match parse_placeholder(expr).unwrap() {
+            Segment::Value { source, .. } => source,
+            Segment::Literal(_) => unreachable!(),
+        };
+        assert_eq!(
+            source("response_header:Server"),
+            Source::ResponseHeader("server".to_string())
+        );
+        assert_eq!(source("now"), Source::Now(TimeFormat::Rfc3339));
+        assert_eq!(source("now:unix_ms"), Source::Now(TimeFormat::UnixMillis));
+        assert_eq!(
+            source("request.id"),
+            Source::Property(vec!["request".to_string(), "id".to_string()])
+        );
+    }
+
+    #[test]
+    fn renders_fallbacks_times_and_integer_properties() {
+        with_host(|host| {
+            host.now = Duration::from_millis(951_782_400_123);
+            host.set_property(&["response", "code"], &404i64.to_le_bytes());
+            host.set_property(&["route_name"], b"orders");
+            let current = host.current;
+            host.streams.entry(current).or_default().request_headers =
+                vec![("x-user".to_string(), "alice".to_string())];
+        });
+        let render = |raw: &str| Template::parse(raw).unwrap().render();
+        assert_eq!(render("${header:x-user:-anon}"), "alice");
+        assert_eq!(render("${header:x-missing:-anon}"), "anon");
+        assert_eq!(render("[${header:x-missing}]"), "[]");
+        assert_eq!(render("${response.code}"), "404");
+        assert_eq!(render("${route_name}/${request.id:-none}"), "orders/none");
+        assert_eq!(render("${now}"), "2000-02-29T00:00:00.123Z");
+        assert_eq!(render("${now:unix}"), "951782400");
+    }
+
+    #[test]
+    fn failed_property_lookups_fall_back() {
+        with_host(|host| host.fail_property(&["connection", "mtls"]));
+        let render = |raw: &str| Template::parse(raw).unwrap().render();
+        assert_eq!(render("mtls=${connection.mtls:-unknown}"), "mtls=unknown");
+        assert_eq!(render("[${connection.mtls}]"), "[]");
+    }
+}
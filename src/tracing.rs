@@ -0,0 +1,423 @@
+//! Trace context propagation in the W3C Trace Context and B3 formats.
+//!
+//! The gateway takes part in the trace as one span: the context read from the request
+//! becomes the parent of a new span id, which is sent upstream in every configured
+//! format. Requests without a context start a new trace.
+
+use crate::config::{field, validate_header_name, ConfigError};
+use crate::matcher::RequestInfo;
+use crate::pipeline::{Flow, LocalReply, Stage};
+use crate::util::random_id;
+use proxy_wasm::hostcalls;
+use proxy_wasm::types::MapType;
+use serde::Deserialize;
+use std::rc::Rc;
+
+const TRACEPARENT: &str = "traceparent";
+const TRACESTATE: &str = "tracestate";
+const B3: &str = "b3";
+const B3_TRACE_ID: &str = "x-b3-traceid";
+const B3_SPAN_ID: &str = "x-b3-spanid";
+const B3_PARENT_SPAN_ID: &str = "x-b3-parentspanid";
+const B3_SAMPLED: &str = "x-b3-sampled";
+const B3_FLAGS: &str = "x-b3-flags";
+
+/// W3C Trace Context caps `tracestate` at 32 entries.
+const MAX_TRACESTATE_ENTRIES: usize = 32;
+
+/// Configuration of the `tracing` stage.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TracingConfig {
+    /// Formats sent upstream. Headers of the other formats are removed from the request.
+    #[serde(default = "default_propagation")]
+    pub propagation: Vec<Propagation>,
+    #[serde(default)]
+    pub sampler: Sampler,
+    /// Response header that receives the trace id, or `null` for none.
+    #[serde(default = "default_trace_id_header")]
+    pub trace_id_header: Option<String>,
+    /// `tracestate` key under which the gateway records its span id.
+    pub tracestate_key: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Propagation {
+    /// `traceparent` and `tracestate`.
+    W3c,
+    /// The single `b3` header.
+    B3,
+    /// The `x-b3-*` headers.
+    B3Multi,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct Sampler {
+    /// Share of the traces sampled, decided from the trace id so every hop agrees.
+    pub ratio: f64,
+    /// Keep the decision the request carries instead of sampling again.
+    pub parent_based: bool,
+}
+
+impl Default for Sampler {
+    fn default() -> Self {
+        Sampler {
+            ratio: 1.0,
+            parent_based: true,
+        }
+    }
+}
+
+impl Sampler {
+    fn sample(&self, trace_id: &[u8; 16]) -> bool {
+        // The low half is random even in 64-bit B3 trace ids.
+        let value = u64::from_be_bytes(trace_id[8..].try_into().unwrap());
+        self.ratio >= 1.0 || (value as f64) < self.ratio * u64::MAX as f64
+    }
+}
+
+fn default_propagation() -> Vec<Propagation> {
+    vec![Propagation::W3c]
+}
+
+fn default_trace_id_header() -> Option<String> {
+    Some("x-trace-id".to_string())
+}
+
+impl TracingConfig {
+    pub fn validate(&self, parent: &str) -> Result<(), ConfigError> {
+        if self.propagation.is_empty() {
+            return Err(ConfigError::new(
+                field(parent, "propagation"),
+                "at least one format is required",
+            ));
+        }
+        if !(0.0..=1.0).contains(&self.sampler.ratio) {
+            return Err(ConfigError::new(
+                field(parent, "sampler.ratio"),
+                "must be between 0 and 1",
+            ));
+        }
+        if let Some(header) = &self.trace_id_header {
+            validate_header_name(&field(parent, "trace_id_header"), header)?;
+        }
+        if let Some(key) = &self.tracestate_key {
+            let valid = key.len() <= 256
+                && key.starts_with(|c: char| c.is_ascii_lowercase() || c.is_ascii_digit())
+                && key
+                    .bytes()
+                    .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b"_-*/".contains(&b));
+            if !valid {
+                return Err(ConfigError::new(
+                    field(parent, "tracestate_key"),
+                    format!("`{key}` is not a valid tracestate key"),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What the request carried of its trace.
+#[derive(Default)]
+struct Incoming {
+    /// Trace id and span id of the caller.
+    ids: Option<([u8; 16], [u8; 8])>,
+    sampled: Option<bool>,
+    tracestate: Option<String>,
+}
+
+impl Incoming {
+    /// Reads the first valid context, trying `traceparent`, then `b3`, then `x-b3-*`.
+    fn extract(request: &RequestInfo) -> Incoming {
+        if let Some(incoming) = request.header(TRACEPARENT).and_then(parse_traceparent) {
+            return Incoming {
+                tracestate: request.header(TRACESTATE).map(str::to_string),
+                ..incoming
+            };
+        }
+        if let Some(incoming) = request.header(B3).and_then(parse_b3) {
+            return incoming;
+        }
+        let trace_id = request.header(B3_TRACE_ID).and_then(parse_b3_trace_id);
+        let span_id = request.header(B3_SPAN_ID).and_then(parse_id);
+        let sampled = if request.header(B3_FLAGS) == Some("1") {
+            Some(true)
+        } else {
+            request.header(B3_SAMPLED).and_then(parse_b3_sampled)
+        };
+        Incoming {
+            ids: trace_id.zip(span_id),
+            sampled,
+            tracestate: None,
+        }
+    }
+}
+
+fn parse_traceparent(value: &str) -> Option<Incoming> {
+    let mut parts = value.trim().splitn(5, '-');
+    let version = parts.next()?;
+    let trace_id = parse_id(parts.next()?)?;
+    let span_id = parse_id(parts.next()?)?;
+    let flags = parts.next()?;
+    // Later versions may append fields; version 00 may not.
+    let extended = parts.next().is_some();
+    if version.len() != 2 || !is_hex(version) || version == "ff" || version == "00" && extended {
+        return None;
+    }
+    if flags.len() != 2 || !is_hex(flags) {
+        return None;
+    }
+    let flags = u8::from_str_radix(flags, 16).ok()?;
+    Some(Incoming {
+        ids: Some((trace_id, span_id)),
+        sampled: Some(flags & 1 == 1),
+        tracestate: None,
+    })
+}
+
+/// Parses `{trace id}-{span id}[-{sampled}[-{parent span id}]]`, or a lone sampling
+/// decision.
+fn parse_b3(value: &str) -> Option<Incoming> {
+    match value.trim().split('-').collect::<Vec<_>>().as_slice() {
+        [sampled] => Some(Incoming {
+            sampled: Some(parse_b3_sampled(sampled)?),
+            ..Incoming::default()
+        }),
+        [trace_id, span_id, rest @ ..] if rest.len() <= 2 => Some(Incoming {
+            ids: Some((parse_b3_trace_id(trace_id)?, parse_id(span_id)?)),
+            sampled: rest.first().and_then(|sampled| parse_b3_sampled(sampled)),
+            tracestate: None,
+        }),
+        _ => None,
+    }
+}
+
+fn parse_b3_sampled(value: &str) -> Option<bool> {
+    match value {
+        // `d` is B3's debug flag, which implies sampling.
+        "1" | "d" | "true" => Some(true),
+        "0" | "false" => Some(false),
+        _ => None,
+    }
+}
+
+/// B3 trace ids are 64 or 128 bits; the short form is padded on the left.
+fn parse_b3_trace_id(value: &str) -> Option<[u8; 16]> {
+    if value.len() != 16 {
+        return parse_id(value);
+    }
+    let short: [u8; 8] = parse_id(value)?;
+    let mut id = [0; 16];
+    id[8..].copy_from_slice(&short);
+    Some(id)
+}
+
+/// Parses a lowercase hex id, which must not be all zeros.
+fn parse_id<const N: usize>(value: &str) -> Option<[u8; N]> {
+    if value.len() != 2 * N || !is_hex(value) {
+        return None;
+    }
+    let mut id = [0; N];
+    hex::decode_to_slice(value, &mut id).ok()?;
+    (id != [0; N]).then_some(id)
+}
+
+fn is_hex(value: &str) -> bool {
+    value
+        .bytes()
+        .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+/// Propagates the trace context upstream and echoes the trace id downstream.
+pub struct TracingStage {
+    config: Rc<TracingConfig>,
+    /// Hex trace id of the request.
+    trace_id: Option<String>,
+}
+
+impl TracingStage {
+    pub fn new(config: Rc<TracingConfig>) -> TracingStage {
+        TracingStage {
+            config,
+            trace_id: None,
+        }
+    }
+
+    /// The `tracestate` sent upstream: the gateway's entry first, then the incoming
+    /// entries, keeping the leftmost when there are too many.
+    fn tracestate(&self, span_id: &str, incoming: Option<&str>) -> Option<String> {
+        let key = self.config.tracestate_key.as_deref();
+        let own = key.map(|key| format!("{key}={span_id}"));
+        let entries: Vec<_> = own
+            .into_iter()
+            .chain(
+                incoming
+                    .unwrap_or_default()
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|entry| !entry.is_empty())
+                    .filter(|entry| key.is_none() || entry.split('=').next() != key)
+                    .map(str::to_string),
+            )
+            .take(MAX_TRACESTATE_ENTRIES)
+            .collect();
+        (!entries.is_empty()).then(|| entries.join(","))
+    }
+}
+
+impl Stage for TracingStage {
+    fn on_request_headers(&mut self, _num_headers: usize, _end_of_stream: bool) -> Flow {
+        let request = RequestInfo::new(hostcalls::get_map(MapType::HttpRequestHeaders).unwrap());
+        let incoming = Incoming::extract(&request);
+        let (trace_id, parent_id) = match incoming.ids {
+            Some((trace_id, span_id)) => (trace_id, Some(hex::encode(span_id))),
+            None => (random_id(), None),
+        };
+        let sampler = &self.config.sampler;
+        let sampled = match incoming.sampled {
+            Some(sampled) if sampler.parent_based => sampled,
+            _ => sampler.sample(&trace_id),
+        };
+        let trace_id = hex::encode(trace_id);
+        let span_id = hex::encode(random_id::<8>());
+        let flag = if sampled { "1" } else { "0" };
+
+        let mut headers = vec![];
+        for propagation in &self.config.propagation {
+            match propagation {
+                Propagation::W3c => {
+                    headers.push((
+                        TRACEPARENT,
+                        Some(format!("00-{trace_id}-{span_id}-0{flag}")),
+                    ));
+                    let tracestate = self.tracestate(&span_id, incoming.tracestate.as_deref());
+                    headers.push((TRACESTATE, tracestate));
+                }
+                Propagation::B3 => {
+                    let mut b3 = format!("{trace_id}-{span_id}-{flag}");
+                    if let Some(parent_id) = &parent_id {
+                        b3.push_str(&format!("-{parent_id}"));
+                    }
+                    headers.push((B3, Some(b3)));
+                }
+                Propagation::B3Multi => {
+                    headers.push((B3_TRACE_ID, Some(trace_id.clone())));
+                    headers.push((B3_SPAN_ID, Some(span_id.clone())));
+                    headers.push((B3_PARENT_SPAN_ID, parent_id.clone()));
+                    headers.push((B3_SAMPLED, Some(flag.to_string())));
+                }
+            }
+        }
+        // Clear every format first, so upstream sees a single context.
+        for name in [
+            TRACEPARENT,
+            TRACESTATE,
+            B3,
+            B3_TRACE_ID,
+            B3_SPAN_ID,
+            B3_PARENT_SPAN_ID,
+            B3_SAMPLED,
+            B3_FLAGS,
+        ] {
+            hostcalls::set_map_value(MapType::HttpRequestHeaders, name, None).unwrap();
+        }
+        for (name, value) in headers {
+            if let Some(value) = value {
+                hostcalls::set_map_value(MapType::HttpRequestHeaders, name, Some(&value)).unwrap();
+            }
+        }
+        self.trace_id = Some(trace_id);
+        Flow::Continue
+    }
+
+    fn on_response_headers(&mut self, _num_headers: usize, _end_of_stream: bool) -> Flow {
+        if let (Some(header), Some(trace_id)) = (&self.config.trace_id_header, &self.trace_id) {
+            hostcalls::set_map_value(MapType::HttpResponseHeaders, header, Some(trace_id)).unwrap();
+        }
+        Flow::Continue
+    }
+    fn on_local_reply(&mut self, reply: &mut LocalReply) {
+        if let (Some(header), Some(trace_id)) = (&self.config.trace_id_header, &self.trace_id) {
+            reply.headers.push((header.clone(), trace_id.clone()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mock::Plugin;
+    use serde_json::json;
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+    const SPAN_ID: &str = "00f067aa0ba902b7";
+
+    #[test]
+    fn continues_a_w3c_trace_in_every_format() {
+        let config = json!({"stages": [{
+            "type": "tracing",
+            "propagation": ["w3c", "b3", "b3_multi"],
+            "tracestate_key": "wasmup"
+        }]});
+        let mut plugin = Plugin::new("add_header_root", &config.to_string());
+        let stream = plugin.stream();
+        stream.request_headers(
+            &[
+                (":path", "/"),
+                ("traceparent", &format!("00-{TRACE_ID}-{SPAN_ID}-01")),
+                ("tracestate", "congo=t61rcWkgMzE, wasmup=0000000000000001"),
+            ],
+            true,
+        );
+        stream.response_headers(&[(":status", "200")], true);
+
+        let traceparent = stream.request_header("traceparent").unwrap();
+        let [version, trace_id, span_id, flags] = traceparent.split('-').collect::<Vec<_>>()[..]
+        else {
+            panic!("malformed traceparent `{traceparent}`");
+        };
+        assert_eq!((version, trace_id, flags), ("00", TRACE_ID, "01"));
+        assert_ne!(span_id, SPAN_ID);
+        assert_eq!(
+            stream.request_header("tracestate").unwrap(),
+            format!("wasmup={span_id},congo=t61rcWkgMzE")
+        );
+        assert_eq!(
+            stream.request_header("b3").unwrap(),
+            format!("{TRACE_ID}-{span_id}-1-{SPAN_ID}")
+        );
+        assert_eq!(stream.request_header("x-b3-parentspanid").unwrap(), SPAN_ID);
+        assert_eq!(stream.request_header("x-b3-sampled").unwrap(), "1");
+        assert_eq!(stream.response_header("x-trace-id").unwrap(), TRACE_ID);
+    }
+
+    #[test]
+    fn converts_b3_and_samples_new_traces() {
+        let config = json!({"stages": [{"type": "tracing", "sampler": {"ratio": 0.0}}]});
+        let mut plugin = Plugin::new("add_header_root", &config.to_string());
+        let stream = plugin.stream();
+        stream.request_headers(
+            &[
+                (":path", "/"),
+                ("x-b3-traceid", "80f198ee56343ba8"),
+                ("x-b3-spanid", "e457b5a2e4d86bd1"),
+                ("x-b3-flags", "1"),
+            ],
+            true,
+        );
+        let traceparent = stream.request_header("traceparent").unwrap();
+        assert!(traceparent.starts_with("00-000000000000000080f198ee56343ba8-"));
+        assert!(traceparent.ends_with("-01"));
+        assert_eq!(stream.request_header("x-b3-traceid"), None);
+        assert_eq!(stream.request_header("x-b3-flags"), None);
+
+        let stream = plugin.stream();
+        stream.request_headers(&[(":path", "/"), ("traceparent", "00-zz-00-01")], true);
+        let traceparent = stream.request_header("traceparent").unwrap();
+        assert!(!traceparent.contains("-zz-"));
+        assert!(traceparent.ends_with("-00"));
+        assert_eq!(stream.request_header("tracestate"), None);
+    }
+}
@@ -0,0 +1,25 @@
+//! Small helpers shared by several stages.
+
+use proxy_wasm::hostcalls;
+use std::time::UNIX_EPOCH;
+
+pub fn now_millis() -> u64 {
+    now_micros() / 1000
+}
+
+pub fn now_micros() -> u64 {
+    hostcalls::get_current_time()
+        .unwrap()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64
+}
+
+/// A random id that is not all zeros, which trace and request ids reserve as invalid.
+pub fn random_id<const N: usize>() -> [u8; N] {
+    let mut id = [0; N];
+    while id == [0; N] {
+        getrandom::getrandom(&mut id).unwrap();
+    }
+    id
+}